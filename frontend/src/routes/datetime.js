@@ -23,3 +23,17 @@ export function isoDateString(dateTime) {
 export function isToday(date) {
   return isoDateString(date) === isoDateString(new Date());
 }
+
+export function localDateString(date) {
+  // Calendar date in local time zone
+  const month = String(date.getMonth() + 1).padStart(2, "0");
+  const day = String(date.getDate()).padStart(2, "0");
+  return `${date.getFullYear()}-${month}-${day}`;
+}
+
+export function trackRange(track) {
+  if (track.from !== undefined) {
+    return `from=${track.from}&to=${track.to}`;
+  }
+  return `ts_start=${encodeURIComponent(track.ts_start)}`;
+}
@@ -0,0 +1 @@
+CREATE INDEX positions_device_ts_idx ON positions (device_id, ts);
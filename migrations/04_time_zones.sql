@@ -0,0 +1,8 @@
+-- IANA time zone names
+ALTER TABLE devices
+ADD COLUMN tz VARCHAR(64);
+
+CREATE TABLE users (
+    user_id VARCHAR(200) PRIMARY KEY,
+    tz VARCHAR(64)
+);
@@ -0,0 +1,14 @@
+-- CREATE SEQUENCE trips_id_seq;
+CREATE TABLE trips (
+    id INTEGER PRIMARY KEY, -- DEFAULT NEXTVAL ('trips_id_seq')
+    device_id INTEGER NOT NULL,
+    ts_start TIMESTAMPTZ NOT NULL,
+    ts_end TIMESTAMPTZ NOT NULL,
+    distance DOUBLE PRECISION NOT NULL -- meters
+);
+
+CREATE INDEX trips_device_ts_idx ON trips (device_id, ts_end);
+
+-- positions before are segmented into trips
+ALTER TABLE devices
+ADD COLUMN trips_until TIMESTAMPTZ;
@@ -0,0 +1,24 @@
+-- CREATE SEQUENCE places_id_seq;
+CREATE TABLE places (
+    id INTEGER PRIMARY KEY, -- DEFAULT NEXTVAL ('places_id_seq')
+    user_id VARCHAR(200) NOT NULL,
+    name VARCHAR(200),
+    lat DOUBLE PRECISION NOT NULL,
+    lon DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX places_user_idx ON places (user_id);
+
+-- CREATE SEQUENCE visits_id_seq;
+CREATE TABLE visits (
+    id INTEGER PRIMARY KEY, -- DEFAULT NEXTVAL ('visits_id_seq')
+    device_id INTEGER NOT NULL,
+    place_id INTEGER NOT NULL,
+    arrival TIMESTAMPTZ NOT NULL,
+    departure TIMESTAMPTZ NOT NULL,
+    -- center of stay positions
+    lat DOUBLE PRECISION NOT NULL,
+    lon DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX visits_device_departure_idx ON visits (device_id, departure);
@@ -0,0 +1,3 @@
+-- Point filter settings (JSON)
+ALTER TABLE devices
+ADD COLUMN filter TEXT;
@@ -0,0 +1,27 @@
+-- CREATE SEQUENCE geofences_id_seq;
+CREATE TABLE geofences (
+    id INTEGER PRIMARY KEY, -- DEFAULT NEXTVAL ('geofences_id_seq')
+    user_id VARCHAR(200) NOT NULL,
+    name VARCHAR(200) NOT NULL,
+    -- circle center and radius in meters
+    lat DOUBLE PRECISION,
+    lon DOUBLE PRECISION,
+    radius DOUBLE PRECISION,
+    -- polygon coordinates (GeoJSON)
+    polygon TEXT
+);
+
+CREATE INDEX geofences_user_idx ON geofences (user_id);
+
+-- CREATE SEQUENCE geofence_transitions_id_seq;
+CREATE TABLE geofence_transitions (
+    id INTEGER PRIMARY KEY, -- DEFAULT NEXTVAL ('geofence_transitions_id_seq')
+    geofence_id INTEGER NOT NULL,
+    device_id INTEGER NOT NULL,
+    ts TIMESTAMPTZ NOT NULL,
+    event VARCHAR(10) NOT NULL, -- enter, leave
+    lat DOUBLE PRECISION NOT NULL,
+    lon DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX geofence_transitions_device_idx ON geofence_transitions (device_id, geofence_id, ts);
@@ -0,0 +1,197 @@
+//! Activity laps for fitness exports (TCX, FIT)
+
+use crate::db::{GpsPoint, TrackData};
+use crate::filter::{Filter, FilterParams};
+use crate::profile::{annotation, with_distance, CADENCE_KEYS, HEART_RATE_KEYS};
+use crate::trips;
+
+/// Lap length of `LapSplit::Distance` in meters
+const LAP_DISTANCE: f64 = 1000.0;
+
+/// Sport type of an activity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sport {
+    Other,
+    Running,
+    Cycling,
+    Walking,
+    Hiking,
+}
+
+impl Sport {
+    pub fn from_param(sport: Option<&str>) -> anyhow::Result<Self> {
+        match sport {
+            None | Some("other") => Ok(Sport::Other),
+            Some("running") => Ok(Sport::Running),
+            Some("cycling") => Ok(Sport::Cycling),
+            Some("walking") => Ok(Sport::Walking),
+            Some("hiking") => Ok(Sport::Hiking),
+            Some(sport) => anyhow::bail!("Unsupported sport `{sport}`"),
+        }
+    }
+}
+
+/// Splitting of an activity into laps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LapSplit {
+    /// A lap per trip, without stays
+    Trip,
+    /// A lap per kilometer
+    Distance,
+}
+
+impl LapSplit {
+    pub fn from_param(laps: Option<&str>) -> anyhow::Result<Self> {
+        match laps {
+            None | Some("trip") => Ok(LapSplit::Trip),
+            Some("km") => Ok(LapSplit::Distance),
+            Some(laps) => anyhow::bail!("Unsupported lap split `{laps}`"),
+        }
+    }
+}
+
+/// Track point with activity values
+pub struct ActivityPoint<'a> {
+    pub pt: &'a GpsPoint,
+    /// Cumulative distance in meters
+    pub distance: f64,
+    pub heart_rate: Option<f64>,
+    pub cadence: Option<f64>,
+}
+
+pub struct Lap<'a> {
+    pub points: Vec<ActivityPoint<'a>>,
+    /// UNIX timestamp
+    pub ts_start: i64,
+    pub ts_end: i64,
+    /// Distance in meters
+    pub distance: f64,
+    /// Lap ended at a distance mark
+    pub distance_trigger: bool,
+}
+
+impl Lap<'_> {
+    /// Elapsed time in seconds
+    pub fn duration(&self) -> i64 {
+        self.ts_end - self.ts_start
+    }
+    /// Average speed in m/s
+    pub fn avg_speed(&self) -> Option<f64> {
+        (self.duration() > 0).then(|| self.distance / self.duration() as f64)
+    }
+    /// Maximal speed in m/s
+    pub fn max_speed(&self) -> Option<f64> {
+        self.points
+            .iter()
+            .filter_map(|p| p.pt.speed)
+            .max()
+            .map(|speed| speed as f64 / 3.6)
+    }
+    pub fn avg_heart_rate(&self) -> Option<f64> {
+        mean(self.points.iter().filter_map(|p| p.heart_rate))
+    }
+    pub fn max_heart_rate(&self) -> Option<f64> {
+        self.points
+            .iter()
+            .filter_map(|p| p.heart_rate)
+            .reduce(f64::max)
+    }
+    pub fn avg_cadence(&self) -> Option<f64> {
+        mean(self.points.iter().filter_map(|p| p.cadence))
+    }
+}
+
+/// Split a track into laps. Without trips, all points form a single lap.
+pub fn laps(track: &TrackData, split: LapSplit) -> Vec<Lap<'_>> {
+    let points: Vec<ActivityPoint> = with_distance(&track.points)
+        .map(|(pt, distance, annotations)| ActivityPoint {
+            pt,
+            distance,
+            heart_rate: annotation(&annotations, HEART_RATE_KEYS),
+            cadence: annotation(&annotations, CADENCE_KEYS),
+        })
+        .collect();
+    if points.is_empty() {
+        return Vec::new();
+    }
+    match split {
+        LapSplit::Trip => trip_laps(track, points),
+        LapSplit::Distance => distance_laps(points),
+    }
+}
+
+fn trip_laps<'a>(track: &TrackData, points: Vec<ActivityPoint<'a>>) -> Vec<Lap<'a>> {
+    let filter = Filter::from(&FilterParams::default());
+    let trips = trips::segment(&track.points, &filter, true).trips;
+    if trips.is_empty() {
+        return vec![lap(points, None, false)];
+    }
+    let mut points = points.into_iter().peekable();
+    let mut laps = Vec::new();
+    // Cumulative distance of the laps, without stays
+    let mut total = 0.0;
+    for trip in trips {
+        // Skip points of stays
+        while points.next_if(|p| p.pt.ts < trip.ts_start).is_some() {}
+        let mut lap_points = Vec::new();
+        while let Some(p) = points.next_if(|p| p.pt.ts <= trip.ts_end) {
+            lap_points.push(p);
+        }
+        if let Some(first) = lap_points.first() {
+            let offset = total - first.distance;
+            for p in &mut lap_points {
+                p.distance += offset;
+            }
+            let lap = lap(lap_points, None, false);
+            total += lap.distance;
+            laps.push(lap);
+        }
+    }
+    laps
+}
+
+fn distance_laps(points: Vec<ActivityPoint>) -> Vec<Lap> {
+    let mut laps = Vec::new();
+    let mut lap_points = Vec::new();
+    let mut mark = LAP_DISTANCE;
+    // End of the previous lap (timestamp, distance)
+    let mut prev_end = None;
+    for p in points {
+        let end = p.distance >= mark;
+        let (ts, distance) = (p.pt.ts, p.distance);
+        lap_points.push(p);
+        if end {
+            laps.push(lap(std::mem::take(&mut lap_points), prev_end, true));
+            prev_end = Some((ts, distance));
+            while mark <= distance {
+                mark += LAP_DISTANCE;
+            }
+        }
+    }
+    if !lap_points.is_empty() {
+        laps.push(lap(lap_points, prev_end, false));
+    }
+    laps
+}
+
+/// Lap starting at the end of the previous lap or at its first point
+fn lap<'a>(
+    points: Vec<ActivityPoint<'a>>,
+    prev_end: Option<(i64, f64)>,
+    distance_trigger: bool,
+) -> Lap<'a> {
+    let (first, last) = (&points[0], &points[points.len() - 1]);
+    let (ts_start, start_distance) = prev_end.unwrap_or((first.pt.ts, first.distance));
+    Lap {
+        ts_start,
+        ts_end: last.pt.ts,
+        distance: last.distance - start_distance,
+        distance_trigger,
+        points,
+    }
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
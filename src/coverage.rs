@@ -0,0 +1,175 @@
+//! Coverage of visited grid cells (Web Mercator tiles identified by quadkey)
+
+use crate::db::Db;
+use crate::filter::FilterParams;
+use crate::mvt::{self, TileId};
+use crate::timestamp::{Period, TimeRange};
+use chrono_tz::Tz;
+use std::collections::BTreeMap;
+
+/// Default zoom level of grid cells (about 600 m at the equator)
+pub const DEFAULT_LEVEL: u8 = 16;
+/// Maximal time between two positions counted as time spent in a cell
+const MAX_GAP: i64 = 20 * 60;
+
+/// Visited grid cell
+#[derive(Debug)]
+pub struct Cell {
+    pub tile: TileId,
+    /// Number of positions
+    pub positions: usize,
+    /// Number of entries into the cell
+    pub visits: usize,
+    /// Time spent in seconds
+    pub duration: i64,
+    /// UNIX timestamp of the first position within the period
+    pub first_ts: i64,
+    pub last_ts: i64,
+    /// Not visited before the period
+    pub new: bool,
+}
+
+/// Visited cells of a device within a period
+pub struct DeviceCoverage {
+    pub device_id: i32,
+    pub tz: Tz,
+    pub cells: Vec<Cell>,
+}
+
+impl DeviceCoverage {
+    pub fn new_cells(&self) -> usize {
+        self.cells.iter().filter(|cell| cell.new).count()
+    }
+}
+
+/// Grid cells visited by all or selected devices within a period.
+/// Cells visited before the period are marked as not new.
+pub async fn query_coverage(
+    db: &Db,
+    device_ids: Option<&[i32]>,
+    period: Period,
+    level: u8,
+) -> anyhow::Result<Vec<DeviceCoverage>> {
+    let mut coverage = Vec::new();
+    for device in db.query_last_positions(None, None).await? {
+        if device_ids.is_some_and(|ids| !ids.contains(&device.device_id)) {
+            continue;
+        }
+        let track = db
+            .query_track(device.device_id, period, &FilterParams::default())
+            .await?;
+        if track.points.is_empty() {
+            continue;
+        }
+        let mut cells: BTreeMap<(u32, u32), Cell> = BTreeMap::new();
+        let mut prev: Option<(i64, (u32, u32))> = None;
+        for pt in &track.points {
+            let tile = TileId::at(level, pt.x, pt.y);
+            let key = (tile.x, tile.y);
+            // Time since the previous position is spent in its cell
+            let prev_key = match prev {
+                Some((ts, prev_key)) if pt.ts - ts <= MAX_GAP => {
+                    if let Some(cell) = cells.get_mut(&prev_key) {
+                        cell.duration += pt.ts - ts;
+                    }
+                    Some(prev_key)
+                }
+                _ => None,
+            };
+            let cell = cells.entry(key).or_insert(Cell {
+                tile,
+                positions: 0,
+                visits: 0,
+                duration: 0,
+                first_ts: pt.ts,
+                last_ts: pt.ts,
+                new: true,
+            });
+            cell.positions += 1;
+            cell.last_ts = pt.ts;
+            if prev_key != Some(key) {
+                cell.visits += 1;
+            }
+            prev = Some((pt.ts, key));
+        }
+
+        // Cells visited before the period, counted within the rows and columns of the visited cells
+        let range = period.time_range(device.tz);
+        let history = TimeRange {
+            from: 0,
+            to: range.from,
+        };
+        let rows = cells.keys().map(|(_, y)| *y as i64).collect();
+        let cols = cells.keys().map(|(x, _)| *x as i64);
+        let cols = cols.clone().min().unwrap_or_default()..=cols.max().unwrap_or_default();
+        let counts =
+            mvt::count_positions(db, device.device_id, history, level, cols, &rows).await?;
+        for (x, y) in counts.keys() {
+            if let Some(cell) = cells.get_mut(&(*x as u32, *y as u32)) {
+                cell.new = false;
+            }
+        }
+
+        coverage.push(DeviceCoverage {
+            device_id: device.device_id,
+            tz: device.tz,
+            cells: cells.into_values().collect(),
+        });
+    }
+    Ok(coverage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position::Position;
+
+    const START: i64 = 1_700_000_000;
+
+    #[actix_web::test]
+    async fn visits_duration_and_new_cells() {
+        let path = std::env::temp_dir().join(format!("coverage-{}.sqlite", std::process::id()));
+        let db = Db::connect_to(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap();
+        db.run_migrations().await.unwrap();
+        // Cell A visited before the period, cell B about 1.5 km east
+        let a = |ts| Position::new("ab", ts, 47.0, 8.0);
+        let b = |ts| Position::new("ab", ts, 47.0, 8.02);
+        let positions = [
+            a(START - 86400),
+            a(START),
+            a(START + 60),
+            b(START + 120),
+            a(START + 180),
+            // After a signal gap
+            a(START + 3000),
+        ];
+        db.insert_positions("user", "device", &positions)
+            .await
+            .unwrap();
+
+        let period = Period::from_params(
+            Some(&START.to_string()),
+            Some(&(START + 3600).to_string()),
+            None,
+        )
+        .unwrap();
+        let coverage = query_coverage(&db, None, period, DEFAULT_LEVEL)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(coverage.len(), 1);
+        let cells = &coverage[0].cells;
+        assert_eq!(cells.len(), 2);
+        assert_eq!(coverage[0].new_cells(), 1);
+        let (a, b) = (&cells[0], &cells[1]);
+        assert_eq!((a.tile.y, a.tile.x + 3), (b.tile.y, b.tile.x));
+        assert_eq!((a.positions, a.visits, a.duration), (4, 3, 120));
+        assert_eq!((a.first_ts, a.last_ts), (START, START + 3000));
+        assert!(!a.new);
+        assert_eq!((b.positions, b.visits, b.duration), (1, 1, 60));
+        assert!(b.new);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::db::{Db, GpsPoint, TrackData};
+use crate::export::TrackRange;
+use crate::filter::FilterParams;
+use crate::timestamp::{format_timestamp, TimeRange};
+use chrono_tz::Tz;
+use futures_util::future;
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::{Map, Value};
+
+/// Time range of a streamed chunk in seconds
+const CHUNK_DURATION: i64 = 24 * 3600;
+/// Number of points carried over to the next chunk
+const PENDING_POINTS: usize = 2;
+
+/// Default columns
+const DEFAULT_COLUMNS: &[Column] = &[
+    Column::Timestamp,
+    Column::Latitude,
+    Column::Longitude,
+    Column::Speed,
+    Column::Elevation,
+    Column::Accuracy,
+    Column::VAccuracy,
+    Column::Cog,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Column {
+    /// Device name `user/device`
+    Device,
+    Timestamp,
+    Latitude,
+    Longitude,
+    Speed,
+    Elevation,
+    Accuracy,
+    VAccuracy,
+    Cog,
+    /// Annotation value with column name and annotation key
+    Annotation(String, String),
+}
+
+impl Column {
+    fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "device" => Ok(Column::Device),
+            "timestamp" => Ok(Column::Timestamp),
+            "latitude" => Ok(Column::Latitude),
+            "longitude" => Ok(Column::Longitude),
+            "speed" => Ok(Column::Speed),
+            "elevation" => Ok(Column::Elevation),
+            "accuracy" => Ok(Column::Accuracy),
+            "v_accuracy" => Ok(Column::VAccuracy),
+            "cog" => Ok(Column::Cog),
+            _ => anyhow::bail!("Unknown column `{name}`"),
+        }
+    }
+    /// Annotation column. Known OwnTracks keys can be selected by their long name.
+    fn annotation(name: &str) -> Self {
+        let key = match name {
+            "trigger" => "t",
+            "battery" => "batt",
+            "connection" => "conn",
+            key => key,
+        };
+        Column::Annotation(name.to_string(), key.to_string())
+    }
+    fn name(&self) -> &str {
+        match self {
+            Column::Device => "device",
+            Column::Timestamp => "timestamp",
+            Column::Latitude => "latitude",
+            Column::Longitude => "longitude",
+            Column::Speed => "speed",
+            Column::Elevation => "elevation",
+            Column::Accuracy => "accuracy",
+            Column::VAccuracy => "v_accuracy",
+            Column::Cog => "cog",
+            Column::Annotation(name, _) => name,
+        }
+    }
+}
+
+/// Timestamp format
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TimeFormat {
+    /// RFC 3339 with the offset of the device time zone
+    Iso,
+    /// UNIX timestamp
+    Epoch,
+}
+
+/// CSV columns and formats
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    columns: Vec<Column>,
+    time_format: TimeFormat,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            columns: DEFAULT_COLUMNS.to_vec(),
+            time_format: TimeFormat::Iso,
+        }
+    }
+}
+
+impl CsvOptions {
+    /// Options from comma separated `columns` and `annotations` and `time_format` (`iso` or `epoch`)
+    pub fn from_params(
+        columns: Option<&str>,
+        annotations: Option<&str>,
+        time_format: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut options = CsvOptions::default();
+        if let Some(columns) = columns {
+            options.columns = split_list(columns)
+                .map(Column::from_name)
+                .collect::<anyhow::Result<_>>()?;
+        }
+        if let Some(annotations) = annotations {
+            options
+                .columns
+                .extend(split_list(annotations).map(Column::annotation));
+        }
+        options.time_format = match time_format {
+            None | Some("iso") => TimeFormat::Iso,
+            Some("epoch") => TimeFormat::Epoch,
+            Some(format) => anyhow::bail!("Unknown time format `{format}`"),
+        };
+        Ok(options)
+    }
+
+    /// Options with a leading device column
+    pub fn with_device(mut self) -> Self {
+        if !self.columns.contains(&Column::Device) {
+            self.columns.insert(0, Column::Device);
+        }
+        self
+    }
+
+    /// CSV header line
+    pub fn header(&self) -> anyhow::Result<Vec<u8>> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(self.columns.iter().map(Column::name))?;
+        Ok(writer.into_inner()?)
+    }
+
+    /// CSV lines of track points
+    pub fn rows(&self, track: &TrackData, device: Option<&str>) -> anyhow::Result<Vec<u8>> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let with_annotations = self
+            .columns
+            .iter()
+            .any(|column| matches!(column, Column::Annotation(..)));
+        for point in &track.points {
+            let annotations: Map<String, Value> = if with_annotations {
+                serde_json::from_str(&point.annotations).unwrap_or_default()
+            } else {
+                Map::new()
+            };
+            writer.write_record(
+                self.columns
+                    .iter()
+                    .map(|column| self.value(column, point, track.tz, device, &annotations)),
+            )?;
+        }
+        Ok(writer.into_inner()?)
+    }
+
+    fn value(
+        &self,
+        column: &Column,
+        point: &GpsPoint,
+        tz: Tz,
+        device: Option<&str>,
+        annotations: &Map<String, Value>,
+    ) -> String {
+        let opt = |value: Option<String>| value.unwrap_or_default();
+        match column {
+            Column::Device => device.unwrap_or_default().to_string(),
+            Column::Timestamp => match self.time_format {
+                TimeFormat::Iso => format_timestamp(point.ts, tz),
+                TimeFormat::Epoch => point.ts.to_string(),
+            },
+            Column::Latitude => format!("{:.7}", point.y),
+            Column::Longitude => format!("{:.7}", point.x),
+            Column::Speed => opt(point.speed.map(|v| v.to_string())),
+            Column::Elevation => opt(point.elevation.map(|v| v.to_string())),
+            Column::Accuracy => opt(point.accuracy.map(|v| v.to_string())),
+            Column::VAccuracy => opt(point.v_accuracy.map(|v| v.to_string())),
+            Column::Cog => opt(point.cog.map(|v| v.to_string())),
+            Column::Annotation(_, key) => match annotations.get(key) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+            },
+        }
+    }
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+/// Build CSV from track data.
+pub fn tracks(tracks: &[TrackData]) -> anyhow::Result<String> {
+    let options = CsvOptions::default();
+    let mut csv_data = options.header()?;
+    for track in tracks {
+        csv_data.extend(options.rows(track, None)?);
+    }
+    Ok(String::from_utf8(csv_data)?)
+}
+
+/// Stream CSV of a device track, querying one day after the other
+pub fn stream(
+    db: Db,
+    device_id: i32,
+    range: TimeRange,
+    filter: FilterParams,
+    options: CsvOptions,
+    device: Option<String>,
+) -> impl Stream<Item = anyhow::Result<Vec<u8>>> {
+    let header = stream::once(future::ready(options.header()));
+    header.chain(rows(db, device_id, range, None, filter, options, device))
+}
+
+/// Stream CSV of tracks of multiple devices with a device column
+pub fn stream_devices(
+    db: Db,
+    tracks: Vec<TrackRange>,
+) -> impl Stream<Item = anyhow::Result<Vec<u8>>> {
+    let options = CsvOptions::default().with_device();
+    let header = stream::once(future::ready(options.header()));
+    let rows = stream::iter(tracks).flat_map(move |track| {
+        rows(
+            db.clone(),
+            track.device_id,
+            track.range,
+            track.parts,
+            FilterParams::default(),
+            options.clone(),
+            Some(track.device),
+        )
+    });
+    header.chain(rows)
+}
+
+/// Chunk position of a streamed track
+struct Chunk {
+    from: i64,
+    /// Time zone and filter settings of the device
+    settings: Option<(Tz, FilterParams)>,
+    /// Last written point
+    last: Option<GpsPoint>,
+    /// Points at the end of the previous chunk, which are filtered with their successors
+    pending: Vec<GpsPoint>,
+}
+
+/// CSV lines of a device track, querying one day after the other.
+/// Filters continue across chunks. Only points within `parts` are written, if given.
+fn rows(
+    db: Db,
+    device_id: i32,
+    range: TimeRange,
+    parts: Option<Vec<TimeRange>>,
+    filter: FilterParams,
+    options: CsvOptions,
+    device: Option<String>,
+) -> impl Stream<Item = anyhow::Result<Vec<u8>>> {
+    let chunk = Chunk {
+        from: range.from,
+        settings: None,
+        last: None,
+        pending: Vec::new(),
+    };
+    stream::try_unfold(chunk, move |mut chunk| {
+        let (db, parts, filter, options, device) = (
+            db.clone(),
+            parts.clone(),
+            filter.clone(),
+            options.clone(),
+            device.clone(),
+        );
+        async move {
+            if chunk.from >= range.to {
+                return Ok(None);
+            }
+            let (tz, filter) = match chunk.settings.take() {
+                Some(settings) => settings,
+                None => (
+                    db.query_device_tz(device_id).await?,
+                    filter.or(&db.query_device_filter(device_id).await?),
+                ),
+            };
+            let to = (chunk.from + CHUNK_DURATION).min(range.to);
+            let mut points = std::mem::take(&mut chunk.pending);
+            points.extend(
+                db.query_points(
+                    device_id,
+                    TimeRange {
+                        from: chunk.from,
+                        to,
+                    },
+                )
+                .await?,
+            );
+            // Keep the last points, whose filtering depends on the next chunk
+            let cutoff = if to < range.to {
+                points
+                    .len()
+                    .checked_sub(PENDING_POINTS)
+                    .map_or(i64::MIN, |i| points[i].ts)
+            } else {
+                i64::MAX
+            };
+            chunk.pending = points
+                .iter()
+                .filter(|pt| pt.ts >= cutoff)
+                .cloned()
+                .collect();
+            let mut points = db
+                .process_points(chunk.last.as_ref(), points, &filter)
+                .await?;
+            points.retain(|pt| pt.ts < cutoff);
+            if let Some(last) = points.last() {
+                chunk.last = Some(last.clone());
+            }
+            if let Some(parts) = parts {
+                points.retain(|pt| parts.iter().any(|part| part.contains(pt.ts)));
+            }
+            let track = TrackData {
+                device_id,
+                date: String::new(),
+                tz,
+                points,
+            };
+            let csv = options.rows(&track, device.as_deref())?;
+            chunk.from = to;
+            chunk.settings = Some((tz, filter));
+            Ok(Some((csv, chunk)))
+        }
+    })
+}
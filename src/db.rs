@@ -1,33 +1,99 @@
+use crate::dem::{Dem, DemMode};
+use crate::events::{Event, Events, Transition, TransitionEvent};
+use crate::filter::{Filter, FilterParams};
+use crate::geofences::{self, Geofence, Shape};
 use crate::position::Position;
+use crate::simplify::Simplification;
+use crate::stats;
+use crate::timestamp::{format_timestamp, local_datetime, parse_tz, Period, TimeRange};
+use crate::trips::{Stay, Trip};
+use chrono::Utc;
+use chrono_tz::Tz;
+use futures_util::Stream;
 use serde::{ser::Error, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 use sqlx::migrate::{MigrateDatabase, Migrator};
-use sqlx::{AnyPool, Sqlite};
+use sqlx::{AnyConnection, AnyPool, Sqlite};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
 
 static MIGRATOR: Migrator = sqlx::migrate!();
 
+/// Maximal accepted clock skew of devices in seconds
+const MAX_FUTURE_OFFSET: i64 = 12 * 3600;
+/// Positions before 2000-01-01 are invalid (e.g. GPS week rollover or unset clock)
+const MIN_TIMESTAMP: i64 = 946_684_800;
+
 /// Track identification
 #[derive(sqlx::FromRow, Deserialize, Debug)]
 pub struct TrackRef {
     pub device_id: i32,
-    pub ts_start: String,
+    /// Start time (UNIX timestamp or ISO 8601)
+    pub from: Option<String>,
+    /// End time (exclusive). Default: one day after `from`
+    pub to: Option<String>,
+    /// Calendar day in the time zone of the device, if `from` is missing
+    pub date: Option<String>,
+    /// Timestamp within the day of the track, if `from` and `date` are missing
+    pub ts_start: Option<String>,
     /// Query segmented track
     pub segmented: Option<bool>,
+    /// Maximal accuracy in meters
+    pub max_accuracy: Option<i32>,
+    /// Drop points without accuracy
+    pub require_accuracy: Option<bool>,
+    /// Maximal speed to the previous point in km/h
+    pub max_speed: Option<f64>,
+    /// Minimal distance to the previous point in meters
+    pub min_distance: Option<f64>,
+    /// Drop repeated points at the same position
+    pub dedup: Option<bool>,
+    /// Elevations from DEM: `fill` or `replace`
+    pub dem: Option<DemMode>,
+    /// Kalman smoothing of coordinates
+    pub smooth: Option<bool>,
+    /// Simplification algorithm: `dp` (Douglas-Peucker, default) or `vw` (Visvalingam-Whyatt)
+    pub simplify: Option<String>,
+    /// Simplification tolerance in meters
+    pub tolerance: Option<f64>,
+    /// Simplify for display at web map zoom level
+    pub zoom: Option<u8>,
+    /// Simplify to a maximal number of points
+    pub max_points: Option<usize>,
+    /// Number of profile samples
+    pub samples: Option<usize>,
+    /// GPX version: `1.0` (default) or `1.1`
+    pub gpx_version: Option<String>,
+    /// Compressed KML
+    pub kmz: Option<bool>,
+    /// Activity sport: `other` (default), `running`, `cycling`, `walking` or `hiking`
+    pub sport: Option<String>,
+    /// Activity laps: `trip` (default) or `km`
+    pub laps: Option<String>,
+    /// Comma separated CSV columns
+    pub columns: Option<String>,
+    /// Comma separated annotation keys added as CSV columns
+    pub annotations: Option<String>,
+    /// CSV time format: `iso` (default) or `epoch`
+    pub time_format: Option<String>,
 }
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct TrackData {
     pub device_id: i32,
     pub date: String, // time::Date,
+    /// Time zone of the device
+    pub tz: Tz,
     pub points: Vec<GpsPoint>,
 }
 
-#[derive(sqlx::FromRow, Debug)]
+#[derive(sqlx::FromRow, Clone, Debug)]
 pub struct GpsPoint {
     pub y: f64,
     pub x: f64,
-    /// Timestamp in format 2025-02-19 06:46:54+00
-    pub ts: String, // DateTime<FixedOffset> is not supported by Any driver
+    /// UNIX timestamp
+    pub ts: i64,
     pub speed: Option<i16>,
     pub elevation: Option<i16>,
     /// Accuracy in meters
@@ -36,15 +102,43 @@ pub struct GpsPoint {
     pub v_accuracy: Option<i16>,
     pub cog: Option<i16>,
     pub annotations: String,
+    /// Speed calculated from positions
+    #[sqlx(skip)]
+    pub speed_derived: bool,
+    /// Course calculated from positions
+    #[sqlx(skip)]
+    pub cog_derived: bool,
+}
+
+#[cfg(test)]
+impl GpsPoint {
+    /// Point without optional values
+    pub fn new(x: f64, y: f64, ts: i64) -> Self {
+        GpsPoint {
+            y,
+            x,
+            ts,
+            speed: None,
+            elevation: None,
+            accuracy: None,
+            v_accuracy: None,
+            cog: None,
+            annotations: String::new(),
+            speed_derived: false,
+            cog_derived: false,
+        }
+    }
 }
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct DevicePosition {
     pub device_id: i32,
+    pub user_id: String,
+    pub device: String,
     pub y: f64,
     pub x: f64,
-    /// Timestamp in format 2025-02-19 06:46:54+00
-    pub ts: String, // DateTime<FixedOffset> is not supported by Any driver
+    /// UNIX timestamp
+    pub ts: i64,
     pub tid: String,
     pub speed: Option<i16>,
     pub elevation: Option<i16>,
@@ -53,6 +147,32 @@ pub struct DevicePosition {
     /// Vertical accuracy in meters
     pub v_accuracy: Option<i16>,
     pub cog: Option<i16>,
+    /// Time zone name of device or user
+    pub tz_name: Option<String>,
+    /// Time zone of the device
+    #[sqlx(skip)]
+    pub tz: Tz,
+}
+
+/// Stored position with device information
+#[derive(sqlx::FromRow, Debug)]
+pub struct PositionRow {
+    pub id: i32,
+    pub device_id: i32,
+    pub user_id: String,
+    pub device: String,
+    /// Current tracker ID of the device. Positions don't store a tracker ID.
+    pub tid: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// UNIX timestamp
+    pub ts: i64,
+    pub velocity: Option<i16>,
+    pub alt: Option<i16>,
+    pub accuracy: Option<i32>,
+    pub v_accuracy: Option<i16>,
+    pub cog: Option<i16>,
+    pub annotations: String,
 }
 
 #[derive(sqlx::FromRow, Serialize, Debug)]
@@ -61,54 +181,272 @@ pub struct TrackInfo {
     pub user_id: String,
     pub device: String,
     pub tid: String,
-    pub ts_start: String, // DateTime<FixedOffset> is not supported by Any driver
-    pub ts_end: String,   // DateTime<FixedOffset> is not supported by Any driver
+    /// First timestamp in RFC 3339 format
+    #[sqlx(skip)]
+    pub ts_start: String,
+    /// Last timestamp in RFC 3339 format
+    #[sqlx(skip)]
+    pub ts_end: String,
+    #[serde(skip)]
+    pub first_ts: i64,
+    #[serde(skip)]
+    pub last_ts: i64,
+    /// Time zone name of device or user
+    #[serde(skip)]
+    pub tz_name: Option<String>,
+    /// Time zone of the device
+    #[sqlx(skip)]
+    pub tz: Tz,
+    /// Start of queried time range
+    #[sqlx(skip)]
+    pub from: i64,
+    /// End of queried time range
+    #[sqlx(skip)]
+    pub to: i64,
 }
 
 impl TrackRef {
-    pub fn date(&self) -> String {
-        // from timestamp in format 2025-02-19 06:46:54+00
-        self.ts_start.split(' ').next().unwrap().to_string()
+    pub fn period(&self) -> anyhow::Result<Period> {
+        Period::from_params(
+            self.from.as_deref(),
+            self.to.as_deref(),
+            self.date.as_deref().or(self.ts_start.as_deref()),
+        )
+    }
+
+    /// Filter settings of the request
+    pub fn filter(&self) -> FilterParams {
+        FilterParams {
+            max_accuracy: self.max_accuracy,
+            require_accuracy: self.require_accuracy,
+            max_speed: self.max_speed,
+            min_distance: self.min_distance,
+            dedup: self.dedup,
+            dem: self.dem,
+        }
+    }
+
+    /// Requested simplification
+    pub fn simplification(&self) -> anyhow::Result<Option<Simplification>> {
+        Simplification::from_params(
+            self.simplify.as_deref(),
+            self.tolerance,
+            self.zoom,
+            self.max_points,
+        )
     }
+
+    /// Track label for file names
+    pub fn label(&self) -> String {
+        self.from
+            .as_deref()
+            .or(self.date.as_deref())
+            .or(self.ts_start.as_deref())
+            .unwrap_or_default()
+            .replace([' ', ':'], "_")
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct TripInfo {
+    /// Trip id. Not set for trips not stored yet.
+    pub id: Option<i32>,
+    pub device_id: i32,
+    pub user_id: String,
+    pub device: String,
+    pub tid: String,
+    /// Start timestamp in RFC 3339 format
+    #[sqlx(skip)]
+    pub ts_start: String,
+    /// End timestamp in RFC 3339 format
+    #[sqlx(skip)]
+    pub ts_end: String,
+    #[serde(skip)]
+    pub first_ts: i64,
+    #[serde(skip)]
+    pub last_ts: i64,
+    /// Distance in meters
+    pub distance: f64,
+    /// Duration in seconds
+    #[sqlx(skip)]
+    pub duration: i64,
+    /// Trip is not finished yet
+    #[sqlx(skip)]
+    pub open: bool,
+    /// Time zone of the device
+    #[sqlx(skip)]
+    pub tz: Tz,
+    /// Time range for track queries
+    #[sqlx(skip)]
+    pub from: i64,
+    #[sqlx(skip)]
+    pub to: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct Place {
+    pub id: i32,
+    pub name: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct VisitInfo {
+    /// Visit id. Not set for visits not stored yet.
+    pub id: Option<i32>,
+    pub device_id: i32,
+    pub user_id: String,
+    pub device: String,
+    /// Place id. Not set for unknown places of visits not stored yet.
+    pub place_id: Option<i32>,
+    /// Place name
+    pub place: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+    /// Arrival timestamp in RFC 3339 format
+    #[sqlx(skip)]
+    pub arrival: String,
+    /// Departure timestamp in RFC 3339 format
+    #[sqlx(skip)]
+    pub departure: String,
+    #[serde(skip)]
+    pub arrival_ts: i64,
+    #[serde(skip)]
+    pub departure_ts: i64,
+    /// Duration in seconds
+    #[sqlx(skip)]
+    pub duration: i64,
+    /// Device is still at the place
+    #[sqlx(skip)]
+    pub open: bool,
+}
+
+/// Stored geofence transition
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct TransitionInfo {
+    pub id: i32,
+    pub geofence_id: i32,
+    /// Geofence name
+    pub geofence: String,
+    pub device_id: i32,
+    pub user_id: String,
+    pub device: String,
+    /// `enter` or `leave`
+    pub event: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// Timestamp in RFC 3339 format
+    #[sqlx(skip)]
+    pub time: String,
+    #[serde(skip)]
+    pub ts: i64,
+}
+
+/// Visit statistics of a place
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct PlaceStats {
+    pub place_id: i32,
+    pub user_id: String,
+    pub name: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+    /// Number of visits
+    pub visits: i64,
+    /// Total duration of visits in seconds
+    pub duration: i64,
 }
 
 #[derive(Clone)]
 pub struct Db {
     pool: AnyPool,
+    /// Time zone of devices and users without configured time zone
+    default_tz: Tz,
+    /// Elevation models for elevation correction
+    dem: Option<Arc<Dem>>,
+    /// Events of inserted positions
+    events: Events,
+    /// PostgreSQL backend instead of SQLite
+    postgres: bool,
 }
 
 impl Db {
     pub async fn connect() -> anyhow::Result<Self> {
         let conn_str =
             dotenvy::var("DB_CONNECTION").unwrap_or("sqlite://tracking.sqlite".to_string());
+        Self::connect_to(&conn_str).await
+    }
+
+    /// Connect to a database, creating SQLite databases
+    pub async fn connect_to(conn_str: &str) -> anyhow::Result<Self> {
         if conn_str.starts_with("sqlite:")
-            && !Sqlite::database_exists(&conn_str).await.unwrap_or(false)
+            && !Sqlite::database_exists(conn_str).await.unwrap_or(false)
         {
             log::info!("Creating database {conn_str}");
-            Sqlite::create_database(&conn_str).await?;
+            Sqlite::create_database(conn_str).await?;
         }
         sqlx::any::install_default_drivers();
         log::info!("Connecting to database...");
-        let pool = AnyPool::connect(&conn_str).await?;
-        Ok(Db { pool })
+        let pool = AnyPool::connect(conn_str).await?;
+        let postgres = pool.acquire().await?.backend_name() == "PostgreSQL";
+        let default_tz = match dotenvy::var("DEFAULT_TZ") {
+            Ok(name) => parse_tz(&name)?,
+            Err(_) => Tz::UTC,
+        };
+        let dem = match dotenvy::var("DEM_DIR") {
+            Ok(dir) => Some(Arc::new(Dem::open(Path::new(&dir))?)),
+            Err(_) => None,
+        };
+        Ok(Db {
+            pool,
+            default_tz,
+            dem,
+            events: Events::default(),
+            postgres,
+        })
+    }
+
+    /// Time zone from a stored name or the default time zone
+    fn tz(&self, name: Option<&str>) -> Tz {
+        match name.map(parse_tz) {
+            Some(Ok(tz)) => tz,
+            Some(Err(e)) => {
+                log::warn!("{e}");
+                self.default_tz
+            }
+            None => self.default_tz,
+        }
     }
 
     pub async fn run_migrations(&self) -> anyhow::Result<()> {
         log::info!("Running database migrations...");
         MIGRATOR.run(&self.pool).await?;
-        let is_pg = self.pool.acquire().await?.backend_name() == "PostgreSQL";
-        if is_pg {
+        if self.postgres {
             let _result = sqlx::raw_sql(
                 r#"
                 CREATE SEQUENCE IF NOT EXISTS devices_id_seq;
                 ALTER TABLE devices ALTER COLUMN id SET DEFAULT NEXTVAL ('devices_id_seq');
                 CREATE SEQUENCE IF NOT EXISTS positions_id_seq;
                 ALTER TABLE positions ALTER COLUMN id SET DEFAULT NEXTVAL ('positions_id_seq');
+                CREATE SEQUENCE IF NOT EXISTS trips_id_seq;
+                ALTER TABLE trips ALTER COLUMN id SET DEFAULT NEXTVAL ('trips_id_seq');
+                CREATE SEQUENCE IF NOT EXISTS places_id_seq;
+                ALTER TABLE places ALTER COLUMN id SET DEFAULT NEXTVAL ('places_id_seq');
+                CREATE SEQUENCE IF NOT EXISTS visits_id_seq;
+                ALTER TABLE visits ALTER COLUMN id SET DEFAULT NEXTVAL ('visits_id_seq');
+                CREATE SEQUENCE IF NOT EXISTS geofences_id_seq;
+                ALTER TABLE geofences ALTER COLUMN id SET DEFAULT NEXTVAL ('geofences_id_seq');
+                CREATE SEQUENCE IF NOT EXISTS geofence_transitions_id_seq;
+                ALTER TABLE geofence_transitions ALTER COLUMN id SET DEFAULT NEXTVAL ('geofence_transitions_id_seq');
                 -- SQLite comaptible date/time functions
                 CREATE OR REPLACE FUNCTION unixepoch(bigint, varchar(20)) RETURNS TIMESTAMPTZ
                     AS 'select to_timestamp($1);'
                     LANGUAGE SQL
                     IMMUTABLE;
+                CREATE OR REPLACE FUNCTION unixepoch(TIMESTAMPTZ, varchar(20)) RETURNS BIGINT
+                    AS 'select extract(epoch from $1)::BIGINT;'
+                    LANGUAGE SQL
+                    IMMUTABLE;
                 CREATE OR REPLACE FUNCTION date(TIMESTAMPTZ, varchar(20)) RETURNS VARCHAR
                     AS 'select $1::DATE::VARCHAR;'
                     LANGUAGE SQL
@@ -133,89 +471,264 @@ impl Db {
         Ok(())
     }
 
+    /// Insert position, update last device position and store geofence transitions
+    /// within one transaction. Transitions are emitted after committing.
+    /// Returns `false` if the position was already stored.
     pub async fn insert_position(
         &self,
         user: &str,
         device: &str,
         pos: &Position,
-    ) -> anyhow::Result<()> {
-        // Upsert device position
-        let device_id: i64 = sqlx::query_scalar(r#"
-            INSERT INTO devices (user_id, device, tid, ts, velocity, lat, lon, alt, accuracy, v_accuracy, cog)
-            VALUES ($1, $2, $3, unixepoch($4, 'unixepoch'), $5, $6, $7, $8, $9, $10, $11)
-            ON CONFLICT(user_id, device) DO UPDATE
-            SET tid=$3, ts=unixepoch($4, 'unixepoch'), velocity=$5, lat=$6, lon=$7, alt=$8, accuracy=$8, v_accuracy=$10, cog=$11
-            RETURNING id"#
-        )
-        .bind(user)
-        .bind(device)
-        .bind(&pos.tid)
-        .bind(pos.ts)
-        .bind(pos.velocity.map(|val| val as i32)) // u16 is not supported by Any driver
-        .bind(pos.lat)
-        .bind(pos.lon)
-        .bind(pos.alt.map(|val| val as i32)) // u16 is not supported by Any driver
-        .bind(pos.accuracy.map(|val| val as i64)) // u32 is not supported by Any driver
-        .bind(pos.v_accuracy)
-        .bind(pos.cog)
-        .fetch_one(&self.pool)
-        .await?;
+    ) -> anyhow::Result<bool> {
+        check_timestamp(pos.ts)?;
+        // Updating the device first locks it until the end of the transaction, so that
+        // concurrent positions of the device see the transitions of each other
+        let mut tx = self.pool.begin().await?;
+        let device_id = upsert_device(&mut tx, user, device, pos).await?;
+        let inserted = insert_position_row(&mut tx, device_id, pos).await?;
+        let mut transitions = Vec::new();
+        if inserted {
+            reset_trips(&mut tx, device_id, pos.ts).await?;
+            transitions = check_geofences(&mut tx, device_id, user, device, pos).await?;
+        }
+        tx.commit().await?;
+        for transition in transitions {
+            log::info!(
+                "{user}/{device} {} {}",
+                transition.event.as_str(),
+                transition.geofence
+            );
+            self.events.emit(Event::Transition(transition));
+        }
+        Ok(inserted)
+    }
 
-        sqlx::query(
-            r#"INSERT INTO positions
-             (device_id, ts, velocity, lat, lon, alt, accuracy, v_accuracy, cog, annotations)
-              VALUES ($1, unixepoch($2, 'unixepoch'), $3, $4, $5, $6, $7, $8, $9, $10)"#,
-        )
-        .bind(device_id)
-        .bind(pos.ts)
-        .bind(pos.velocity.map(|val| val as i32)) // u16 is not supported by Any driver
-        .bind(pos.lat)
-        .bind(pos.lon)
-        .bind(pos.alt.map(|val| val as i32)) // u16 is not supported by Any driver
-        .bind(pos.accuracy.map(|val| val as i64)) // u32 is not supported by Any driver
-        .bind(pos.v_accuracy)
-        .bind(pos.cog)
-        .bind(&pos.annotations)
-        .execute(&self.pool)
-        .await?;
+    /// Receiver of events emitted after inserting positions
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
 
-        Ok(())
+    /// Insert positions of a device within one transaction.
+    /// Returns the number of inserted positions without invalid and duplicate positions.
+    pub async fn insert_positions(
+        &self,
+        user: &str,
+        device: &str,
+        positions: &[Position],
+    ) -> anyhow::Result<usize> {
+        let positions: Vec<&Position> = positions
+            .iter()
+            .filter(|pos| match check_timestamp(pos.ts) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("{e}");
+                    false
+                }
+            })
+            .collect();
+        let Some(last_pos) = positions.iter().max_by_key(|pos| pos.ts) else {
+            return Ok(0);
+        };
+        let mut tx = self.pool.begin().await?;
+        let device_id = upsert_device(&mut tx, user, device, last_pos).await?;
+        let mut count = 0;
+        let mut first_ts = None;
+        for pos in positions {
+            if insert_position_row(&mut tx, device_id, pos).await? {
+                count += 1;
+                first_ts = Some(first_ts.map_or(pos.ts, |ts: i64| ts.min(pos.ts)));
+            }
+        }
+        if let Some(ts) = first_ts {
+            reset_trips(&mut tx, device_id, ts).await?;
+        }
+        tx.commit().await?;
+        Ok(count)
     }
 
-    /// Return track infos of a given date
-    pub async fn query_tracks_info(&self, date: &str) -> anyhow::Result<Vec<TrackInfo>> {
-        let mut tracks: Vec<TrackInfo> = sqlx::query_as(
+    /// Return track infos of a period
+    pub async fn query_tracks_info(&self, period: Period) -> anyhow::Result<Vec<TrackInfo>> {
+        // Day boundaries depend on the time zone of each device. Positions are grouped in
+        // 15 minute intervals, which are within or outside of a day in any time zone.
+        let bounds = period.bounds();
+        let rows: Vec<TrackInfo> = sqlx::query_as(
             r#"SELECT
                 device_id,
-                user_id,
+                devices.user_id,
                 device,
                 devices.tid,
-                datetime(min(positions.ts), 'unixepoch') as ts_start,
-                datetime(max(positions.ts), 'unixepoch') as ts_end
+                COALESCE(devices.tz, users.tz) AS tz_name,
+                unixepoch(min(positions.ts), 'unixepoch') as first_ts,
+                unixepoch(max(positions.ts), 'unixepoch') as last_ts
             FROM positions
             JOIN devices ON positions.device_id = devices.id
-            WHERE date(positions.ts, 'unixepoch') = $1
-            GROUP BY device_id, user_id, device, devices.tid"#,
+            LEFT JOIN users ON users.user_id = devices.user_id
+            WHERE positions.ts >= unixepoch($1, 'unixepoch')
+            AND positions.ts < unixepoch($2, 'unixepoch')
+            GROUP BY device_id, devices.user_id, device, devices.tid, tz_name,
+                unixepoch(positions.ts, 'unixepoch') / 900"#,
         )
-        .bind(date)
+        .bind(bounds.from)
+        .bind(bounds.to)
         .fetch_all(&self.pool)
         .await?;
 
+        let mut tracks: BTreeMap<i32, TrackInfo> = BTreeMap::new();
+        for mut row in rows {
+            let tz = self.tz(row.tz_name.as_deref());
+            let range = period.time_range(tz);
+            if !range.contains(row.first_ts) {
+                continue;
+            }
+            let (first_ts, last_ts) = (row.first_ts, row.last_ts);
+            let track = tracks.entry(row.device_id).or_insert_with(|| {
+                row.tz = tz;
+                row.from = range.from;
+                row.to = range.to;
+                row
+            });
+            track.first_ts = track.first_ts.min(first_ts);
+            track.last_ts = track.last_ts.max(last_ts);
+        }
+        let mut tracks: Vec<TrackInfo> = tracks.into_values().collect();
+        for track in &mut tracks {
+            track.ts_start = format_timestamp(track.first_ts, track.tz);
+            track.ts_end = format_timestamp(track.last_ts, track.tz);
+        }
+
         // Sort the tracks by ts_end in descending order
-        tracks.sort_by(|a, b| b.ts_end.cmp(&a.ts_end));
+        tracks.sort_by_key(|track| std::cmp::Reverse(track.last_ts));
 
         Ok(tracks)
     }
 
-    /// Query a single track
-    pub async fn query_track(&self, track_ref: &TrackRef) -> anyhow::Result<TrackData> {
-        let date = track_ref.date();
-        let points: Vec<GpsPoint> = sqlx::query_as(
+    /// Return time zone of a device
+    pub async fn query_device_tz(&self, device_id: i32) -> anyhow::Result<Tz> {
+        let tz: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT COALESCE(devices.tz, users.tz)
+            FROM devices
+            LEFT JOIN users ON users.user_id = devices.user_id
+            WHERE devices.id = $1
+            "#,
+        )
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+        Ok(self.tz(tz.as_deref()))
+    }
+
+    /// Set time zone of a device. Returns `false` for unknown devices.
+    pub async fn set_device_tz(&self, user: &str, device: &str, tz: Tz) -> anyhow::Result<bool> {
+        let result = sqlx::query("UPDATE devices SET tz = $1 WHERE user_id = $2 AND device = $3")
+            .bind(tz.name())
+            .bind(user)
+            .bind(device)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set time zone of a user
+    pub async fn set_user_tz(&self, user: &str, tz: Tz) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO users (user_id, tz) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET tz = excluded.tz
+            "#,
+        )
+        .bind(user)
+        .bind(tz.name())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Filter points following the kept point `prev`, correct elevations and derive missing speed and course
+    pub async fn process_points(
+        &self,
+        prev: Option<&GpsPoint>,
+        points: Vec<GpsPoint>,
+        filter: &FilterParams,
+    ) -> anyhow::Result<Vec<GpsPoint>> {
+        let mut points = Filter::from(filter).apply(prev, points);
+        if let (Some(dem), Some(mode)) = (&self.dem, filter.dem) {
+            // DEM tiles are read from files on first use
+            let dem = dem.clone();
+            points = tokio::task::spawn_blocking(move || {
+                dem.apply(&mut points, mode);
+                points
+            })
+            .await?;
+        }
+        Ok(stats::with_motion(points))
+    }
+
+    /// Stored filter settings of a device
+    pub async fn query_device_filter(&self, device_id: i32) -> anyhow::Result<FilterParams> {
+        let filter: Option<String> = sqlx::query_scalar("SELECT filter FROM devices WHERE id = $1")
+            .bind(device_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .flatten();
+        match filter {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(FilterParams::default()),
+        }
+    }
+
+    /// Store filter settings of a device. Returns `false` for unknown devices.
+    pub async fn set_device_filter(
+        &self,
+        user: &str,
+        device: &str,
+        filter: &FilterParams,
+    ) -> anyhow::Result<bool> {
+        let result =
+            sqlx::query("UPDATE devices SET filter = $1 WHERE user_id = $2 AND device = $3")
+                .bind(serde_json::to_string(filter)?)
+                .bind(user)
+                .bind(device)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Query filtered track points. Missing filter settings are taken from the device.
+    pub async fn query_track(
+        &self,
+        device_id: i32,
+        period: Period,
+        filter: &FilterParams,
+    ) -> anyhow::Result<TrackData> {
+        let tz = self.query_device_tz(device_id).await?;
+        let filter = filter.or(&self.query_device_filter(device_id).await?);
+        let range = period.time_range(tz);
+        let points = self.query_points(device_id, range).await?;
+
+        let track = TrackData {
+            device_id,
+            date: local_datetime(range.from, tz).date_naive().to_string(),
+            tz,
+            points: self.process_points(None, points, &filter).await?,
+        };
+
+        Ok(track)
+    }
+
+    /// Unfiltered positions of a device within a time range
+    pub async fn query_points(
+        &self,
+        device_id: i32,
+        range: TimeRange,
+    ) -> anyhow::Result<Vec<GpsPoint>> {
+        let points = sqlx::query_as(
             r#"
                 SELECT
                     lat as y,
                     lon as x,
-                    datetime(ts, 'unixepoch') AS ts,
+                    unixepoch(ts, 'unixepoch') AS ts,
                     velocity as speed,
                     alt as elevation,
                     accuracy,
@@ -223,51 +736,649 @@ impl Db {
                     cog,
                     annotations
                 FROM positions
-                WHERE date(ts, 'unixepoch') = $1
-                AND device_id = $2
-                ORDER BY id
+                WHERE device_id = $1
+                AND ts >= unixepoch($2, 'unixepoch')
+                AND ts < unixepoch($3, 'unixepoch')
+                ORDER BY ts, id
                 "#,
         )
-        .bind(&date)
-        .bind(track_ref.device_id)
+        .bind(device_id)
+        .bind(range.from)
+        .bind(range.to)
         .fetch_all(&self.pool)
         .await?;
+        Ok(points)
+    }
 
-        let track = TrackData {
-            device_id: track_ref.device_id,
-            date,
-            points,
+    /// Return timestamp up to which positions of a device are segmented into trips
+    pub async fn query_trips_until(&self, device_id: i32) -> anyhow::Result<i64> {
+        let ts: Option<i64> = sqlx::query_scalar(
+            "SELECT unixepoch(trips_until, 'unixepoch') FROM devices WHERE id = $1",
+        )
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+        Ok(ts.unwrap_or(0))
+    }
+
+    /// Query positions of a device starting at a timestamp
+    pub async fn query_points_since(
+        &self,
+        device_id: i32,
+        since: i64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<GpsPoint>> {
+        let points = sqlx::query_as(
+            r#"
+                SELECT
+                    lat as y,
+                    lon as x,
+                    unixepoch(ts, 'unixepoch') AS ts,
+                    velocity as speed,
+                    alt as elevation,
+                    accuracy,
+                    v_accuracy,
+                    cog,
+                    annotations
+                FROM positions
+                WHERE device_id = $1
+                AND ts >= unixepoch($2, 'unixepoch')
+                ORDER BY ts, id
+                LIMIT $3
+                "#,
+        )
+        .bind(device_id)
+        .bind(since)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(points)
+    }
+
+    /// Query unfiltered positions of a device after a position (timestamp, id), ordered by time
+    pub async fn query_position_rows(
+        &self,
+        device_id: i32,
+        range: TimeRange,
+        after: (i64, i32),
+        limit: usize,
+    ) -> anyhow::Result<Vec<PositionRow>> {
+        let (after_ts, after_id) = after;
+        let rows = sqlx::query_as(
+            r#"
+                SELECT
+                    positions.id,
+                    device_id,
+                    user_id,
+                    device,
+                    devices.tid,
+                    positions.lat,
+                    positions.lon,
+                    unixepoch(positions.ts, 'unixepoch') AS ts,
+                    positions.velocity,
+                    positions.alt,
+                    positions.accuracy,
+                    positions.v_accuracy,
+                    positions.cog,
+                    annotations
+                FROM positions
+                JOIN devices ON positions.device_id = devices.id
+                WHERE device_id = $1
+                AND positions.ts < unixepoch($2, 'unixepoch')
+                AND (positions.ts > unixepoch($3, 'unixepoch')
+                    OR (positions.ts = unixepoch($3, 'unixepoch') AND positions.id > $4))
+                ORDER BY positions.ts, positions.id
+                LIMIT $5
+                "#,
+        )
+        .bind(device_id)
+        .bind(range.to)
+        .bind(after_ts)
+        .bind(after_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// Stream coordinates and timestamps (x, y, ts) of a device within a bounding box
+    pub fn stream_positions_in_bbox(
+        &self,
+        device_id: i32,
+        range: TimeRange,
+        bbox: [f64; 4],
+    ) -> impl Stream<Item = Result<(f64, f64, i64), sqlx::Error>> + '_ {
+        let [min_x, min_y, max_x, max_y] = bbox;
+        sqlx::query_as(
+            r#"
+                SELECT
+                    lon,
+                    lat,
+                    unixepoch(ts, 'unixepoch') AS ts
+                FROM positions
+                WHERE device_id = $1
+                AND ts >= unixepoch($2, 'unixepoch')
+                AND ts < unixepoch($3, 'unixepoch')
+                AND lon >= $4 AND lon <= $5
+                AND lat >= $6 AND lat <= $7
+                "#,
+        )
+        .bind(device_id)
+        .bind(range.from)
+        .bind(range.to)
+        .bind(min_x)
+        .bind(max_x)
+        .bind(min_y)
+        .bind(max_y)
+        .fetch(&self.pool)
+    }
+
+    /// Number of positions of a device within a bounding box per column and latitude bin
+    /// `(col, bin, north, count)`. Columns and bins of size `cell` are counted from `origin`
+    /// (x, y) towards east and north. `north` is 1 for positions north of the boundary
+    /// latitude of their bin in `boundaries`, and 0 in bins without boundary.
+    pub async fn query_position_grid(
+        &self,
+        device_id: i32,
+        range: TimeRange,
+        bbox: [f64; 4],
+        origin: (f64, f64),
+        cell: (f64, f64),
+        boundaries: &BTreeMap<i64, f64>,
+    ) -> anyhow::Result<Vec<(i64, i64, i64, i64)>> {
+        let [min_x, min_y, max_x, max_y] = bbox;
+        // Offsets from the origin are positive, so truncating is rounding down on SQLite,
+        // which has no FLOOR function without math functions
+        let floor = |expr: &str| {
+            if self.postgres {
+                format!("CAST(FLOOR({expr}) AS BIGINT)")
+            } else {
+                format!("CAST({expr} AS INTEGER)")
+            }
+        };
+        // VALUES needs at least one row, bins are never negative
+        let values = if boundaries.is_empty() {
+            "(-1, 90.0)".to_string()
+        } else {
+            (0..boundaries.len())
+                .map(|i| format!("(${}, ${})", 12 + 2 * i, 13 + 2 * i))
+                .collect::<Vec<_>>()
+                .join(", ")
         };
+        let sql = format!(
+            r#"
+                WITH boundaries (bin, boundary) AS (VALUES {values})
+                SELECT
+                    p.col,
+                    p.bin,
+                    CASE WHEN p.lat > b.boundary THEN 1 ELSE 0 END AS north,
+                    COUNT(*) AS count
+                FROM (
+                    SELECT {col} AS col, {bin} AS bin, lat
+                    FROM positions
+                    WHERE device_id = $1
+                    AND ts >= unixepoch($2, 'unixepoch')
+                    AND ts < unixepoch($3, 'unixepoch')
+                    AND lon >= $4 AND lon <= $5
+                    AND lat >= $6 AND lat <= $7
+                ) AS p
+                LEFT JOIN boundaries AS b ON b.bin = p.bin
+                GROUP BY p.col, p.bin, north
+                "#,
+            col = floor("(lon - $8) / $10"),
+            bin = floor("(lat - $9) / $11"),
+        );
+        let mut query = sqlx::query_as(&sql)
+            .bind(device_id)
+            .bind(range.from)
+            .bind(range.to)
+            .bind(min_x)
+            .bind(max_x)
+            .bind(min_y)
+            .bind(max_y)
+            .bind(origin.0)
+            .bind(origin.1)
+            .bind(cell.0)
+            .bind(cell.1);
+        for (bin, boundary) in boundaries {
+            query = query.bind(*bin).bind(*boundary);
+        }
+        Ok(query.fetch_all(&self.pool).await?)
+    }
 
-        Ok(track)
+    /// Store segmented trips, visits and the timestamp up to which positions are segmented
+    pub async fn store_segmentation(
+        &self,
+        device_id: i32,
+        trips: &[Trip],
+        visits: &[(&Stay, i32)],
+        until: i64,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for trip in trips {
+            sqlx::query(
+                r#"INSERT INTO trips (device_id, ts_start, ts_end, distance)
+                VALUES ($1, unixepoch($2, 'unixepoch'), unixepoch($3, 'unixepoch'), $4)"#,
+            )
+            .bind(device_id)
+            .bind(trip.ts_start)
+            .bind(trip.ts_end)
+            .bind(trip.distance)
+            .execute(&mut *tx)
+            .await?;
+        }
+        for (stay, place_id) in visits {
+            sqlx::query(
+                r#"INSERT INTO visits (device_id, place_id, arrival, departure, lat, lon)
+                VALUES ($1, $2, unixepoch($3, 'unixepoch'), unixepoch($4, 'unixepoch'), $5, $6)"#,
+            )
+            .bind(device_id)
+            .bind(place_id)
+            .bind(stay.arrival)
+            .bind(stay.departure)
+            .bind(stay.lat)
+            .bind(stay.lon)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("UPDATE devices SET trips_until = unixepoch($1, 'unixepoch') WHERE id = $2")
+            .bind(until)
+            .bind(device_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Return stored trips of a device overlapping a time range
+    pub async fn query_trips(
+        &self,
+        device: &DevicePosition,
+        range: TimeRange,
+    ) -> anyhow::Result<Vec<TripInfo>> {
+        let mut trips: Vec<TripInfo> = sqlx::query_as(
+            r#"SELECT
+                trips.id,
+                device_id,
+                user_id,
+                device,
+                devices.tid,
+                unixepoch(trips.ts_start, 'unixepoch') as first_ts,
+                unixepoch(trips.ts_end, 'unixepoch') as last_ts,
+                distance
+            FROM trips
+            JOIN devices ON trips.device_id = devices.id
+            WHERE device_id = $1
+            AND trips.ts_end >= unixepoch($2, 'unixepoch')
+            AND trips.ts_start < unixepoch($3, 'unixepoch')
+            ORDER BY trips.ts_start"#,
+        )
+        .bind(device.device_id)
+        .bind(range.from)
+        .bind(range.to)
+        .fetch_all(&self.pool)
+        .await?;
+        for trip in &mut trips {
+            trip.ts_start = format_timestamp(trip.first_ts, device.tz);
+            trip.ts_end = format_timestamp(trip.last_ts, device.tz);
+            trip.duration = trip.last_ts - trip.first_ts;
+            trip.tz = device.tz;
+            trip.from = trip.first_ts;
+            trip.to = trip.last_ts + 1;
+        }
+        Ok(trips)
+    }
+
+    /// Return places of a user
+    pub async fn query_places(&self, user: &str) -> anyhow::Result<Vec<Place>> {
+        let places = sqlx::query_as("SELECT id, name, lat, lon FROM places WHERE user_id = $1")
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(places)
+    }
+
+    /// Insert a new place of a user
+    pub async fn insert_place(
+        &self,
+        user: &str,
+        name: Option<&str>,
+        lat: f64,
+        lon: f64,
+    ) -> anyhow::Result<Place> {
+        let place = sqlx::query_as(
+            r#"INSERT INTO places (user_id, name, lat, lon) VALUES ($1, $2, $3, $4)
+            RETURNING id, name, lat, lon"#,
+        )
+        .bind(user)
+        .bind(name)
+        .bind(lat)
+        .bind(lon)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(place)
+    }
+
+    /// Rename a place. Returns `false` for unknown places.
+    pub async fn rename_place(&self, id: i32, name: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query("UPDATE places SET name = $1 WHERE id = $2")
+            .bind(name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Return geofences of all users or a single user
+    pub async fn query_geofences(&self, user: Option<&str>) -> anyhow::Result<Vec<Geofence>> {
+        let fences = sqlx::query_as(
+            r#"SELECT id, user_id, name, lat, lon, radius, polygon FROM geofences
+            WHERE $1 IS NULL OR user_id = $1 ORDER BY id"#,
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(fences)
+    }
+
+    pub async fn query_geofence(&self, id: i32) -> anyhow::Result<Option<Geofence>> {
+        let fence = sqlx::query_as(
+            "SELECT id, user_id, name, lat, lon, radius, polygon FROM geofences WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(fence)
+    }
+
+    /// Insert a new geofence of a user. Returns the geofence id.
+    pub async fn insert_geofence(
+        &self,
+        user: &str,
+        name: &str,
+        shape: &Shape,
+    ) -> anyhow::Result<i32> {
+        let (lat, lon, radius, polygon) = shape.columns();
+        let id = sqlx::query_scalar(
+            r#"INSERT INTO geofences (user_id, name, lat, lon, radius, polygon)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id"#,
+        )
+        .bind(user)
+        .bind(name)
+        .bind(lat)
+        .bind(lon)
+        .bind(radius)
+        .bind(polygon)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// Replace name and geometry of a geofence. Returns `false` for unknown geofences.
+    pub async fn update_geofence(
+        &self,
+        id: i32,
+        name: &str,
+        shape: &Shape,
+    ) -> anyhow::Result<bool> {
+        let (lat, lon, radius, polygon) = shape.columns();
+        let result = sqlx::query(
+            r#"UPDATE geofences SET name = $1, lat = $2, lon = $3, radius = $4, polygon = $5
+            WHERE id = $6"#,
+        )
+        .bind(name)
+        .bind(lat)
+        .bind(lon)
+        .bind(radius)
+        .bind(polygon)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Delete a geofence with its transitions. Returns `false` for unknown geofences.
+    pub async fn delete_geofence(&self, id: i32) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM geofence_transitions WHERE geofence_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM geofences WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Return geofence transitions of a device within a time range
+    pub async fn query_transitions(
+        &self,
+        device_id: i32,
+        range: TimeRange,
+    ) -> anyhow::Result<Vec<TransitionInfo>> {
+        let transitions = sqlx::query_as(
+            r#"SELECT
+                t.id,
+                geofence_id,
+                geofences.name AS geofence,
+                device_id,
+                devices.user_id,
+                device,
+                event,
+                t.lat,
+                t.lon,
+                unixepoch(t.ts, 'unixepoch') AS ts
+            FROM geofence_transitions t
+            JOIN geofences ON geofences.id = t.geofence_id
+            JOIN devices ON devices.id = t.device_id
+            WHERE device_id = $1
+              AND t.ts >= unixepoch($2, 'unixepoch') AND t.ts < unixepoch($3, 'unixepoch')
+            ORDER BY t.ts, t.id"#,
+        )
+        .bind(device_id)
+        .bind(range.from)
+        .bind(range.to)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(transitions)
+    }
+
+    /// Return stored visits of a device overlapping a time range
+    pub async fn query_visits(
+        &self,
+        device: &DevicePosition,
+        range: TimeRange,
+    ) -> anyhow::Result<Vec<VisitInfo>> {
+        let mut visits: Vec<VisitInfo> = sqlx::query_as(
+            r#"SELECT
+                visits.id,
+                device_id,
+                devices.user_id,
+                device,
+                place_id,
+                places.name AS place,
+                visits.lat,
+                visits.lon,
+                unixepoch(arrival, 'unixepoch') as arrival_ts,
+                unixepoch(departure, 'unixepoch') as departure_ts
+            FROM visits
+            JOIN devices ON visits.device_id = devices.id
+            JOIN places ON visits.place_id = places.id
+            WHERE device_id = $1
+            AND departure >= unixepoch($2, 'unixepoch')
+            AND arrival < unixepoch($3, 'unixepoch')
+            ORDER BY arrival"#,
+        )
+        .bind(device.device_id)
+        .bind(range.from)
+        .bind(range.to)
+        .fetch_all(&self.pool)
+        .await?;
+        for visit in &mut visits {
+            visit.arrival = format_timestamp(visit.arrival_ts, device.tz);
+            visit.departure = format_timestamp(visit.departure_ts, device.tz);
+            visit.duration = visit.departure_ts - visit.arrival_ts;
+        }
+        Ok(visits)
+    }
+
+    /// Return visit statistics per place of a device for visits starting within a time range
+    pub async fn query_place_stats(
+        &self,
+        device_id: i32,
+        range: TimeRange,
+    ) -> anyhow::Result<Vec<PlaceStats>> {
+        let stats = sqlx::query_as(
+            r#"SELECT
+                places.id AS place_id,
+                places.user_id,
+                places.name,
+                places.lat,
+                places.lon,
+                count(*) AS visits,
+                CAST(sum(unixepoch(departure, 'unixepoch') - unixepoch(arrival, 'unixepoch')) AS BIGINT) AS duration
+            FROM visits
+            JOIN places ON visits.place_id = places.id
+            WHERE device_id = $1
+            AND arrival >= unixepoch($2, 'unixepoch')
+            AND arrival < unixepoch($3, 'unixepoch')
+            GROUP BY places.id, places.user_id, places.name, places.lat, places.lon"#,
+        )
+        .bind(device_id)
+        .bind(range.from)
+        .bind(range.to)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(stats)
+    }
+
+    /// Return user names
+    pub async fn query_users(&self) -> anyhow::Result<Vec<String>> {
+        let users = sqlx::query_scalar("SELECT DISTINCT user_id FROM devices ORDER BY user_id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(users)
+    }
+
+    /// Return device names of a user
+    pub async fn query_devices(&self, user: &str) -> anyhow::Result<Vec<String>> {
+        let devices =
+            sqlx::query_scalar("SELECT device FROM devices WHERE user_id = $1 ORDER BY device")
+                .bind(user)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(devices)
+    }
+
+    /// Return id of a user device
+    pub async fn query_device_id(&self, user: &str, device: &str) -> anyhow::Result<Option<i32>> {
+        let device_id =
+            sqlx::query_scalar("SELECT id FROM devices WHERE user_id = $1 AND device = $2")
+                .bind(user)
+                .bind(device)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(device_id)
+    }
+
+    /// Return name in format `user/device` and tracker ID of a device
+    pub async fn query_device_label(
+        &self,
+        device_id: i32,
+    ) -> anyhow::Result<Option<(String, String)>> {
+        let label: Option<(String, String, String)> =
+            sqlx::query_as("SELECT user_id, device, tid FROM devices WHERE id = $1")
+                .bind(device_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(label.map(|(user, device, tid)| (format!("{user}/{device}"), tid)))
+    }
+
+    /// Return months with positions of a device in format YYYY-MM
+    pub async fn query_device_months(&self, device_id: i32) -> anyhow::Result<Vec<String>> {
+        let months = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT substr(date(ts, 'unixepoch'), 1, 7) AS month
+            FROM positions
+            WHERE device_id = $1
+            ORDER BY month
+            "#,
+        )
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(months)
     }
 
-    /// Return last device positions
-    pub async fn query_positions(&self, date: &str) -> anyhow::Result<Vec<DevicePosition>> {
-        let positions: Vec<DevicePosition> = sqlx::query_as(
+    /// Return last positions of all devices, optionally filtered by user and device
+    pub async fn query_last_positions(
+        &self,
+        user: Option<&str>,
+        device: Option<&str>,
+    ) -> anyhow::Result<Vec<DevicePosition>> {
+        self.query_device_positions(user, device, None).await
+    }
+
+    /// Return last device positions, optionally within a time range
+    async fn query_device_positions(
+        &self,
+        user: Option<&str>,
+        device: Option<&str>,
+        range: Option<TimeRange>,
+    ) -> anyhow::Result<Vec<DevicePosition>> {
+        let mut positions: Vec<DevicePosition> = sqlx::query_as(
             r#"
             SELECT
                 id as device_id,
+                devices.user_id,
+                device,
                 lat as y,
                 lon as x,
-                datetime(ts, 'unixepoch') AS ts,
+                unixepoch(ts, 'unixepoch') AS ts,
                 tid,
                 velocity as speed,
                 alt as elevation,
                 accuracy,
                 v_accuracy,
-                cog
+                cog,
+                COALESCE(devices.tz, users.tz) AS tz_name
             FROM devices
-            WHERE date(ts, 'unixepoch') = $1
+            LEFT JOIN users ON users.user_id = devices.user_id
+            WHERE ($1 IS NULL OR devices.user_id = $1)
+            AND ($2 IS NULL OR device = $2)
+            AND ($3 IS NULL OR devices.ts >= unixepoch($3, 'unixepoch'))
+            AND ($4 IS NULL OR devices.ts < unixepoch($4, 'unixepoch'))
+            ORDER BY devices.user_id, device
             "#,
         )
-        .bind(date)
+        .bind(user)
+        .bind(device)
+        .bind(range.map(|range| range.from))
+        .bind(range.map(|range| range.to))
         .fetch_all(&self.pool)
         .await?;
+        for pos in &mut positions {
+            pos.tz = self.tz(pos.tz_name.as_deref());
+        }
 
         Ok(positions)
     }
 
+    /// Return last device positions within a period
+    pub async fn query_positions(&self, period: Period) -> anyhow::Result<Vec<DevicePosition>> {
+        let bounds = period.bounds();
+        let mut positions = self
+            .query_device_positions(None, None, Some(bounds))
+            .await?;
+        // Day boundaries depend on the time zone of each device
+        positions.retain(|pos| period.time_range(pos.tz).contains(pos.ts));
+        Ok(positions)
+    }
+
     /// Check validity of invite
     pub async fn is_valid_invite(&self) -> anyhow::Result<bool> {
         // Check URL token for friend invites (TODO)
@@ -285,6 +1396,256 @@ impl Db {
     }
 }
 
+/// Store geofence transitions of the latest position of a device
+async fn check_geofences(
+    conn: &mut AnyConnection,
+    device_id: i64,
+    user: &str,
+    device: &str,
+    pos: &Position,
+) -> anyhow::Result<Vec<Transition>> {
+    if pos
+        .accuracy
+        .is_some_and(|acc| acc > geofences::MAX_ACCURACY)
+    {
+        return Ok(Vec::new());
+    }
+    let fences: Vec<Geofence> = sqlx::query_as(
+        "SELECT id, user_id, name, lat, lon, radius, polygon FROM geofences WHERE user_id = $1",
+    )
+    .bind(user)
+    .fetch_all(&mut *conn)
+    .await?;
+    if fences.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Delayed positions would change the state of the past
+    let latest: Option<i64> =
+        sqlx::query_scalar("SELECT unixepoch(ts, 'unixepoch') FROM devices WHERE id = $1")
+            .bind(device_id)
+            .fetch_one(&mut *conn)
+            .await?;
+    if latest.is_some_and(|ts| ts > pos.ts) {
+        return Ok(Vec::new());
+    }
+    // Last transition of each geofence
+    let last: Vec<(i32, String, i64)> = sqlx::query_as(
+        r#"SELECT geofence_id, event, unixepoch(ts, 'unixepoch') FROM geofence_transitions t
+        WHERE device_id = $1 AND id = (
+            SELECT max(id) FROM geofence_transitions
+            WHERE device_id = $1 AND geofence_id = t.geofence_id
+        )"#,
+    )
+    .bind(device_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    let (lat, lon) = (pos.lat as f64, pos.lon as f64);
+    let mut transitions = Vec::new();
+    for fence in fences {
+        let shape = match fence.shape() {
+            Ok(shape) => shape,
+            Err(e) => {
+                log::warn!("{e}");
+                continue;
+            }
+        };
+        let last = last.iter().find(|(id, _, _)| *id == fence.id);
+        if last.is_some_and(|(_, _, ts)| *ts >= pos.ts) {
+            continue;
+        }
+        let last_event = last.map(|(_, event, _)| match event.as_str() {
+            "enter" => TransitionEvent::Enter,
+            _ => TransitionEvent::Leave,
+        });
+        let Some(event) = geofences::transition(shape.contains(lon, lat), last_event) else {
+            continue;
+        };
+        sqlx::query(
+            r#"INSERT INTO geofence_transitions (geofence_id, device_id, ts, event, lat, lon)
+            VALUES ($1, $2, unixepoch($3, 'unixepoch'), $4, $5, $6)"#,
+        )
+        .bind(fence.id)
+        .bind(device_id)
+        .bind(pos.ts)
+        .bind(event.as_str())
+        .bind(lat)
+        .bind(lon)
+        .execute(&mut *conn)
+        .await?;
+        transitions.push(Transition {
+            geofence_id: fence.id,
+            geofence: fence.name,
+            device_id: device_id as i32,
+            user_id: user.to_string(),
+            device: device.to_string(),
+            tid: pos.tid.clone(),
+            event,
+            ts: pos.ts,
+            lat,
+            lon,
+            accuracy: pos.accuracy,
+        });
+    }
+    Ok(transitions)
+}
+
+/// Upsert device position, keeping the last position if the new one is older
+async fn upsert_device(
+    conn: &mut AnyConnection,
+    user: &str,
+    device: &str,
+    pos: &Position,
+) -> anyhow::Result<i64> {
+    let device_id: Option<i64> = sqlx::query_scalar(r#"
+        INSERT INTO devices (user_id, device, tid, ts, velocity, lat, lon, alt, accuracy, v_accuracy, cog)
+        VALUES ($1, $2, $3, unixepoch($4, 'unixepoch'), $5, $6, $7, $8, $9, $10, $11)
+        ON CONFLICT(user_id, device) DO UPDATE
+        SET tid=$3, ts=unixepoch($4, 'unixepoch'), velocity=$5, lat=$6, lon=$7, alt=$8, accuracy=$9, v_accuracy=$10, cog=$11
+        WHERE devices.ts <= unixepoch($4, 'unixepoch')
+        RETURNING id"#
+    )
+    .bind(user)
+    .bind(device)
+    .bind(&pos.tid)
+    .bind(pos.ts)
+    .bind(pos.velocity.map(|val| val as i32)) // u16 is not supported by Any driver
+    .bind(pos.lat)
+    .bind(pos.lon)
+    .bind(pos.alt.map(|val| val as i32)) // u16 is not supported by Any driver
+    .bind(pos.accuracy.map(|val| val as i64)) // u32 is not supported by Any driver
+    .bind(pos.v_accuracy)
+    .bind(pos.cog)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let device_id = match device_id {
+        Some(id) => id,
+        None => {
+            // Out-of-order position: device row was not updated
+            log::debug!("Delayed position of {user}/{device} at {}", pos.ts);
+            sqlx::query_scalar("SELECT id FROM devices WHERE user_id = $1 AND device = $2")
+                .bind(user)
+                .bind(device)
+                .fetch_one(&mut *conn)
+                .await?
+        }
+    };
+    Ok(device_id)
+}
+
+/// Insert position, skipping duplicates with the same device and timestamp
+async fn insert_position_row(
+    conn: &mut AnyConnection,
+    device_id: i64,
+    pos: &Position,
+) -> anyhow::Result<bool> {
+    let result = sqlx::query(
+        r#"INSERT INTO positions
+         (device_id, ts, velocity, lat, lon, alt, accuracy, v_accuracy, cog, annotations)
+          SELECT $1, unixepoch($2, 'unixepoch'), $3, $4, $5, $6, $7, $8, $9, $10
+          WHERE NOT EXISTS (
+            SELECT 1 FROM positions WHERE device_id = $1 AND ts = unixepoch($2, 'unixepoch')
+          )"#,
+    )
+    .bind(device_id)
+    .bind(pos.ts)
+    .bind(pos.velocity.map(|val| val as i32)) // u16 is not supported by Any driver
+    .bind(pos.lat)
+    .bind(pos.lon)
+    .bind(pos.alt.map(|val| val as i32)) // u16 is not supported by Any driver
+    .bind(pos.accuracy.map(|val| val as i64)) // u32 is not supported by Any driver
+    .bind(pos.v_accuracy)
+    .bind(pos.cog)
+    .bind(&pos.annotations)
+    .execute(&mut *conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Remove trips and visits after an inserted position for new segmentation
+async fn reset_trips(conn: &mut AnyConnection, device_id: i64, ts: i64) -> anyhow::Result<()> {
+    let until: Option<i64> =
+        sqlx::query_scalar("SELECT unixepoch(trips_until, 'unixepoch') FROM devices WHERE id = $1")
+            .bind(device_id)
+            .fetch_one(&mut *conn)
+            .await?;
+    if until.is_none_or(|until| until <= ts) {
+        return Ok(());
+    }
+    // Resume segmentation at the end of the last trip or stay before the position
+    let trip_end: Option<i64> = sqlx::query_scalar(
+        r#"SELECT unixepoch(max(ts_end), 'unixepoch') FROM trips
+        WHERE device_id = $1 AND ts_end < unixepoch($2, 'unixepoch')"#,
+    )
+    .bind(device_id)
+    .bind(ts)
+    .fetch_one(&mut *conn)
+    .await?;
+    let departure: Option<i64> = sqlx::query_scalar(
+        r#"SELECT unixepoch(max(departure), 'unixepoch') FROM visits
+        WHERE device_id = $1 AND departure < unixepoch($2, 'unixepoch')"#,
+    )
+    .bind(device_id)
+    .bind(ts)
+    .fetch_one(&mut *conn)
+    .await?;
+    let resume = match trip_end.max(departure) {
+        Some(resume) => resume,
+        None => {
+            // No earlier trip or stay: resume at the first affected trip or stay
+            let trip_start: Option<i64> = sqlx::query_scalar(
+                "SELECT unixepoch(min(ts_start), 'unixepoch') FROM trips WHERE device_id = $1",
+            )
+            .bind(device_id)
+            .fetch_one(&mut *conn)
+            .await?;
+            let arrival: Option<i64> = sqlx::query_scalar(
+                "SELECT unixepoch(min(arrival), 'unixepoch') FROM visits WHERE device_id = $1",
+            )
+            .bind(device_id)
+            .fetch_one(&mut *conn)
+            .await?;
+            [Some(ts), trip_start, arrival]
+                .into_iter()
+                .flatten()
+                .min()
+                .unwrap_or(ts)
+        }
+    };
+    sqlx::query("DELETE FROM trips WHERE device_id = $1 AND ts_end > unixepoch($2, 'unixepoch')")
+        .bind(device_id)
+        .bind(resume)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        "DELETE FROM visits WHERE device_id = $1 AND departure > unixepoch($2, 'unixepoch')",
+    )
+    .bind(device_id)
+    .bind(resume)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("UPDATE devices SET trips_until = unixepoch($1, 'unixepoch') WHERE id = $2")
+        .bind(resume)
+        .bind(device_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Reject timestamps in the future or before a plausible epoch
+fn check_timestamp(ts: i64) -> anyhow::Result<()> {
+    let now = Utc::now().timestamp();
+    if ts > now + MAX_FUTURE_OFFSET {
+        anyhow::bail!(
+            "Rejecting position with timestamp {ts} in the future ({}s ahead)",
+            ts - now
+        );
+    }
+    if ts < MIN_TIMESTAMP {
+        anyhow::bail!("Rejecting position with implausible timestamp {ts}");
+    }
+    Ok(())
+}
+
 pub fn serialize_raw_json<S: Serializer>(v: &str, s: S) -> Result<S::Ok, S::Error> {
     let v: serde_json::Value =
         serde_json::from_str(v).map_err(|_| Error::custom("error parsing serialized json"))?;
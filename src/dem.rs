@@ -0,0 +1,350 @@
+//! Elevation lookup in digital elevation models
+
+use crate::db::GpsPoint;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+/// Maximal number of tiles kept in memory
+const MAX_CACHED_TILES: usize = 8;
+/// Void value of SRTM HGT tiles
+const HGT_VOID: i16 = -32768;
+
+/// Usage of DEM elevations
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DemMode {
+    /// Use DEM elevations for points without GPS altitude
+    Fill,
+    /// Replace GPS altitudes with DEM elevations
+    Replace,
+}
+
+#[derive(Debug)]
+enum Format {
+    /// SRTM HGT tile
+    Hgt,
+    /// GeoTIFF in geographic coordinates (EPSG:4326)
+    GeoTiff,
+}
+
+#[derive(Debug)]
+struct TileInfo {
+    path: PathBuf,
+    format: Format,
+    /// minx, miny, maxx, maxy
+    bounds: [f64; 4],
+}
+
+/// Raster with sample coordinates `x0 + col * dx`, `y0 - row * dy`
+struct Grid {
+    x0: f64,
+    y0: f64,
+    dx: f64,
+    dy: f64,
+    width: usize,
+    height: usize,
+    /// Elevations in meters, NaN for voids
+    data: Vec<f32>,
+}
+
+/// Elevation models in a directory
+pub struct Dem {
+    tiles: Vec<TileInfo>,
+    /// Loaded tiles by index, least recently used first
+    cache: Mutex<Vec<(usize, Arc<Grid>)>>,
+}
+
+impl Dem {
+    /// Index SRTM HGT tiles and GeoTIFF files of a directory
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let mut tiles = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let ext = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_lowercase());
+            let tile = match ext.as_deref() {
+                Some("hgt") => hgt_info(&path),
+                Some("tif" | "tiff") => geotiff_info(&path),
+                _ => continue,
+            };
+            match tile {
+                Ok(tile) => tiles.push(tile),
+                Err(e) => log::warn!("Skipping DEM file {}: {e}", path.display()),
+            }
+        }
+        log::info!("{} DEM tiles found in {}", tiles.len(), dir.display());
+        Ok(Dem {
+            tiles,
+            cache: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Interpolated elevation in meters
+    pub fn elevation(&self, x: f64, y: f64) -> Option<f64> {
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter(|(_, tile)| {
+                let [minx, miny, maxx, maxy] = tile.bounds;
+                x >= minx && x <= maxx && y >= miny && y <= maxy
+            })
+            .find_map(|(idx, _)| self.grid(idx)?.sample(x, y))
+    }
+
+    /// Set elevations of track points
+    pub fn apply(&self, points: &mut [GpsPoint], mode: DemMode) {
+        for pt in points {
+            if mode == DemMode::Fill && pt.elevation.is_some() {
+                continue;
+            }
+            if let Some(elevation) = self.elevation(pt.x, pt.y) {
+                pt.elevation = Some(elevation.round() as i16);
+            }
+        }
+    }
+
+    fn grid(&self, idx: usize) -> Option<Arc<Grid>> {
+        if let Some(grid) = self.cached(idx) {
+            return Some(grid);
+        }
+        // Read the tile without holding the lock, so that lookups in cached tiles don't wait
+        let tile = &self.tiles[idx];
+        let grid = match tile.format {
+            Format::Hgt => read_hgt(tile),
+            Format::GeoTiff => read_geotiff(tile),
+        };
+        let grid = match grid {
+            Ok(grid) => Arc::new(grid),
+            Err(e) => {
+                log::error!("Failed to read DEM file {}: {e}", tile.path.display());
+                return None;
+            }
+        };
+        let mut cache = self.cache.lock().ok()?;
+        // Another lookup may have loaded the tile in the meantime
+        if !cache.iter().any(|(cached, _)| *cached == idx) {
+            if cache.len() >= MAX_CACHED_TILES {
+                cache.remove(0);
+            }
+            cache.push((idx, grid.clone()));
+        }
+        Some(grid)
+    }
+
+    /// Cached tile, marked as most recently used
+    fn cached(&self, idx: usize) -> Option<Arc<Grid>> {
+        let mut cache = self.cache.lock().ok()?;
+        let pos = cache.iter().position(|(cached, _)| *cached == idx)?;
+        let entry = cache.remove(pos);
+        let grid = entry.1.clone();
+        cache.push(entry);
+        Some(grid)
+    }
+}
+
+impl Grid {
+    /// Bilinear interpolation
+    fn sample(&self, x: f64, y: f64) -> Option<f64> {
+        let col = (x - self.x0) / self.dx;
+        let row = (self.y0 - y) / self.dy;
+        let max_col = (self.width - 1) as f64;
+        let max_row = (self.height - 1) as f64;
+        if !(0.0..=max_col).contains(&col) || !(0.0..=max_row).contains(&row) {
+            return None;
+        }
+        let (c0, r0) = (
+            col.floor().min(max_col - 1.0),
+            row.floor().min(max_row - 1.0),
+        );
+        let (fx, fy) = (col - c0, row - r0);
+        let value = |r: f64, c: f64| self.data[r as usize * self.width + c as usize] as f64;
+        let elevation = value(r0, c0) * (1.0 - fx) * (1.0 - fy)
+            + value(r0, c0 + 1.0) * fx * (1.0 - fy)
+            + value(r0 + 1.0, c0) * (1.0 - fx) * fy
+            + value(r0 + 1.0, c0 + 1.0) * fx * fy;
+        (!elevation.is_nan()).then_some(elevation)
+    }
+}
+
+/// Bounds from HGT file name like `N47E009.hgt`
+fn hgt_info(path: &Path) -> anyhow::Result<TileInfo> {
+    let name = path
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_uppercase();
+    if name.len() != 7 || !name.is_ascii() {
+        anyhow::bail!("Invalid HGT file name");
+    }
+    let lat: f64 = name[1..3].parse()?;
+    let lon: f64 = name[4..7].parse()?;
+    let lat = match &name[0..1] {
+        "N" => lat,
+        "S" => -lat,
+        _ => anyhow::bail!("Invalid HGT file name"),
+    };
+    let lon = match &name[3..4] {
+        "E" => lon,
+        "W" => -lon,
+        _ => anyhow::bail!("Invalid HGT file name"),
+    };
+    Ok(TileInfo {
+        path: path.to_path_buf(),
+        format: Format::Hgt,
+        bounds: [lon, lat, lon + 1.0, lat + 1.0],
+    })
+}
+
+/// Read HGT tile with 1201x1201 (3") or 3601x3601 (1") big-endian samples
+fn read_hgt(tile: &TileInfo) -> anyhow::Result<Grid> {
+    let mut bytes = Vec::new();
+    BufReader::new(File::open(&tile.path)?).read_to_end(&mut bytes)?;
+    let size = ((bytes.len() / 2) as f64).sqrt() as usize;
+    if size < 2 || size * size * 2 != bytes.len() {
+        anyhow::bail!("Unexpected HGT file size");
+    }
+    let data = bytes
+        .chunks_exact(2)
+        .map(|pair| match i16::from_be_bytes([pair[0], pair[1]]) {
+            HGT_VOID => f32::NAN,
+            value => value as f32,
+        })
+        .collect();
+    let [minx, _, _, maxy] = tile.bounds;
+    let step = 1.0 / (size - 1) as f64;
+    Ok(Grid {
+        x0: minx,
+        y0: maxy,
+        dx: step,
+        dy: step,
+        width: size,
+        height: size,
+        data,
+    })
+}
+
+/// Georeference of a GeoTIFF: upper left pixel center, pixel size and dimensions
+fn geotiff_georef(
+    decoder: &mut Decoder<BufReader<File>>,
+) -> anyhow::Result<(f64, f64, f64, f64, usize, usize)> {
+    let (width, height) = decoder.dimensions()?;
+    let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+    let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+    if scale.len() < 2 || tiepoint.len() < 6 {
+        anyhow::bail!("Missing georeference");
+    }
+    let (dx, dy) = (scale[0], scale[1]);
+    // Tie point at raster position (i, j) with pixel corner coordinates
+    let x0 = tiepoint[3] - tiepoint[0] * dx + dx / 2.0;
+    let y0 = tiepoint[4] + tiepoint[1] * dy - dy / 2.0;
+    Ok((x0, y0, dx, dy, width as usize, height as usize))
+}
+
+fn geotiff_info(path: &Path) -> anyhow::Result<TileInfo> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let (x0, y0, dx, dy, width, height) = geotiff_georef(&mut decoder)?;
+    Ok(TileInfo {
+        path: path.to_path_buf(),
+        format: Format::GeoTiff,
+        bounds: [
+            x0,
+            y0 - (height - 1) as f64 * dy,
+            x0 + (width - 1) as f64 * dx,
+            y0,
+        ],
+    })
+}
+
+fn read_geotiff(tile: &TileInfo) -> anyhow::Result<Grid> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(&tile.path)?))?;
+    let (x0, y0, dx, dy, width, height) = geotiff_georef(&mut decoder)?;
+    let nodata: Option<f64> = decoder
+        .get_tag_ascii_string(Tag::GdalNodata)
+        .ok()
+        .and_then(|value| value.trim_matches(char::from(0)).trim().parse().ok());
+    let data: Vec<f64> = match decoder.read_image()? {
+        DecodingResult::U8(data) => data.into_iter().map(f64::from).collect(),
+        DecodingResult::U16(data) => data.into_iter().map(f64::from).collect(),
+        DecodingResult::U32(data) => data.into_iter().map(f64::from).collect(),
+        DecodingResult::I8(data) => data.into_iter().map(f64::from).collect(),
+        DecodingResult::I16(data) => data.into_iter().map(f64::from).collect(),
+        DecodingResult::I32(data) => data.into_iter().map(f64::from).collect(),
+        DecodingResult::F32(data) => data.into_iter().map(f64::from).collect(),
+        DecodingResult::F64(data) => data,
+        _ => anyhow::bail!("Unsupported sample format"),
+    };
+    if data.len() != width * height {
+        anyhow::bail!("Only single band GeoTIFFs are supported");
+    }
+    let data = data
+        .into_iter()
+        .map(|value| match nodata {
+            Some(nodata) if value == nodata => f32::NAN,
+            _ => value as f32,
+        })
+        .collect();
+    Ok(Grid {
+        x0,
+        y0,
+        dx,
+        dy,
+        width,
+        height,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directory with 2x2 HGT tiles of constant elevation `100 * lon` in N47E000 to N47E009
+    fn hgt_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("owntrack-dem-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for lon in 0..10i16 {
+            let bytes: Vec<u8> = (0..4).flat_map(|_| (lon * 100).to_be_bytes()).collect();
+            std::fs::write(dir.join(format!("N47E{lon:03}.hgt")), bytes).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn lru_cache() {
+        let dir = hgt_dir();
+        let dem = Dem::open(&dir).unwrap();
+        assert_eq!(dem.tiles.len(), 10);
+        let idx = |lon: f64| {
+            dem.tiles
+                .iter()
+                .position(|tile| tile.bounds[0] == lon)
+                .unwrap()
+        };
+        let cached = || -> Vec<usize> {
+            let cache = dem.cache.lock().unwrap();
+            cache.iter().map(|(idx, _)| *idx).collect()
+        };
+        for lon in 0..8 {
+            let elevation = dem.elevation(lon as f64 + 0.5, 47.5);
+            assert_eq!(elevation, Some(lon as f64 * 100.0));
+        }
+        // Use the first tile again and load two more tiles
+        dem.elevation(0.5, 47.5);
+        dem.elevation(8.5, 47.5);
+        dem.elevation(9.5, 47.5);
+        let cached = cached();
+        assert_eq!(cached.len(), MAX_CACHED_TILES);
+        assert!(cached.contains(&idx(0.0)));
+        assert!(!cached.contains(&idx(1.0)));
+        assert!(!cached.contains(&idx(2.0)));
+        assert_eq!(cached.last(), Some(&idx(9.0)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Events of stored positions published to subscribers (MQTT, server-sent events)
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Number of buffered events per subscriber
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Event {
+    Transition(Transition),
+}
+
+#[derive(Clone, Copy, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransitionEvent {
+    Enter,
+    Leave,
+}
+
+impl TransitionEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransitionEvent::Enter => "enter",
+            TransitionEvent::Leave => "leave",
+        }
+    }
+}
+
+/// Device entered or left a geofence
+#[derive(Clone, Serialize, Debug)]
+pub struct Transition {
+    pub geofence_id: i32,
+    /// Geofence name
+    pub geofence: String,
+    pub device_id: i32,
+    pub user_id: String,
+    pub device: String,
+    pub tid: String,
+    pub event: TransitionEvent,
+    /// UNIX timestamp of the position
+    pub ts: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub accuracy: Option<u32>,
+}
+
+/// Broadcast channel of events
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Events(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    /// Send an event to all current subscribers
+    pub fn emit(&self, event: Event) {
+        // Sending fails without subscribers
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,249 @@
+//! Multi-track exports
+
+use crate::db::{Db, TrackData};
+use crate::filter::FilterParams;
+use crate::timestamp::{local_datetime, Period, TimeRange};
+use crate::{csv, geojson, gpx, trips};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Write};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// Splitting of exported tracks
+#[derive(Clone, Copy, Debug)]
+pub enum Split {
+    /// One track per device and calendar day
+    Day,
+    /// One track per trip
+    Trip,
+}
+
+/// Export file format
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Gpx(gpx::Version),
+    GeoJson,
+    Csv,
+    /// Row per position
+    GeoParquet,
+    /// Feature per position
+    FlatGeobuf,
+}
+
+impl Format {
+    pub fn from_name(name: &str, gpx_version: gpx::Version) -> anyhow::Result<Self> {
+        match name {
+            "gpx" => Ok(Format::Gpx(gpx_version)),
+            "geojson" => Ok(Format::GeoJson),
+            "csv" => Ok(Format::Csv),
+            "geoparquet" => Ok(Format::GeoParquet),
+            "flatgeobuf" => Ok(Format::FlatGeobuf),
+            _ => anyhow::bail!("Unsupported format `{name}`"),
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Gpx(_) => "gpx",
+            Format::GeoJson => "json",
+            Format::Csv => "csv",
+            Format::GeoParquet => "parquet",
+            Format::FlatGeobuf => "fgb",
+        }
+    }
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Gpx(_) => "application/gpx+xml",
+            Format::GeoJson => "application/geo+json",
+            Format::Csv => "text/csv",
+            Format::GeoParquet => "application/vnd.apache.parquet",
+            Format::FlatGeobuf => "application/octet-stream",
+        }
+    }
+    /// Positions are exported as points instead of tracks
+    pub fn is_point_format(&self) -> bool {
+        matches!(self, Format::GeoParquet | Format::FlatGeobuf)
+    }
+}
+
+/// Tracks of multiple devices
+pub struct Export {
+    pub tracks: Vec<TrackData>,
+    /// Device names (`user/device`) by device id
+    pub devices: HashMap<i32, String>,
+}
+
+/// Query tracks of all or selected devices within a period
+pub async fn query_tracks(
+    db: &Db,
+    device_ids: Option<&[i32]>,
+    period: Period,
+    split: Split,
+) -> anyhow::Result<Export> {
+    let trips = match split {
+        Split::Trip => trips::query_trips(db, device_ids, period).await?,
+        Split::Day => Vec::new(),
+    };
+    let mut export = Export {
+        tracks: Vec::new(),
+        devices: HashMap::new(),
+    };
+    for device in db.query_last_positions(None, None).await? {
+        if device_ids.is_some_and(|ids| !ids.contains(&device.device_id)) {
+            continue;
+        }
+        let track = db
+            .query_track(device.device_id, period, &FilterParams::default())
+            .await?;
+        let tz = track.tz;
+        match split {
+            Split::Day => {
+                let mut days: BTreeMap<String, Vec<_>> = BTreeMap::new();
+                for pt in track.points {
+                    let date = local_datetime(pt.ts, tz).date_naive().to_string();
+                    days.entry(date).or_default().push(pt);
+                }
+                export
+                    .tracks
+                    .extend(days.into_iter().map(|(date, points)| TrackData {
+                        device_id: device.device_id,
+                        date,
+                        tz,
+                        points,
+                    }));
+            }
+            Split::Trip => {
+                let mut device_trips: Vec<_> = trips
+                    .iter()
+                    .filter(|trip| trip.device_id == device.device_id)
+                    .collect();
+                device_trips.sort_by_key(|trip| trip.from);
+                let mut points = track.points.into_iter().peekable();
+                for trip in device_trips {
+                    // Skip points between trips
+                    while points.next_if(|pt| pt.ts < trip.from).is_some() {}
+                    let mut trip_points = Vec::new();
+                    while let Some(pt) = points.next_if(|pt| pt.ts < trip.to) {
+                        trip_points.push(pt);
+                    }
+                    if !trip_points.is_empty() {
+                        export.tracks.push(TrackData {
+                            device_id: device.device_id,
+                            date: trip.ts_start.clone(),
+                            tz,
+                            points: trip_points,
+                        });
+                    }
+                }
+            }
+        }
+        export.devices.insert(
+            device.device_id,
+            format!("{}/{}", device.user_id, device.device),
+        );
+    }
+    Ok(export)
+}
+
+/// Time range of the track of a device
+pub struct TrackRange {
+    pub device_id: i32,
+    /// `user/device`
+    pub device: String,
+    pub range: TimeRange,
+    /// Parts of the range with exported points. All points if missing.
+    pub parts: Option<Vec<TimeRange>>,
+}
+
+/// Time ranges of tracks of all or selected devices within a period
+pub async fn query_track_ranges(
+    db: &Db,
+    device_ids: Option<&[i32]>,
+    period: Period,
+    split: Split,
+) -> anyhow::Result<Vec<TrackRange>> {
+    let trips = match split {
+        Split::Trip => trips::query_trips(db, device_ids, period).await?,
+        Split::Day => Vec::new(),
+    };
+    let mut ranges = Vec::new();
+    for device in db.query_last_positions(None, None).await? {
+        if device_ids.is_some_and(|ids| !ids.contains(&device.device_id)) {
+            continue;
+        }
+        let parts = match split {
+            Split::Trip => {
+                let mut parts: Vec<_> = trips
+                    .iter()
+                    .filter(|trip| trip.device_id == device.device_id)
+                    .map(|trip| TimeRange {
+                        from: trip.from,
+                        to: trip.to,
+                    })
+                    .collect();
+                parts.sort_by_key(|part| part.from);
+                Some(parts)
+            }
+            Split::Day => None,
+        };
+        ranges.push(TrackRange {
+            device_id: device.device_id,
+            device: format!("{}/{}", device.user_id, device.device),
+            range: period.time_range(device.tz),
+            parts,
+        });
+    }
+    Ok(ranges)
+}
+
+/// Write all tracks into one file
+pub fn write(export: &Export, format: Format) -> anyhow::Result<String> {
+    match format {
+        Format::Gpx(gpx::Version::Gpx10) => gpx::tracks(&export.tracks),
+        Format::Gpx(gpx::Version::Gpx11) => gpx::tracks_v11(&export.tracks, &export.devices),
+        Format::GeoJson => geojson::track(&export.tracks),
+        Format::Csv => anyhow::bail!("CSV exports are streamed"),
+        Format::GeoParquet | Format::FlatGeobuf => {
+            anyhow::bail!(
+                "Point format `{}` is not a track format",
+                format.extension()
+            )
+        }
+    }
+}
+
+/// Write a ZIP archive with a file per track
+pub fn write_zip(export: Export, format: Format) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for track in export.tracks {
+        let device = export
+            .devices
+            .get(&track.device_id)
+            .cloned()
+            .unwrap_or_default();
+        let mut name = format!("{device}_{}", track.date).replace(['/', ' ', ':'], "_");
+        // Unique names for trips starting at the same time
+        let count = names.entry(name.clone()).or_default();
+        *count += 1;
+        if *count > 1 {
+            name = format!("{name}_{count}");
+        }
+        let tracks = [track];
+        let data = match format {
+            Format::Gpx(gpx::Version::Gpx10) => gpx::tracks(&tracks)?,
+            Format::Gpx(gpx::Version::Gpx11) => gpx::tracks_v11(&tracks, &export.devices)?,
+            Format::GeoJson => geojson::track(&tracks)?,
+            Format::Csv => csv::tracks(&tracks)?,
+            Format::GeoParquet | Format::FlatGeobuf => {
+                anyhow::bail!(
+                    "Point format `{}` is not a track format",
+                    format.extension()
+                )
+            }
+        };
+        zip.start_file(format!("{name}.{}", format.extension()), options)?;
+        zip.write_all(data.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
@@ -0,0 +1,261 @@
+//! Filter pipeline for track points
+
+use crate::db::GpsPoint;
+use crate::dem::DemMode;
+use crate::stats;
+use serde::{Deserialize, Serialize};
+
+/// Default maximal accuracy in meters
+const MAX_ACCURACY: i32 = 200;
+/// Default maximal speed between two points in km/h
+const MAX_SPEED: f64 = 500.0;
+
+/// Filter settings. Missing values are taken from the device settings or the defaults.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct FilterParams {
+    /// Maximal accuracy in meters (0: no accuracy filter). Default: 200
+    pub max_accuracy: Option<i32>,
+    /// Drop points without accuracy. Default: false
+    pub require_accuracy: Option<bool>,
+    /// Maximal speed to the previous point in km/h (0: no speed filter). Default: 500
+    pub max_speed: Option<f64>,
+    /// Minimal distance to the previous point in meters. Default: 0
+    pub min_distance: Option<f64>,
+    /// Drop repeated points at the same position. Default: true
+    pub dedup: Option<bool>,
+    /// Elevations from DEM. Default: GPS altitudes only
+    pub dem: Option<DemMode>,
+}
+
+impl FilterParams {
+    /// Settings with missing values taken from `defaults`
+    pub fn or(&self, defaults: &FilterParams) -> FilterParams {
+        FilterParams {
+            max_accuracy: self.max_accuracy.or(defaults.max_accuracy),
+            require_accuracy: self.require_accuracy.or(defaults.require_accuracy),
+            max_speed: self.max_speed.or(defaults.max_speed),
+            min_distance: self.min_distance.or(defaults.min_distance),
+            dedup: self.dedup.or(defaults.dedup),
+            dem: self.dem.or(defaults.dem),
+        }
+    }
+}
+
+/// Point filter with resolved settings
+#[derive(Clone, Debug)]
+pub struct Filter {
+    max_accuracy: i32,
+    require_accuracy: bool,
+    max_speed: f64,
+    min_distance: f64,
+    dedup: bool,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::from(&FilterParams::default())
+    }
+}
+
+impl From<&FilterParams> for Filter {
+    fn from(params: &FilterParams) -> Self {
+        Filter {
+            max_accuracy: params.max_accuracy.unwrap_or(MAX_ACCURACY),
+            require_accuracy: params.require_accuracy.unwrap_or(false),
+            max_speed: params.max_speed.unwrap_or(MAX_SPEED),
+            min_distance: params.min_distance.unwrap_or(0.0),
+            dedup: params.dedup.unwrap_or(true),
+        }
+    }
+}
+
+impl Filter {
+    /// Points passing all filters
+    pub fn select<'a>(&self, points: &'a [GpsPoint]) -> Vec<&'a GpsPoint> {
+        let keep = self.keep(None, points);
+        points
+            .iter()
+            .zip(keep)
+            .filter_map(|(pt, keep)| keep.then_some(pt))
+            .collect()
+    }
+
+    /// Remove points not passing all filters, continuing after the kept point `prev`
+    pub fn apply(&self, prev: Option<&GpsPoint>, points: Vec<GpsPoint>) -> Vec<GpsPoint> {
+        let keep = self.keep(prev, &points);
+        points
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(pt, keep)| keep.then_some(pt))
+            .collect()
+    }
+
+    fn keep(&self, prev: Option<&GpsPoint>, points: &[GpsPoint]) -> Vec<bool> {
+        let candidates: Vec<usize> = (0..points.len())
+            .filter(|&i| self.accurate(&points[i]))
+            .collect();
+        let mut keep = vec![false; points.len()];
+        let mut last = prev;
+        for (n, &i) in candidates.iter().enumerate() {
+            let pt = &points[i];
+            if last.is_none() && self.max_speed > 0.0 && self.first_spike(points, &candidates[n..])
+            {
+                continue;
+            }
+            if let Some(prev) = last {
+                let dist = distance(prev, pt);
+                if self.max_speed > 0.0 && speed(prev, pt, dist) > self.max_speed {
+                    // A spike is too fast from the previous and to the next point
+                    let next = candidates.get(n + 1).map(|&k| &points[k]);
+                    if next.is_none_or(|next| speed(pt, next, distance(pt, next)) > self.max_speed)
+                    {
+                        continue;
+                    }
+                }
+                if n + 1 < candidates.len() && dist < self.min_distance {
+                    continue;
+                }
+                if self.dedup {
+                    // Keep the first and last point of a stationary run for correct durations
+                    let next = candidates.get(n + 1).map(|&k| &points[k]);
+                    let repeated =
+                        dist == 0.0 && next.is_some_and(|next| next.x == pt.x && next.y == pt.y);
+                    if pt.ts == prev.ts || repeated {
+                        continue;
+                    }
+                }
+            }
+            keep[i] = true;
+            last = Some(pt);
+        }
+        keep
+    }
+
+    /// The first point is a spike if it is too fast to its successor,
+    /// while the successor is plausible to the following point
+    fn first_spike(&self, points: &[GpsPoint], candidates: &[usize]) -> bool {
+        let [first, next, after, ..] = candidates else {
+            return false;
+        };
+        let (first, next, after) = (&points[*first], &points[*next], &points[*after]);
+        speed(first, next, distance(first, next)) > self.max_speed
+            && speed(next, after, distance(next, after)) <= self.max_speed
+    }
+
+    fn accurate(&self, pt: &GpsPoint) -> bool {
+        match pt.accuracy {
+            Some(accuracy) => self.max_accuracy <= 0 || accuracy < self.max_accuracy,
+            None => !self.require_accuracy,
+        }
+    }
+}
+
+fn distance(p1: &GpsPoint, p2: &GpsPoint) -> f64 {
+    stats::distance((p1.x, p1.y), (p2.x, p2.y))
+}
+
+/// Speed in km/h
+fn speed(p1: &GpsPoint, p2: &GpsPoint, distance: f64) -> f64 {
+    let duration = (p2.ts - p1.ts).max(1) as f64;
+    distance / duration * 3.6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: i64 = 1_700_000_000;
+
+    /// Points moving east by about 7.6 m per 10 seconds
+    fn track(n: usize) -> Vec<GpsPoint> {
+        (0..n)
+            .map(|i| GpsPoint::new(8.0 + i as f64 * 0.0001, 47.0, START + i as i64 * 10))
+            .collect()
+    }
+
+    fn timestamps(points: &[GpsPoint]) -> Vec<i64> {
+        points.iter().map(|pt| pt.ts).collect()
+    }
+
+    #[test]
+    fn spike_in_first_point() {
+        let mut points = track(5);
+        points[0].x = 9.0;
+        let kept = Filter::default().apply(None, points);
+        assert_eq!(timestamps(&kept), timestamps(&track(5)[1..]));
+    }
+
+    #[test]
+    fn spike_within_track() {
+        let mut points = track(5);
+        points[2].y = 48.0;
+        let kept = Filter::default().apply(None, points);
+        assert_eq!(kept.len(), 4);
+        assert!(kept.iter().all(|pt| pt.y == 47.0));
+    }
+
+    #[test]
+    fn inaccurate_points() {
+        let mut points = track(4);
+        points[1].accuracy = Some(500);
+        points[2].accuracy = Some(20);
+        let kept = Filter::default().apply(None, points);
+        assert_eq!(timestamps(&kept), vec![START, START + 20, START + 30]);
+        let filter = Filter::from(&FilterParams {
+            require_accuracy: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(filter.apply(None, track(4)).len(), 0);
+    }
+
+    #[test]
+    fn min_distance() {
+        let filter = Filter::from(&FilterParams {
+            min_distance: Some(10.0),
+            ..Default::default()
+        });
+        let kept = filter.apply(None, track(6));
+        // Every second point and the last point
+        assert_eq!(
+            timestamps(&kept),
+            vec![START, START + 20, START + 40, START + 50]
+        );
+    }
+
+    #[test]
+    fn dedup() {
+        let mut points = track(3);
+        let mut stationary: Vec<GpsPoint> = (1..5)
+            .map(|i| GpsPoint::new(points[2].x, points[2].y, points[2].ts + i * 10))
+            .collect();
+        points.append(&mut stationary);
+        points.push(GpsPoint::new(8.001, 47.0, START + 100));
+        // Repeated timestamp
+        points.push(GpsPoint::new(8.0011, 47.0, START + 100));
+        let kept = Filter::default().select(&points);
+        // First and last point of the stationary run
+        assert_eq!(
+            kept.iter().map(|pt| pt.ts).collect::<Vec<_>>(),
+            vec![START, START + 10, START + 20, START + 60, START + 100]
+        );
+        let filter = Filter::from(&FilterParams {
+            dedup: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(filter.apply(None, points).len(), 9);
+    }
+
+    #[test]
+    fn continue_after_point() {
+        let points = track(6);
+        let filter = Filter::default();
+        let mut chunk = track(6).split_off(3);
+        // Spike at the start of the second chunk
+        chunk[0].x = 9.0;
+        let kept = filter.apply(points.get(2), chunk);
+        assert_eq!(timestamps(&kept), vec![START + 40, START + 50]);
+        // Repeated timestamp of the previous chunk
+        let kept = filter.apply(points.get(2), track(6).split_off(2));
+        assert_eq!(timestamps(&kept), timestamps(&points[3..]));
+    }
+}
@@ -0,0 +1,518 @@
+//! Garmin FIT activity export
+//!
+//! Writes the messages of the FIT activity file cookbook: file_id, events, records, laps, session and activity.
+
+use crate::activity::{laps, Lap, LapSplit, Sport};
+use crate::db::TrackData;
+use crate::timestamp::local_datetime;
+use chrono::Offset;
+
+/// UNIX timestamp of the FIT epoch (1989-12-31 00:00:00 UTC)
+const FIT_EPOCH: i64 = 631_065_600;
+const PROTOCOL_VERSION: u8 = 0x20;
+const PROFILE_VERSION: u16 = 2132;
+/// Manufacturer `development`
+const MANUFACTURER: i64 = 255;
+
+#[derive(Clone, Copy)]
+enum BaseType {
+    Enum,
+    Uint8,
+    Uint16,
+    Sint32,
+    Uint32,
+}
+
+impl BaseType {
+    fn id(self) -> u8 {
+        match self {
+            BaseType::Enum => 0x00,
+            BaseType::Uint8 => 0x02,
+            BaseType::Uint16 => 0x84,
+            BaseType::Sint32 => 0x85,
+            BaseType::Uint32 => 0x86,
+        }
+    }
+    fn size(self) -> u8 {
+        match self {
+            BaseType::Enum | BaseType::Uint8 => 1,
+            BaseType::Uint16 => 2,
+            BaseType::Sint32 | BaseType::Uint32 => 4,
+        }
+    }
+    /// Little-endian value, invalid value if missing or out of range
+    fn encode(self, value: Option<i64>, data: &mut Vec<u8>) {
+        match self {
+            BaseType::Enum | BaseType::Uint8 => {
+                let value = value.and_then(|v| u8::try_from(v).ok()).unwrap_or(u8::MAX);
+                data.push(value);
+            }
+            BaseType::Uint16 => {
+                let value = value
+                    .and_then(|v| u16::try_from(v).ok())
+                    .unwrap_or(u16::MAX);
+                data.extend(value.to_le_bytes());
+            }
+            BaseType::Sint32 => {
+                let value = value
+                    .and_then(|v| i32::try_from(v).ok())
+                    .unwrap_or(i32::MAX);
+                data.extend(value.to_le_bytes());
+            }
+            BaseType::Uint32 => {
+                let value = value
+                    .and_then(|v| u32::try_from(v).ok())
+                    .unwrap_or(u32::MAX);
+                data.extend(value.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Message definition with field numbers and types
+struct Message {
+    global: u16,
+    local: u8,
+    fields: &'static [(u8, BaseType)],
+}
+
+use BaseType::*;
+
+const FILE_ID: Message = Message {
+    global: 0,
+    local: 0,
+    // type, manufacturer, product, time_created
+    fields: &[(0, Enum), (1, Uint16), (2, Uint16), (4, Uint32)],
+};
+const EVENT: Message = Message {
+    global: 21,
+    local: 1,
+    // timestamp, event, event_type
+    fields: &[(253, Uint32), (0, Enum), (1, Enum)],
+};
+const RECORD: Message = Message {
+    global: 20,
+    local: 2,
+    // timestamp, position_lat, position_long, altitude, heart_rate, cadence, distance, speed
+    fields: &[
+        (253, Uint32),
+        (0, Sint32),
+        (1, Sint32),
+        (2, Uint16),
+        (3, Uint8),
+        (4, Uint8),
+        (5, Uint32),
+        (6, Uint16),
+    ],
+};
+const LAP: Message = Message {
+    global: 19,
+    local: 3,
+    // message_index, timestamp, event, event_type, start_time, start_position_lat,
+    // start_position_long, end_position_lat, end_position_long, total_elapsed_time,
+    // total_timer_time, total_distance, avg_speed, max_speed, avg_heart_rate,
+    // max_heart_rate, avg_cadence, lap_trigger, sport
+    fields: &[
+        (254, Uint16),
+        (253, Uint32),
+        (0, Enum),
+        (1, Enum),
+        (2, Uint32),
+        (3, Sint32),
+        (4, Sint32),
+        (5, Sint32),
+        (6, Sint32),
+        (7, Uint32),
+        (8, Uint32),
+        (9, Uint32),
+        (13, Uint16),
+        (14, Uint16),
+        (15, Uint8),
+        (16, Uint8),
+        (17, Uint8),
+        (24, Enum),
+        (25, Enum),
+    ],
+};
+const SESSION: Message = Message {
+    global: 18,
+    local: 4,
+    // message_index, timestamp, event, event_type, start_time, start_position_lat,
+    // start_position_long, sport, sub_sport, total_elapsed_time, total_timer_time,
+    // total_distance, avg_speed, max_speed, avg_heart_rate, max_heart_rate,
+    // first_lap_index, num_laps, trigger
+    fields: &[
+        (254, Uint16),
+        (253, Uint32),
+        (0, Enum),
+        (1, Enum),
+        (2, Uint32),
+        (3, Sint32),
+        (4, Sint32),
+        (5, Enum),
+        (6, Enum),
+        (7, Uint32),
+        (8, Uint32),
+        (9, Uint32),
+        (14, Uint16),
+        (15, Uint16),
+        (16, Uint8),
+        (17, Uint8),
+        (25, Uint16),
+        (26, Uint16),
+        (28, Enum),
+    ],
+};
+const ACTIVITY: Message = Message {
+    global: 34,
+    local: 5,
+    // timestamp, total_timer_time, num_sessions, type, event, event_type, local_timestamp
+    fields: &[
+        (253, Uint32),
+        (0, Uint32),
+        (1, Uint16),
+        (2, Enum),
+        (3, Enum),
+        (4, Enum),
+        (5, Uint32),
+    ],
+};
+
+// Event values
+const EVENT_TIMER: i64 = 0;
+const EVENT_SESSION: i64 = 8;
+const EVENT_LAP: i64 = 9;
+const EVENT_ACTIVITY: i64 = 26;
+const EVENT_TYPE_START: i64 = 0;
+const EVENT_TYPE_STOP: i64 = 1;
+const EVENT_TYPE_STOP_ALL: i64 = 4;
+// Lap trigger values
+const LAP_TRIGGER_MANUAL: i64 = 0;
+const LAP_TRIGGER_DISTANCE: i64 = 2;
+const LAP_TRIGGER_SESSION_END: i64 = 7;
+
+#[derive(Default)]
+struct FitWriter {
+    data: Vec<u8>,
+}
+
+impl FitWriter {
+    fn define(&mut self, msg: &Message) {
+        self.data.push(0x40 | msg.local);
+        // Reserved, little-endian architecture
+        self.data.extend([0, 0]);
+        self.data.extend(msg.global.to_le_bytes());
+        self.data.push(msg.fields.len() as u8);
+        for (num, base_type) in msg.fields {
+            self.data.extend([*num, base_type.size(), base_type.id()]);
+        }
+    }
+    fn write(&mut self, msg: &Message, values: &[Option<i64>]) {
+        debug_assert_eq!(values.len(), msg.fields.len());
+        self.data.push(msg.local);
+        for ((_, base_type), value) in msg.fields.iter().zip(values) {
+            base_type.encode(*value, &mut self.data);
+        }
+    }
+    /// File with header and CRC
+    fn finish(self) -> Vec<u8> {
+        let mut file = vec![14, PROTOCOL_VERSION];
+        file.extend(PROFILE_VERSION.to_le_bytes());
+        file.extend((self.data.len() as u32).to_le_bytes());
+        file.extend(b".FIT");
+        file.extend(crc(&file).to_le_bytes());
+        file.extend(self.data);
+        file.extend(crc(&file).to_le_bytes());
+        file
+    }
+}
+
+/// Build a FIT activity file with a session per track
+pub fn tracks(tracks: &[TrackData], sport: Sport, split: LapSplit) -> anyhow::Result<Vec<u8>> {
+    let sport = match sport {
+        Sport::Other => 0,
+        Sport::Running => 1,
+        Sport::Cycling => 2,
+        Sport::Walking => 11,
+        Sport::Hiking => 17,
+    };
+    let tracks: Vec<_> = tracks
+        .iter()
+        .map(|track| (track, laps(track, split)))
+        .filter(|(_, laps)| !laps.is_empty())
+        .collect();
+    let (Some((_, first_laps)), Some((last_track, last_laps))) = (tracks.first(), tracks.last())
+    else {
+        anyhow::bail!("No track points");
+    };
+    let ts_start = first_laps[0].ts_start;
+    let ts_end = last_laps[last_laps.len() - 1].ts_end;
+
+    let mut fit = FitWriter::default();
+    for msg in [&FILE_ID, &EVENT, &RECORD, &LAP, &SESSION, &ACTIVITY] {
+        fit.define(msg);
+    }
+    fit.write(
+        &FILE_ID,
+        &[
+            Some(4),
+            Some(MANUFACTURER),
+            Some(0),
+            Some(fit_time(ts_start)),
+        ],
+    );
+    let mut lap_index = 0;
+    let mut timer_time = 0;
+    for (session_index, (_, laps)) in tracks.iter().enumerate() {
+        let first_lap_index = lap_index;
+        for (i, lap) in laps.iter().enumerate() {
+            write_lap_records(&mut fit, lap);
+            write_lap(&mut fit, lap, lap_index, i + 1 == laps.len(), sport);
+            lap_index += 1;
+        }
+        let (first, last) = (&laps[0], &laps[laps.len() - 1]);
+        let start = &first.points[0];
+        let session_timer_time: i64 = laps.iter().map(Lap::duration).sum();
+        timer_time += session_timer_time;
+        let distance: f64 = laps.iter().map(|lap| lap.distance).sum();
+        let max_speed = laps.iter().filter_map(Lap::max_speed).reduce(f64::max);
+        let max_heart_rate = laps.iter().filter_map(Lap::max_heart_rate).reduce(f64::max);
+        let avg_heart_rate = {
+            let heart_rates: Vec<f64> = laps
+                .iter()
+                .flat_map(|lap| lap.points.iter().filter_map(|p| p.heart_rate))
+                .collect();
+            (!heart_rates.is_empty())
+                .then(|| heart_rates.iter().sum::<f64>() / heart_rates.len() as f64)
+        };
+        let avg_speed = (session_timer_time > 0).then(|| distance / session_timer_time as f64);
+        fit.write(
+            &SESSION,
+            &[
+                Some(session_index as i64),
+                Some(fit_time(last.ts_end)),
+                Some(EVENT_SESSION),
+                Some(EVENT_TYPE_STOP),
+                Some(fit_time(first.ts_start)),
+                Some(semicircles(start.pt.y)),
+                Some(semicircles(start.pt.x)),
+                Some(sport),
+                Some(0),
+                Some((last.ts_end - first.ts_start) * 1000),
+                Some(session_timer_time * 1000),
+                Some((distance * 100.0).round() as i64),
+                avg_speed.map(|speed| (speed * 1000.0).round() as i64),
+                max_speed.map(|speed| (speed * 1000.0).round() as i64),
+                avg_heart_rate.map(|hr| hr.round() as i64),
+                max_heart_rate.map(|hr| hr.round() as i64),
+                Some(first_lap_index),
+                Some(laps.len() as i64),
+                Some(0),
+            ],
+        );
+    }
+    let offset = local_datetime(ts_end, last_track.tz)
+        .offset()
+        .fix()
+        .local_minus_utc() as i64;
+    fit.write(
+        &ACTIVITY,
+        &[
+            Some(fit_time(ts_end)),
+            Some(timer_time * 1000),
+            Some(tracks.len() as i64),
+            Some(0),
+            Some(EVENT_ACTIVITY),
+            Some(EVENT_TYPE_STOP),
+            Some(fit_time(ts_end) + offset),
+        ],
+    );
+    Ok(fit.finish())
+}
+
+/// Timer start event, records and timer stop event of a lap
+fn write_lap_records(fit: &mut FitWriter, lap: &Lap) {
+    fit.write(
+        &EVENT,
+        &[
+            Some(fit_time(lap.points[0].pt.ts)),
+            Some(EVENT_TIMER),
+            Some(EVENT_TYPE_START),
+        ],
+    );
+    for p in &lap.points {
+        let pt = p.pt;
+        fit.write(
+            &RECORD,
+            &[
+                Some(fit_time(pt.ts)),
+                Some(semicircles(pt.y)),
+                Some(semicircles(pt.x)),
+                // Scale 5, offset 500
+                pt.elevation
+                    .map(|elevation| ((elevation as f64 + 500.0) * 5.0).round() as i64),
+                p.heart_rate.map(|hr| hr.round() as i64),
+                p.cadence.map(|cadence| cadence.round() as i64),
+                Some((p.distance * 100.0).round() as i64),
+                pt.speed
+                    .map(|speed| (speed as f64 / 3.6 * 1000.0).round() as i64),
+            ],
+        );
+    }
+    fit.write(
+        &EVENT,
+        &[
+            Some(fit_time(lap.ts_end)),
+            Some(EVENT_TIMER),
+            Some(EVENT_TYPE_STOP_ALL),
+        ],
+    );
+}
+
+fn write_lap(fit: &mut FitWriter, lap: &Lap, index: i64, last_lap: bool, sport: i64) {
+    let (first, last) = (&lap.points[0], &lap.points[lap.points.len() - 1]);
+    let trigger = if lap.distance_trigger {
+        LAP_TRIGGER_DISTANCE
+    } else if last_lap {
+        LAP_TRIGGER_SESSION_END
+    } else {
+        LAP_TRIGGER_MANUAL
+    };
+    fit.write(
+        &LAP,
+        &[
+            Some(index),
+            Some(fit_time(lap.ts_end)),
+            Some(EVENT_LAP),
+            Some(EVENT_TYPE_STOP),
+            Some(fit_time(lap.ts_start)),
+            Some(semicircles(first.pt.y)),
+            Some(semicircles(first.pt.x)),
+            Some(semicircles(last.pt.y)),
+            Some(semicircles(last.pt.x)),
+            Some(lap.duration() * 1000),
+            Some(lap.duration() * 1000),
+            Some((lap.distance * 100.0).round() as i64),
+            lap.avg_speed().map(|speed| (speed * 1000.0).round() as i64),
+            lap.max_speed().map(|speed| (speed * 1000.0).round() as i64),
+            lap.avg_heart_rate().map(|hr| hr.round() as i64),
+            lap.max_heart_rate().map(|hr| hr.round() as i64),
+            lap.avg_cadence().map(|cadence| cadence.round() as i64),
+            Some(trigger),
+            Some(sport),
+        ],
+    );
+}
+
+fn fit_time(ts: i64) -> i64 {
+    ts - FIT_EPOCH
+}
+
+/// Degrees in semicircles (2^31 / 180°)
+fn semicircles(degrees: f64) -> i64 {
+    (degrees * (2f64.powi(31) / 180.0)).round() as i64
+}
+
+/// FIT CRC-16
+fn crc(data: &[u8]) -> u16 {
+    const CRC_TABLE: [u16; 16] = [
+        0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401, 0xA001, 0x6C00, 0x7800,
+        0xB401, 0x5000, 0x9C01, 0x8801, 0x4400,
+    ];
+    data.iter().fold(0, |mut crc, byte| {
+        for nibble in [byte & 0x0F, byte >> 4] {
+            let tmp = CRC_TABLE[(crc & 0x0F) as usize];
+            crc = (crc >> 4) & 0x0FFF;
+            crc = crc ^ tmp ^ CRC_TABLE[nibble as usize];
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::GpsPoint;
+    use std::collections::HashMap;
+
+    const START: i64 = 1_700_000_000;
+
+    /// Walk north for 30 minutes with a point every 10 seconds
+    fn track() -> TrackData {
+        let points = (0..180)
+            .map(|i| {
+                let mut pt = GpsPoint::new(8.0, 47.0 + i as f64 * 0.0001, START + i * 10);
+                pt.elevation = Some(400 + i as i16);
+                pt.speed = Some(4);
+                pt
+            })
+            .collect();
+        TrackData {
+            device_id: 1,
+            date: "2023-11-14".to_string(),
+            tz: chrono_tz::UTC,
+            points,
+        }
+    }
+
+    #[test]
+    fn file_structure() {
+        let file = tracks(&[track()], Sport::Walking, LapSplit::Distance).unwrap();
+        // Header
+        assert_eq!(file[0], 14);
+        assert_eq!(file[1], PROTOCOL_VERSION);
+        assert_eq!(u16::from_le_bytes([file[2], file[3]]), PROFILE_VERSION);
+        assert_eq!(&file[8..12], b".FIT");
+        assert_eq!(u16::from_le_bytes([file[12], file[13]]), crc(&file[..12]));
+        let data_size = u32::from_le_bytes([file[4], file[5], file[6], file[7]]) as usize;
+        assert_eq!(file.len(), 14 + data_size + 2);
+        // A CRC over the data including the file CRC is 0
+        assert_eq!(crc(&file), 0);
+
+        // Definition and data messages
+        let mut definitions: HashMap<u8, (u16, Vec<u8>)> = HashMap::new();
+        let mut counts: HashMap<u16, usize> = HashMap::new();
+        let mut altitudes = Vec::new();
+        let data = &file[14..14 + data_size];
+        let mut pos = 0;
+        while pos < data.len() {
+            let header = data[pos];
+            let local = header & 0x0F;
+            pos += 1;
+            if header & 0x40 != 0 {
+                let global = u16::from_le_bytes([data[pos + 2], data[pos + 3]]);
+                let num_fields = data[pos + 4] as usize;
+                let fields = &data[pos + 5..pos + 5 + num_fields * 3];
+                let msg = [&FILE_ID, &EVENT, &RECORD, &LAP, &SESSION, &ACTIVITY]
+                    .into_iter()
+                    .find(|msg| msg.local == local)
+                    .unwrap();
+                assert_eq!(global, msg.global);
+                assert_eq!(num_fields, msg.fields.len());
+                let sizes = fields.chunks(3).map(|field| field[1]).collect();
+                definitions.insert(local, (global, sizes));
+                pos += 5 + num_fields * 3;
+            } else {
+                let (global, sizes) = &definitions[&local];
+                if *global == RECORD.global {
+                    // Altitude after timestamp, latitude and longitude
+                    let offset = pos + 12;
+                    altitudes.push(u16::from_le_bytes([data[offset], data[offset + 1]]));
+                }
+                pos += sizes.iter().map(|size| *size as usize).sum::<usize>();
+                *counts.entry(*global).or_default() += 1;
+            }
+        }
+        assert_eq!(pos, data.len());
+        assert_eq!(counts[&FILE_ID.global], 1);
+        assert_eq!(counts[&RECORD.global], 180);
+        // Two laps of about 1 km with a timer start and stop event each
+        assert_eq!(counts[&LAP.global], 2);
+        assert_eq!(counts[&EVENT.global], 4);
+        assert_eq!(counts[&SESSION.global], 1);
+        assert_eq!(counts[&ACTIVITY.global], 1);
+        // Scale 5, offset 500
+        assert_eq!(altitudes[0], 4500);
+        assert_eq!(altitudes[179], (579 + 500) * 5);
+    }
+}
@@ -0,0 +1,193 @@
+//! FlatGeobuf export with a point feature per position
+//!
+//! Header and features are encoded following the FlatGeobuf schemas
+//! (<https://github.com/flatgeobuf/flatgeobuf/tree/master/src/fbs>), without spatial index.
+
+use crate::db::{Db, PositionRow};
+use crate::points::{parse_annotations, position_batches, PointExport, ValueType};
+use crate::timestamp::format_timestamp;
+use chrono_tz::Tz;
+use flatbuffers::{FlatBufferBuilder, WIPOffset};
+use futures_util::future;
+use futures_util::stream::{self, Stream, StreamExt};
+
+const MAGIC: &[u8] = b"fgb\x03fgb\x00";
+/// GeometryType::Point
+const GEOMETRY_POINT: u8 = 1;
+
+/// Vtable offset of a table field
+const fn slot(field: u16) -> u16 {
+    4 + 2 * field
+}
+
+/// ColumnType of properties
+#[derive(Clone, Copy)]
+enum ColumnType {
+    Bool = 2,
+    Short = 3,
+    Int = 5,
+    Long = 7,
+    Double = 10,
+    String = 11,
+    Json = 12,
+    DateTime = 13,
+}
+
+/// Stream a FlatGeobuf file with the header followed by batches of features
+pub fn stream(db: Db, export: PointExport) -> impl Stream<Item = anyhow::Result<Vec<u8>>> {
+    let header = stream::once(future::ready(Ok(header(&export))));
+    let features = position_batches(db, export.ranges.clone()).map(move |rows| {
+        let mut data = Vec::new();
+        let mut fbb = FlatBufferBuilder::new();
+        for row in rows? {
+            write_feature(&mut fbb, &export, &row);
+            data.extend(fbb.finished_data());
+            fbb.reset();
+        }
+        Ok(data)
+    });
+    header.chain(features)
+}
+
+fn columns(export: &PointExport) -> Vec<(&str, ColumnType)> {
+    let mut columns = vec![
+        ("id", ColumnType::Int),
+        ("device_id", ColumnType::Int),
+        ("user_id", ColumnType::String),
+        ("device", ColumnType::String),
+        ("tid", ColumnType::String),
+        ("ts", ColumnType::DateTime),
+        ("lat", ColumnType::Double),
+        ("lon", ColumnType::Double),
+        ("velocity", ColumnType::Short),
+        ("alt", ColumnType::Short),
+        ("accuracy", ColumnType::Int),
+        ("v_accuracy", ColumnType::Short),
+        ("cog", ColumnType::Short),
+        ("annotations", ColumnType::Json),
+    ];
+    for column in &export.annotations {
+        let column_type = match column.value_type {
+            ValueType::Bool => ColumnType::Bool,
+            ValueType::Int => ColumnType::Long,
+            ValueType::Float => ColumnType::Double,
+            ValueType::String => ColumnType::String,
+        };
+        columns.push((&column.name, column_type));
+    }
+    columns
+}
+
+/// Magic bytes and size prefixed header
+fn header(export: &PointExport) -> Vec<u8> {
+    let mut fbb = FlatBufferBuilder::new();
+    let columns: Vec<_> = columns(export)
+        .into_iter()
+        .map(|(name, column_type)| {
+            let name = fbb.create_string(name);
+            let column = fbb.start_table();
+            fbb.push_slot_always(slot(0), name);
+            fbb.push_slot(slot(1), column_type as u8, 0);
+            fbb.end_table(column)
+        })
+        .collect();
+    let columns = fbb.create_vector(&columns);
+    let name = fbb.create_string("positions");
+    let org = fbb.create_string("EPSG");
+    let crs = fbb.start_table();
+    fbb.push_slot_always(slot(0), org);
+    fbb.push_slot(slot(1), 4326, 0);
+    let crs = fbb.end_table(crs);
+    let header = fbb.start_table();
+    fbb.push_slot_always(slot(0), name);
+    fbb.push_slot(slot(2), GEOMETRY_POINT, 0);
+    fbb.push_slot_always(slot(7), columns);
+    // Unknown number of features
+    fbb.push_slot(slot(8), 0u64, 0);
+    // No spatial index
+    fbb.push_slot(slot(9), 0u16, 16);
+    fbb.push_slot_always(slot(10), crs);
+    let header = fbb.end_table(header);
+    fbb.finish_size_prefixed(header, None);
+    [MAGIC, fbb.finished_data()].concat()
+}
+
+/// Size prefixed feature with point geometry and properties
+fn write_feature(fbb: &mut FlatBufferBuilder, export: &PointExport, row: &PositionRow) {
+    let mut properties = Properties::default();
+    properties.int(row.id);
+    properties.int(row.device_id);
+    properties.string(&row.user_id);
+    properties.string(&row.device);
+    properties.string(&row.tid);
+    properties.string(&format_timestamp(row.ts, Tz::UTC));
+    properties.double(row.lat);
+    properties.double(row.lon);
+    properties.opt(row.velocity, Properties::short);
+    properties.opt(row.alt, Properties::short);
+    properties.opt(row.accuracy, Properties::int);
+    properties.opt(row.v_accuracy, Properties::short);
+    properties.opt(row.cog, Properties::short);
+    properties.string(&row.annotations);
+    let annotations = parse_annotations(row);
+    for column in &export.annotations {
+        match column.value_type {
+            ValueType::Bool => properties.opt(column.bool(&annotations), Properties::bool),
+            ValueType::Int => properties.opt(column.int(&annotations), Properties::long),
+            ValueType::Float => properties.opt(column.float(&annotations), Properties::double),
+            ValueType::String => properties.opt(column.string(&annotations), |p, v| p.string(&v)),
+        }
+    }
+
+    let xy = fbb.create_vector(&[row.lon, row.lat]);
+    let geometry = fbb.start_table();
+    fbb.push_slot_always(slot(1), xy);
+    let geometry = fbb.end_table(geometry);
+    let properties = fbb.create_vector(&properties.data);
+    let feature = fbb.start_table();
+    fbb.push_slot_always::<WIPOffset<_>>(slot(0), geometry);
+    fbb.push_slot_always(slot(1), properties);
+    let feature = fbb.end_table(feature);
+    fbb.finish_size_prefixed(feature, None);
+}
+
+/// Properties buffer with column index and value of non-null values
+#[derive(Default)]
+struct Properties {
+    data: Vec<u8>,
+    column: u16,
+}
+
+impl Properties {
+    /// Add value of the next column
+    fn next(&mut self, value: &[u8]) {
+        self.data.extend(self.column.to_le_bytes());
+        self.data.extend(value);
+        self.column += 1;
+    }
+    /// Add optional value of the next column
+    fn opt<T>(&mut self, value: Option<T>, add: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(value) => add(self, value),
+            None => self.column += 1,
+        }
+    }
+    fn bool(&mut self, value: bool) {
+        self.next(&[value as u8]);
+    }
+    fn short(&mut self, value: i16) {
+        self.next(&value.to_le_bytes());
+    }
+    fn int(&mut self, value: i32) {
+        self.next(&value.to_le_bytes());
+    }
+    fn long(&mut self, value: i64) {
+        self.next(&value.to_le_bytes());
+    }
+    fn double(&mut self, value: f64) {
+        self.next(&value.to_le_bytes());
+    }
+    fn string(&mut self, value: &str) {
+        self.next(&[&(value.len() as u32).to_le_bytes(), value.as_bytes()].concat());
+    }
+}
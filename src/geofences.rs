@@ -0,0 +1,261 @@
+//! Server-defined geofences (circles and polygons) with enter/leave detection
+
+use crate::db::{Db, TransitionInfo};
+use crate::events::TransitionEvent;
+use crate::stats;
+use crate::timestamp::{format_timestamp, Period};
+use geo::Contains;
+use geo_types::{LineString, Point, Polygon};
+use geojson::{Feature, Geometry, JsonValue};
+
+/// Positions with a worse accuracy in meters are not evaluated
+pub const MAX_ACCURACY: u32 = 250;
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct Geofence {
+    pub id: i32,
+    pub user_id: String,
+    pub name: String,
+    /// Circle center
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    /// Circle radius in meters
+    pub radius: Option<f64>,
+    /// Polygon coordinates (GeoJSON)
+    pub polygon: Option<String>,
+}
+
+impl Geofence {
+    pub fn shape(&self) -> anyhow::Result<Shape> {
+        match (self.lat, self.lon, self.radius, &self.polygon) {
+            (Some(lat), Some(lon), Some(radius), _) => Ok(Shape::Circle { lat, lon, radius }),
+            (_, _, _, Some(polygon)) => Shape::polygon(serde_json::from_str(polygon)?),
+            _ => anyhow::bail!("Geofence {} without geometry", self.id),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Shape {
+    Circle {
+        lat: f64,
+        lon: f64,
+        radius: f64,
+    },
+    /// Exterior ring followed by holes with `[lon, lat]` positions
+    Polygon(Vec<Vec<Vec<f64>>>),
+}
+
+impl Shape {
+    /// Parse a GeoJSON Feature with a Point geometry and a `radius` property
+    /// or a Polygon geometry. Returns the `name` property and the shape.
+    pub fn from_feature(feature: &Feature) -> anyhow::Result<(String, Self)> {
+        let Some(JsonValue::String(name)) = feature.property("name") else {
+            anyhow::bail!("Missing geofence name");
+        };
+        let shape = match feature.geometry.as_ref().map(|geometry| &geometry.value) {
+            Some(geojson::Value::Point(position)) => {
+                let [lon, lat] = check_position(position)?;
+                let radius = feature
+                    .property("radius")
+                    .and_then(JsonValue::as_f64)
+                    .filter(|radius| *radius > 0.0 && radius.is_finite())
+                    .ok_or_else(|| anyhow::anyhow!("Missing or invalid radius of circle"))?;
+                Shape::Circle { lat, lon, radius }
+            }
+            Some(geojson::Value::Polygon(rings)) => Shape::polygon(rings.clone())?,
+            _ => anyhow::bail!("Geometry must be a Point with radius or a Polygon"),
+        };
+        Ok((name.clone(), shape))
+    }
+
+    fn polygon(mut rings: Vec<Vec<Vec<f64>>>) -> anyhow::Result<Self> {
+        if rings.is_empty() {
+            anyhow::bail!("Polygon without exterior ring");
+        }
+        for ring in &mut rings {
+            for position in ring.iter() {
+                check_position(position)?;
+            }
+            if ring.first() != ring.last() {
+                ring.push(ring[0].clone());
+            }
+            if ring.len() < 4 {
+                anyhow::bail!("Polygon ring with less than three positions");
+            }
+        }
+        Ok(Shape::Polygon(rings))
+    }
+
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        match self {
+            Shape::Circle {
+                lat: center_lat,
+                lon: center_lon,
+                radius,
+            } => stats::distance((*center_lon, *center_lat), (lon, lat)) <= *radius,
+            Shape::Polygon(rings) => {
+                let line_string = |ring: &Vec<Vec<f64>>| {
+                    LineString::from_iter(ring.iter().map(|position| (position[0], position[1])))
+                };
+                let polygon = Polygon::new(
+                    line_string(&rings[0]),
+                    rings[1..].iter().map(line_string).collect(),
+                );
+                polygon.contains(&Point::new(lon, lat))
+            }
+        }
+    }
+
+    /// GeoJSON geometry (center point of circles)
+    pub fn geometry(&self) -> Geometry {
+        match self {
+            Shape::Circle { lat, lon, .. } => {
+                Geometry::new(geojson::Value::Point(vec![*lon, *lat]))
+            }
+            Shape::Polygon(rings) => Geometry::new(geojson::Value::Polygon(rings.clone())),
+        }
+    }
+
+    /// Values of the `lat`, `lon`, `radius` and `polygon` columns
+    pub fn columns(&self) -> (Option<f64>, Option<f64>, Option<f64>, Option<String>) {
+        match self {
+            Shape::Circle { lat, lon, radius } => (Some(*lat), Some(*lon), Some(*radius), None),
+            Shape::Polygon(rings) => (None, None, None, serde_json::to_string(rings).ok()),
+        }
+    }
+}
+
+/// Position as `[lon, lat]` within valid coordinate ranges
+fn check_position(position: &[f64]) -> anyhow::Result<[f64; 2]> {
+    match position {
+        [lon, lat, ..] if (-180.0..=180.0).contains(lon) && (-90.0..=90.0).contains(lat) => {
+            Ok([*lon, *lat])
+        }
+        _ => anyhow::bail!("Invalid position {position:?}"),
+    }
+}
+
+/// Event of a position given the event of the last transition
+pub fn transition(inside: bool, last: Option<TransitionEvent>) -> Option<TransitionEvent> {
+    match (inside, last) {
+        (true, None | Some(TransitionEvent::Leave)) => Some(TransitionEvent::Enter),
+        (false, Some(TransitionEvent::Enter)) => Some(TransitionEvent::Leave),
+        _ => None,
+    }
+}
+
+/// Geofence transitions of all or selected devices within a period
+pub async fn query_transitions(
+    db: &Db,
+    device_ids: Option<&[i32]>,
+    period: Period,
+) -> anyhow::Result<Vec<TransitionInfo>> {
+    let mut transitions = Vec::new();
+    for device in db.query_last_positions(None, None).await? {
+        if device_ids.is_some_and(|ids| !ids.contains(&device.device_id)) {
+            continue;
+        }
+        let range = period.time_range(device.tz);
+        for mut transition in db.query_transitions(device.device_id, range).await? {
+            transition.time = format_timestamp(transition.ts, device.tz);
+            transitions.push(transition);
+        }
+    }
+    Ok(transitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TransitionEvent::{Enter, Leave};
+
+    fn feature(json: &str) -> Feature {
+        json.parse().unwrap()
+    }
+
+    #[test]
+    fn circle() {
+        let center = (8.0, 47.0);
+        let edge = (8.0, 47.01);
+        let radius = stats::distance(center, edge);
+        let shape = Shape::Circle {
+            lat: center.1,
+            lon: center.0,
+            radius,
+        };
+        assert!(shape.contains(center.0, center.1));
+        assert!(shape.contains(edge.0, edge.1));
+        assert!(!shape.contains(8.0, 47.0101));
+        assert!(!shape.contains(8.0, 46.9899));
+    }
+
+    #[test]
+    fn polygon_with_hole() {
+        // Open rings are closed
+        let shape = Shape::polygon(vec![
+            vec![
+                vec![8.0, 47.0],
+                vec![8.1, 47.0],
+                vec![8.1, 47.1],
+                vec![8.0, 47.1],
+            ],
+            vec![
+                vec![8.04, 47.04],
+                vec![8.06, 47.04],
+                vec![8.06, 47.06],
+                vec![8.04, 47.06],
+                vec![8.04, 47.04],
+            ],
+        ])
+        .unwrap();
+        assert!(shape.contains(8.02, 47.02));
+        assert!(shape.contains(8.08, 47.05));
+        assert!(!shape.contains(8.05, 47.05));
+        assert!(!shape.contains(8.15, 47.05));
+        assert!(!shape.contains(7.95, 47.05));
+
+        assert!(Shape::polygon(vec![]).is_err());
+        assert!(Shape::polygon(vec![vec![vec![8.0, 47.0], vec![8.1, 47.0]]]).is_err());
+    }
+
+    #[test]
+    fn transitions() {
+        assert_eq!(transition(true, None), Some(Enter));
+        assert_eq!(transition(false, None), None);
+        assert_eq!(transition(true, Some(Enter)), None);
+        assert_eq!(transition(false, Some(Enter)), Some(Leave));
+        assert_eq!(transition(true, Some(Leave)), Some(Enter));
+        assert_eq!(transition(false, Some(Leave)), None);
+    }
+
+    #[test]
+    fn features() {
+        let (name, shape) = Shape::from_feature(&feature(
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[8.54,47.37]},"properties":{"name":"Home","radius":150}}"#,
+        ))
+        .unwrap();
+        assert_eq!(name, "Home");
+        assert_eq!(
+            shape.columns(),
+            (Some(47.37), Some(8.54), Some(150.0), None)
+        );
+
+        let (_, shape) = Shape::from_feature(&feature(
+            r#"{"type":"Feature","geometry":{"type":"Polygon","coordinates":[[[8.0,47.0],[8.1,47.0],[8.1,47.1],[8.0,47.0]]]},"properties":{"name":"Work"}}"#,
+        ))
+        .unwrap();
+        assert!(matches!(shape, Shape::Polygon(_)));
+
+        for json in [
+            r#"{"type":"Feature","geometry":{"type":"LineString","coordinates":[[8.0,47.0],[8.1,47.0]]},"properties":{"name":"Road"}}"#,
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[8.54,47.37]},"properties":{"name":"Home"}}"#,
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[8.54,47.37]},"properties":{"name":"Home","radius":-150}}"#,
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[8.54,47.37]},"properties":{"radius":150}}"#,
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[8.54,97.37]},"properties":{"name":"Home","radius":150}}"#,
+            r#"{"type":"Feature","geometry":null,"properties":{"name":"Home"}}"#,
+        ] {
+            assert!(Shape::from_feature(&feature(json)).is_err(), "{json}");
+        }
+    }
+}
@@ -1,19 +1,32 @@
+use crate::coverage::DeviceCoverage;
 use crate::db::{DevicePosition, GpsPoint, TrackData};
-use crate::stats::{BboxStats, DistanceStats, ElevationDiffStats, TrackStats};
+use crate::geofences::Geofence;
+use crate::simplify::Simplification;
+use crate::stats::{BboxStats, DistanceStats, ElevationDiffStats, MovingStats, TrackStats};
+use crate::timestamp::format_timestamp;
+use chrono_tz::Tz;
 use geojson::{Feature, FeatureCollection, Geometry, JsonObject, JsonValue};
 
-const MAX_ACCURACY: i32 = 200; // meters
 const ANNOTATIONS_SKIP_LIST: &[&str] = &["_id", "m", "BSSID", "SSID", "created_at"];
 
-fn point_properties(pt: &GpsPoint) -> JsonObject {
+fn point_properties(pt: &GpsPoint, tz: Tz) -> JsonObject {
     let mut json = JsonObject::from_iter([
-        ("time".to_string(), JsonValue::from(pt.ts.to_string())),
+        (
+            "time".to_string(),
+            JsonValue::from(format_timestamp(pt.ts, tz)),
+        ),
         ("speed".to_string(), JsonValue::from(pt.speed)),
         ("elevation".to_string(), JsonValue::from(pt.elevation)),
         ("accuracy".to_string(), JsonValue::from(pt.accuracy)),
         ("v_accuracy".to_string(), JsonValue::from(pt.v_accuracy)),
         ("cog".to_string(), JsonValue::from(pt.cog)),
     ]);
+    if pt.speed_derived {
+        json.insert("speed_derived".to_string(), JsonValue::from(true));
+    }
+    if pt.cog_derived {
+        json.insert("cog_derived".to_string(), JsonValue::from(true));
+    }
     let annotations: serde_json::Map<String, serde_json::Value> =
         serde_json::from_str(pt.annotations.as_str()).unwrap();
     json.extend(
@@ -29,16 +42,18 @@ pub fn track(tracks: &[TrackData]) -> anyhow::Result<String> {
     let features: Vec<Feature> = tracks
         .iter()
         .map(|track| {
-            let mut points = track.points.iter().filter(|point| {
-                // keep only points within accuracy
-                point.accuracy.unwrap_or(0) < MAX_ACCURACY
-            });
+            let mut points = track.points.iter();
             let geometry = Geometry::new(geojson::Value::LineString(
                 points.clone().map(|pt| vec![pt.x, pt.y]).collect(),
             ));
             let bbox = BboxStats::from_xy_iter(points.clone().map(|pt| (pt.x, pt.y))).bbox();
             // Use properties of last point
-            let properties = points.next_back().map(point_properties);
+            let properties = points.next_back().map(|pt| {
+                let mut properties = point_properties(pt, track.tz);
+                properties.insert("device_id".to_string(), JsonValue::from(track.device_id));
+                properties.insert("date".to_string(), JsonValue::from(track.date.clone()));
+                properties
+            });
             Feature {
                 geometry: Some(geometry),
                 properties,
@@ -64,16 +79,12 @@ pub fn track_with_segments(tracks: &[TrackData]) -> anyhow::Result<String> {
             let segments: Vec<Feature> = track
                 .points
                 .iter()
-                .filter(|point| {
-                    // keep only points within accuracy
-                    point.accuracy.unwrap_or(0) < MAX_ACCURACY
-                })
                 .collect::<Vec<_>>()
                 .windows(2)
                 .map(|pts| {
                     let line = vec![vec![pts[0].x, pts[0].y], vec![pts[1].x, pts[1].y]];
                     let geometry = Geometry::new(geojson::Value::LineString(line));
-                    let mut properties = point_properties(pts[0]);
+                    let mut properties = point_properties(pts[0], track.tz);
                     properties.extend([("trackno".to_string(), JsonValue::from(no))]);
                     Feature {
                         geometry: Some(geometry),
@@ -93,21 +104,24 @@ pub fn track_with_segments(tracks: &[TrackData]) -> anyhow::Result<String> {
     Ok(geojson.to_string())
 }
 
-/// Build a GeoJSON Point FeatureCollection
-pub fn track_points(tracks: &[TrackData]) -> anyhow::Result<String> {
-    let feat_iter = tracks
+/// Build a GeoJSON Point FeatureCollection.
+/// Statistics are calculated from all points, also when simplified.
+pub fn track_points(
+    tracks: &[TrackData],
+    simplification: Option<&Simplification>,
+) -> anyhow::Result<String> {
+    let tz = tracks.first().map(|track| track.tz).unwrap_or_default();
+    let feat_iter = tracks.iter().flat_map(|track| track.points.iter());
+    let features = tracks
         .iter()
-        .flat_map(|track| track.points.iter())
-        .filter(|point| {
-            // keep only points within accuracy
-            point.accuracy.unwrap_or(0) < MAX_ACCURACY
-        });
-    let features = feat_iter
-        .clone()
+        .flat_map(|track| match simplification {
+            Some(simplification) => simplification.select(&track.points),
+            None => track.points.iter().collect(),
+        })
         .enumerate()
         .map(|(idx, pt)| {
             let geometry = Geometry::new(geojson::Value::Point(vec![pt.x, pt.y]));
-            let properties = point_properties(pt);
+            let properties = point_properties(pt, tz);
             Feature {
                 id: Some(geojson::feature::Id::Number(serde_json::Number::from(idx))),
                 geometry: Some(geometry),
@@ -119,11 +133,12 @@ pub fn track_points(tracks: &[TrackData]) -> anyhow::Result<String> {
 
     let bbox = BboxStats::from_xy_iter(feat_iter.clone().map(|pt| (pt.x, pt.y))).bbox();
 
-    let mut stats = TrackStats::from_iter(feat_iter.clone()).as_properties();
+    let mut stats = TrackStats::from_iter(feat_iter.clone()).as_properties(tz);
     stats.extend(
         ElevationDiffStats::from_iter(feat_iter.clone().filter_map(|pt| pt.elevation))
             .as_properties(),
     );
+    stats.extend(MovingStats::from_iter(feat_iter.clone()).as_properties());
     stats.extend(DistanceStats::from_xy_iter(feat_iter.map(|pt| (pt.x, pt.y))).as_properties());
     let stats_json = JsonObject::from_iter([("stats".to_string(), JsonValue::from(stats))]);
 
@@ -143,7 +158,10 @@ pub fn positions(points: &[DevicePosition]) -> anyhow::Result<String> {
             let geometry = Geometry::new(geojson::Value::Point(vec![pt.x, pt.y]));
             let properties = JsonObject::from_iter([
                 ("device_id".to_string(), JsonValue::from(pt.device_id)),
-                ("time".to_string(), JsonValue::from(pt.ts.to_string())),
+                (
+                    "time".to_string(),
+                    JsonValue::from(format_timestamp(pt.ts, pt.tz)),
+                ),
                 ("tid".to_string(), JsonValue::from(pt.tid.clone())),
                 ("speed".to_string(), JsonValue::from(pt.speed)),
                 ("elevation".to_string(), JsonValue::from(pt.elevation)),
@@ -170,3 +188,98 @@ pub fn positions(points: &[DevicePosition]) -> anyhow::Result<String> {
     };
     Ok(geojson.to_string())
 }
+
+/// Build a GeoJSON Polygon FeatureCollection of visited grid cells
+pub fn coverage(coverage: &[DeviceCoverage]) -> anyhow::Result<String> {
+    let features = coverage
+        .iter()
+        .flat_map(|device| {
+            device.cells.iter().map(|cell| {
+                let [min_x, min_y, max_x, max_y] = cell.tile.bounds();
+                let ring = vec![
+                    vec![min_x, min_y],
+                    vec![max_x, min_y],
+                    vec![max_x, max_y],
+                    vec![min_x, max_y],
+                    vec![min_x, min_y],
+                ];
+                let geometry = Geometry::new(geojson::Value::Polygon(vec![ring]));
+                let properties = JsonObject::from_iter([
+                    ("device_id".to_string(), JsonValue::from(device.device_id)),
+                    ("quadkey".to_string(), JsonValue::from(cell.tile.quadkey())),
+                    ("positions".to_string(), JsonValue::from(cell.positions)),
+                    ("visits".to_string(), JsonValue::from(cell.visits)),
+                    ("duration".to_string(), JsonValue::from(cell.duration)),
+                    (
+                        "first_time".to_string(),
+                        JsonValue::from(format_timestamp(cell.first_ts, device.tz)),
+                    ),
+                    (
+                        "last_time".to_string(),
+                        JsonValue::from(format_timestamp(cell.last_ts, device.tz)),
+                    ),
+                    ("new".to_string(), JsonValue::from(cell.new)),
+                ]);
+                Feature {
+                    geometry: Some(geometry),
+                    properties: Some(properties),
+                    ..Default::default()
+                }
+            })
+        })
+        .collect();
+    let devices: Vec<JsonValue> = coverage
+        .iter()
+        .map(|device| {
+            serde_json::json!({
+                "device_id": device.device_id,
+                "cells": device.cells.len(),
+                "new_cells": device.new_cells(),
+                "duration": device.cells.iter().map(|cell| cell.duration).sum::<i64>(),
+            })
+        })
+        .collect();
+    let stats = JsonObject::from_iter([("devices".to_string(), JsonValue::from(devices))]);
+
+    let geojson = FeatureCollection {
+        features,
+        foreign_members: Some(stats),
+        ..Default::default()
+    };
+    Ok(geojson.to_string())
+}
+
+/// GeoJSON Feature of a geofence. Circles are Points with a `radius` property.
+pub fn geofence_feature(fence: &Geofence) -> anyhow::Result<Feature> {
+    let shape = fence.shape()?;
+    let mut properties = JsonObject::from_iter([
+        ("id".to_string(), JsonValue::from(fence.id)),
+        (
+            "user_id".to_string(),
+            JsonValue::from(fence.user_id.clone()),
+        ),
+        ("name".to_string(), JsonValue::from(fence.name.clone())),
+    ]);
+    if let Some(radius) = fence.radius {
+        properties.insert("radius".to_string(), JsonValue::from(radius));
+    }
+    Ok(Feature {
+        geometry: Some(shape.geometry()),
+        id: Some(geojson::feature::Id::Number(fence.id.into())),
+        properties: Some(properties),
+        ..Default::default()
+    })
+}
+
+/// Build a GeoJSON FeatureCollection of geofences
+pub fn geofences(fences: &[Geofence]) -> anyhow::Result<String> {
+    let features = fences
+        .iter()
+        .map(geofence_feature)
+        .collect::<anyhow::Result<_>>()?;
+    let geojson = FeatureCollection {
+        features,
+        ..Default::default()
+    };
+    Ok(geojson.to_string())
+}
@@ -0,0 +1,165 @@
+//! GeoParquet export with a row per position
+
+use crate::db::{Db, PositionRow};
+use crate::points::{parse_annotations, position_batches, Bbox, PointExport, ValueType};
+use anyhow::Context;
+use arrow_array::{
+    ArrayRef, BinaryArray, BooleanArray, Float64Array, Int16Array, Int32Array, Int64Array,
+    RecordBatch, StringArray, TimestampSecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use futures_util::future;
+use futures_util::stream::{self, Stream, StreamExt};
+use parquet::arrow::ArrowWriter;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use serde_json::json;
+use std::sync::Arc;
+
+/// Maximal number of rows in a row group
+const ROW_GROUP_SIZE: usize = 100_000;
+
+/// Stream a GeoParquet file. Bytes are sent as soon as the writer has written them.
+pub fn stream(
+    db: Db,
+    export: PointExport,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<Vec<u8>>>> {
+    let schema = schema(&export);
+    let properties = WriterProperties::builder()
+        .set_max_row_group_size(ROW_GROUP_SIZE)
+        .build();
+    let mut writer = Some(ArrowWriter::try_new(
+        Vec::new(),
+        schema.clone(),
+        Some(properties),
+    )?);
+    let mut bbox = Bbox::default();
+    let batches = position_batches(db, export.ranges.clone())
+        .map(Some)
+        .chain(stream::once(future::ready(None)));
+    Ok(batches.map(move |batch| {
+        let Some(rows) = batch else {
+            // Write the footer with the bounding box of all positions
+            let mut writer = writer.take().context("GeoParquet file already closed")?;
+            writer.append_key_value_metadata(KeyValue::new("geo".to_string(), geo_metadata(&bbox)));
+            return Ok(writer.into_inner()?);
+        };
+        let rows = rows?;
+        for row in &rows {
+            bbox.extend(row.lon, row.lat);
+        }
+        let writer = writer.as_mut().context("GeoParquet file already closed")?;
+        writer.write(&record_batch(&schema, &export, &rows)?)?;
+        // Send the bytes written so far
+        Ok(std::mem::take(writer.inner_mut()))
+    }))
+}
+
+fn schema(export: &PointExport) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("id", DataType::Int32, false),
+        Field::new("device_id", DataType::Int32, false),
+        Field::new("user_id", DataType::Utf8, false),
+        Field::new("device", DataType::Utf8, false),
+        Field::new("tid", DataType::Utf8, false),
+        Field::new(
+            "ts",
+            DataType::Timestamp(TimeUnit::Second, Some("UTC".into())),
+            false,
+        ),
+        Field::new("lat", DataType::Float64, false),
+        Field::new("lon", DataType::Float64, false),
+        Field::new("velocity", DataType::Int16, true),
+        Field::new("alt", DataType::Int16, true),
+        Field::new("accuracy", DataType::Int32, true),
+        Field::new("v_accuracy", DataType::Int16, true),
+        Field::new("cog", DataType::Int16, true),
+        Field::new("annotations", DataType::Utf8, false),
+    ];
+    for column in &export.annotations {
+        let data_type = match column.value_type {
+            ValueType::Bool => DataType::Boolean,
+            ValueType::Int => DataType::Int64,
+            ValueType::Float => DataType::Float64,
+            ValueType::String => DataType::Utf8,
+        };
+        fields.push(Field::new(&column.name, data_type, true));
+    }
+    fields.push(Field::new("geometry", DataType::Binary, false));
+    Arc::new(Schema::new(fields))
+}
+
+fn record_batch(
+    schema: &SchemaRef,
+    export: &PointExport,
+    rows: &[PositionRow],
+) -> anyhow::Result<RecordBatch> {
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.id))),
+        Arc::new(Int32Array::from_iter_values(
+            rows.iter().map(|r| r.device_id),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|r| &r.user_id),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|r| &r.device),
+        )),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &r.tid))),
+        Arc::new(
+            TimestampSecondArray::from_iter_values(rows.iter().map(|r| r.ts)).with_timezone("UTC"),
+        ),
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(|r| r.lat))),
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(|r| r.lon))),
+        Arc::new(Int16Array::from_iter(rows.iter().map(|r| r.velocity))),
+        Arc::new(Int16Array::from_iter(rows.iter().map(|r| r.alt))),
+        Arc::new(Int32Array::from_iter(rows.iter().map(|r| r.accuracy))),
+        Arc::new(Int16Array::from_iter(rows.iter().map(|r| r.v_accuracy))),
+        Arc::new(Int16Array::from_iter(rows.iter().map(|r| r.cog))),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|r| &r.annotations),
+        )),
+    ];
+    let annotations: Vec<_> = rows.iter().map(parse_annotations).collect();
+    for column in &export.annotations {
+        let values = annotations.iter();
+        let array: ArrayRef = match column.value_type {
+            ValueType::Bool => Arc::new(BooleanArray::from_iter(values.map(|a| column.bool(a)))),
+            ValueType::Int => Arc::new(Int64Array::from_iter(values.map(|a| column.int(a)))),
+            ValueType::Float => Arc::new(Float64Array::from_iter(values.map(|a| column.float(a)))),
+            ValueType::String => Arc::new(StringArray::from_iter(values.map(|a| column.string(a)))),
+        };
+        columns.push(array);
+    }
+    columns.push(Arc::new(BinaryArray::from_iter_values(
+        rows.iter().map(|r| wkb_point(r.lon, r.lat)),
+    )));
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+/// Point geometry in little endian WKB
+fn wkb_point(x: f64, y: f64) -> Vec<u8> {
+    let mut wkb = Vec::with_capacity(21);
+    wkb.push(1);
+    wkb.extend(1u32.to_le_bytes());
+    wkb.extend(x.to_le_bytes());
+    wkb.extend(y.to_le_bytes());
+    wkb
+}
+
+/// GeoParquet file metadata. Without `crs`, coordinates are WGS 84 longitude/latitude.
+fn geo_metadata(bbox: &Bbox) -> String {
+    let mut geometry = json!({
+        "encoding": "WKB",
+        "geometry_types": ["Point"],
+    });
+    if let Some(bounds) = bbox.bounds() {
+        geometry["bbox"] = json!(bounds);
+    }
+    json!({
+        "version": "1.1.0",
+        "primary_column": "geometry",
+        "columns": { "geometry": geometry },
+    })
+    .to_string()
+}
@@ -1,64 +1,269 @@
-use crate::db::TrackData;
-use geo_types::Point;
-use gpx::{Gpx, GpxVersion, Track, TrackSegment, Waypoint};
-use time::{macros::format_description, OffsetDateTime};
+use crate::db::{GpsPoint, TrackData};
+use crate::profile::{annotation, BATTERY_KEYS, HEART_RATE_KEYS};
+use crate::stats::BboxStats;
+use crate::timestamp::format_timestamp;
+use crate::xml_writer::{text_element, writer, Writer};
+use chrono_tz::Tz;
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap};
+use xml::writer::XmlEvent;
 
-/// Build a GPX track from track data.
+const CREATOR: &str = "owntrack-rs";
+const GPX10_NS: &str = "http://www.topografix.com/GPX/1/0";
+const GPX_NS: &str = "http://www.topografix.com/GPX/1/1";
+const XSI_NS: &str = "http://www.w3.org/2001/XMLSchema-instance";
+/// Garmin extension with speed and course
+const GPXTPX_NS: &str = "http://www.garmin.com/xmlschemas/TrackPointExtension/v2";
+/// Extension with OwnTracks position attributes
+const OWNTRACK_NS: &str = "https://github.com/pka/owntrack-rs/gpx/1";
+const SCHEMA_LOCATION: &str = "http://www.topografix.com/GPX/1/1 http://www.topografix.com/GPX/1/1/gpx.xsd http://www.garmin.com/xmlschemas/TrackPointExtension/v2 https://www8.garmin.com/xmlschemas/TrackPointExtensionv2.xsd";
+/// Annotations not written into GPX extensions
+const ANNOTATIONS_SKIP_LIST: &[&str] = &["_id", "m", "BSSID", "SSID", "created_at", "hdop"];
+
+/// GPX version of exports
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Version {
+    /// GPX 1.0, which includes speed
+    #[default]
+    Gpx10,
+    /// GPX 1.1 with metadata, waypoints and extensions
+    Gpx11,
+}
+
+impl Version {
+    pub fn from_param(version: Option<&str>) -> anyhow::Result<Self> {
+        match version {
+            None | Some("1.0") => Ok(Version::Gpx10),
+            Some("1.1") => Ok(Version::Gpx11),
+            Some(version) => anyhow::bail!("Unsupported GPX version `{version}`"),
+        }
+    }
+}
+
+/// Build a GPX 1.0 file with speed and course of track points.
 pub fn tracks(tracks: &[TrackData]) -> anyhow::Result<String> {
-    let ot_format =
-        format_description!("[year]-[month]-[day] [hour]:[minute]:[second][offset_hour]");
-    let tracks = tracks
-        .iter()
-        .map(|track| {
-            let track_segment = TrackSegment {
-                points: track
-                    .points
-                    .iter()
-                    .filter_map(|point| {
-                        // keep only points within 200 meters accuracy
-                        if point.accuracy.unwrap_or(0) < 200 {
-                            let time: Option<gpx::Time> =
-                                OffsetDateTime::parse(&point.ts, &ot_format)
-                                    .map(|dt| dt.into())
-                                    .ok();
-                            let mut wpt = Waypoint::new(Point::new(point.x, point.y));
-                            wpt.time = time;
-                            wpt.elevation = point.elevation.map(|val| val as f64);
-                            wpt.speed = point.speed.map(|val| val as f64 / 3.6);
-                            Some(wpt)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect(),
-            };
-            Track {
-                name: Some(format!(
-                    "Track {date}-{device_id}",
-                    date = track.date,
-                    device_id = track.device_id
-                )),
-                comment: None,
-                description: None,
-                source: None,
-                links: vec![],
-                type_: None,
-                number: None,
-                segments: vec![track_segment],
+    let mut w = writer();
+    w.write(
+        XmlEvent::start_element("gpx")
+            .default_ns(GPX10_NS)
+            .attr("version", "1.0")
+            .attr("creator", CREATOR),
+    )?;
+    for track in tracks {
+        w.write(XmlEvent::start_element("trk"))?;
+        let name = format!("Track {}-{}", track.date, track.device_id);
+        text_element(&mut w, "name", &name)?;
+        w.write(XmlEvent::start_element("trkseg"))?;
+        for pt in &track.points {
+            start_point(&mut w, "trkpt", pt, track.tz)?;
+            if let Some(cog) = pt.cog {
+                text_element(&mut w, "course", &cog.to_string())?;
             }
-        })
+            if let Some(speed) = pt.speed {
+                // m/s
+                text_element(&mut w, "speed", &format!("{:.2}", speed as f64 / 3.6))?;
+            }
+            w.write(XmlEvent::end_element())?;
+        }
+        w.write(XmlEvent::end_element())?;
+        w.write(XmlEvent::end_element())?;
+    }
+    w.write(XmlEvent::end_element())?;
+    Ok(String::from_utf8(w.into_inner())?)
+}
+
+/// Build a GPX 1.1 file with metadata, waypoints of POIs and transitions
+/// and track point extensions. `devices` contains device names by device id.
+pub fn tracks_v11(tracks: &[TrackData], devices: &HashMap<i32, String>) -> anyhow::Result<String> {
+    let mut w = writer();
+    w.write(
+        XmlEvent::start_element("gpx")
+            .default_ns(GPX_NS)
+            .ns("xsi", XSI_NS)
+            .ns("gpxtpx", GPXTPX_NS)
+            .ns("owntrack", OWNTRACK_NS)
+            .attr("version", "1.1")
+            .attr("creator", CREATOR)
+            .attr("xsi:schemaLocation", SCHEMA_LOCATION),
+    )?;
+    write_metadata(&mut w, tracks, devices)?;
+
+    for track in tracks {
+        for pt in &track.points {
+            let annotations = annotations(pt);
+            if let Some((name, type_)) = waypoint_name(&annotations) {
+                start_point(&mut w, "wpt", pt, track.tz)?;
+                text_element(&mut w, "name", &name)?;
+                text_element(&mut w, "type", type_)?;
+                w.write(XmlEvent::end_element())?;
+            }
+        }
+    }
+
+    for track in tracks {
+        w.write(XmlEvent::start_element("trk"))?;
+        let name = match devices.get(&track.device_id) {
+            Some(device) => format!("{device} {}", track.date),
+            None => format!("Track {}-{}", track.date, track.device_id),
+        };
+        text_element(&mut w, "name", &name)?;
+        w.write(XmlEvent::start_element("trkseg"))?;
+        for pt in &track.points {
+            write_trackpoint(&mut w, pt, track.tz)?;
+        }
+        w.write(XmlEvent::end_element())?;
+        w.write(XmlEvent::end_element())?;
+    }
+    w.write(XmlEvent::end_element())?;
+    Ok(String::from_utf8(w.into_inner())?)
+}
+
+/// Device names, time bounds and bounding box
+fn write_metadata(
+    w: &mut Writer,
+    tracks: &[TrackData],
+    devices: &HashMap<i32, String>,
+) -> anyhow::Result<()> {
+    let names: BTreeSet<&str> = tracks
+        .iter()
+        .filter_map(|track| devices.get(&track.device_id).map(String::as_str))
         .collect();
-    let gpx = Gpx {
-        version: GpxVersion::Gpx10, // Speed is only included in GPX 1.0. Will be in next georust/gpx release.
-        creator: None,
-        metadata: None,
-        waypoints: vec![],
-        tracks,
-        routes: vec![],
-    };
+    let points = || tracks.iter().flat_map(|track| &track.points);
+    let ts_start = points().map(|pt| pt.ts).min();
+    let ts_end = points().map(|pt| pt.ts).max();
+    let tz = tracks.first().map(|track| track.tz).unwrap_or_default();
+    let bbox = BboxStats::from_xy_iter(points().map(|pt| (pt.x, pt.y))).bbox();
+
+    w.write(XmlEvent::start_element("metadata"))?;
+    if !names.is_empty() {
+        let names: Vec<&str> = names.into_iter().collect();
+        text_element(w, "name", &names.join(", "))?;
+    }
+    if let Some(ts) = ts_start {
+        text_element(w, "time", &format_timestamp(ts, tz))?;
+    }
+    if let Some(bbox) = bbox {
+        let [minlon, minlat, maxlon, maxlat] =
+            [bbox[0], bbox[1], bbox[2], bbox[3]].map(|val| format!("{val:.7}"));
+        w.write(
+            XmlEvent::start_element("bounds")
+                .attr("minlat", &minlat)
+                .attr("minlon", &minlon)
+                .attr("maxlat", &maxlat)
+                .attr("maxlon", &maxlon),
+        )?;
+        w.write(XmlEvent::end_element())?;
+    }
+    if let (Some(ts_start), Some(ts_end)) = (ts_start, ts_end) {
+        w.write(XmlEvent::start_element("extensions"))?;
+        text_element(w, "owntrack:ts_start", &format_timestamp(ts_start, tz))?;
+        text_element(w, "owntrack:ts_end", &format_timestamp(ts_end, tz))?;
+        w.write(XmlEvent::end_element())?;
+    }
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_trackpoint(w: &mut Writer, pt: &GpsPoint, tz: Tz) -> anyhow::Result<()> {
+    let mut annotations = annotations(pt);
+    start_point(w, "trkpt", pt, tz)?;
+    if let Some(hdop) = annotations.get("hdop").and_then(Value::as_f64) {
+        text_element(w, "hdop", &hdop.to_string())?;
+    }
+    let heart_rate = annotation(&annotations, HEART_RATE_KEYS);
+    let battery = annotation(&annotations, BATTERY_KEYS);
+    annotations.retain(|key, _| {
+        !ANNOTATIONS_SKIP_LIST.contains(&key.as_str())
+            && !BATTERY_KEYS.contains(&key.as_str())
+            && !HEART_RATE_KEYS.contains(&key.as_str())
+    });
+    let garmin_extension = heart_rate.is_some() || pt.speed.is_some() || pt.cog.is_some();
+    let owntrack_extension = pt.accuracy.is_some()
+        || pt.v_accuracy.is_some()
+        || battery.is_some()
+        || !annotations.is_empty();
+    if !garmin_extension && !owntrack_extension {
+        // Close trkpt
+        w.write(XmlEvent::end_element())?;
+        return Ok(());
+    }
+    w.write(XmlEvent::start_element("extensions"))?;
+    if garmin_extension {
+        w.write(XmlEvent::start_element("gpxtpx:TrackPointExtension"))?;
+        if let Some(heart_rate) = heart_rate {
+            text_element(w, "gpxtpx:hr", &(heart_rate.round() as i64).to_string())?;
+        }
+        if let Some(speed) = pt.speed {
+            // m/s
+            text_element(w, "gpxtpx:speed", &format!("{:.2}", speed as f64 / 3.6))?;
+        }
+        if let Some(cog) = pt.cog {
+            text_element(w, "gpxtpx:course", &cog.to_string())?;
+        }
+        w.write(XmlEvent::end_element())?;
+    }
+    if let Some(accuracy) = pt.accuracy {
+        text_element(w, "owntrack:accuracy", &accuracy.to_string())?;
+    }
+    if let Some(v_accuracy) = pt.v_accuracy {
+        text_element(w, "owntrack:v_accuracy", &v_accuracy.to_string())?;
+    }
+    if let Some(battery) = battery {
+        text_element(w, "owntrack:battery", &battery.to_string())?;
+    }
+    if !annotations.is_empty() {
+        text_element(
+            w,
+            "owntrack:annotations",
+            &Value::Object(annotations).to_string(),
+        )?;
+    }
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+/// Start a `wpt` or `trkpt` element with coordinates, elevation and time
+fn start_point(w: &mut Writer, element: &str, pt: &GpsPoint, tz: Tz) -> anyhow::Result<()> {
+    let (lat, lon) = (format!("{:.7}", pt.y), format!("{:.7}", pt.x));
+    w.write(
+        XmlEvent::start_element(element)
+            .attr("lat", &lat)
+            .attr("lon", &lon),
+    )?;
+    if let Some(elevation) = pt.elevation {
+        text_element(w, "ele", &elevation.to_string())?;
+    }
+    text_element(w, "time", &format_timestamp(pt.ts, tz))
+}
+
+fn annotations(pt: &GpsPoint) -> Map<String, Value> {
+    serde_json::from_str(&pt.annotations).unwrap_or_default()
+}
 
-    let mut vec = Vec::new();
-    gpx::write(&gpx, &mut vec).unwrap();
-    let gpxstr = String::from_utf8(vec).unwrap();
-    Ok(gpxstr)
+/// Waypoint name and type of points with POI, visit or region annotations
+pub fn waypoint_name(annotations: &Map<String, Value>) -> Option<(String, &'static str)> {
+    let text = |key| annotations.get(key).and_then(Value::as_str);
+    if let Some(poi) = text("poi") {
+        return Some((poi.to_string(), "poi"));
+    }
+    if let Some(visit) = text("visit") {
+        let place = text("semantic_type").unwrap_or("Place");
+        return Some((format!("{place} {visit}"), "visit"));
+    }
+    // OwnTracks region enter and leave events
+    if matches!(text("t"), Some("c" | "C" | "b")) {
+        let regions: Vec<&str> = annotations
+            .get("inregions")
+            .and_then(Value::as_array)
+            .map(|regions| regions.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let name = if regions.is_empty() {
+            "Region event".to_string()
+        } else {
+            regions.join(", ")
+        };
+        return Some((name, "transition"));
+    }
+    None
 }
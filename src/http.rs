@@ -1,15 +1,41 @@
-use crate::db::{Db, TrackRef};
+use crate::activity::{LapSplit, Sport};
+use crate::coverage;
+use crate::csv;
+use crate::db::{Db, TrackData, TrackRef};
+use crate::export;
+use crate::filter::FilterParams;
+use crate::fit;
+use crate::flatgeobuf;
+use crate::geofences::{self, Shape};
 use crate::geojson;
+use crate::geoparquet;
 use crate::gpx;
+use crate::import;
+use crate::kml;
+use crate::mvt::{self, TileId};
 use crate::owntracks::{otrc_json, AppConfig, Message};
+use crate::places;
+use crate::points::{self, PointExport};
+use crate::profile;
+use crate::recorder_api;
+use crate::smooth;
+use crate::tcx;
+use crate::timestamp::{parse_tz, Period, TimeRange};
+use crate::trips;
 use actix_cors::Cors;
+use actix_multipart::Multipart;
 use actix_web::{
-    error, get, middleware, middleware::Logger, post, route, web, App, HttpRequest, HttpResponse,
-    HttpServer, Responder,
+    delete, error, get, http::header, middleware, middleware::Logger, post, put, route, web, App,
+    HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web_rust_embed_responder::{EmbedResponse, EmbedableFileResponse, IntoResponse};
+use chrono::Utc;
+use futures_util::{StreamExt, TryStreamExt};
 use rust_embed_for_web::RustEmbed;
 use serde::Deserialize;
+use std::io::{self, BufReader};
+use std::str::FromStr;
+use tokio::sync::broadcast::error::RecvError;
 
 #[derive(Deserialize)]
 struct OtParams {
@@ -64,9 +90,190 @@ async fn rawjson(body: String) -> actix_web::Result<impl Responder> {
     Ok("ok")
 }
 
+#[derive(Deserialize)]
+struct ImportParams {
+    u: String,
+    d: String,
+    /// File format. Detected from file extension if missing.
+    format: Option<String>,
+}
+
+/// Upload settings
+#[derive(Clone, Copy)]
+struct ImportConfig {
+    /// Maximal size of all files of an upload in bytes
+    max_size: usize,
+}
+
+impl ImportConfig {
+    fn from_env() -> Self {
+        let max_size_mb = dotenvy::var("IMPORT_MAX_SIZE")
+            .ok()
+            .and_then(|size| size.parse::<usize>().ok())
+            .unwrap_or(1024);
+        ImportConfig {
+            max_size: max_size_mb * 1024 * 1024,
+        }
+    }
+
+    fn too_large(&self) -> error::Error {
+        error::ErrorPayloadTooLarge(format!(
+            "Upload exceeds {} MB",
+            self.max_size / (1024 * 1024)
+        ))
+    }
+}
+
+/// Import uploaded GPX, GeoJSON, KML, CSV or Google location history files
+#[post("/import")]
+async fn import_files(
+    db: web::Data<Db>,
+    config: web::Data<ImportConfig>,
+    params: web::Query<ImportParams>,
+    req: HttpRequest,
+    mut payload: Multipart,
+) -> actix_web::Result<impl Responder> {
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > config.max_size) {
+        return Err(config.too_large());
+    }
+    let format = params
+        .format
+        .as_deref()
+        .map(import::Format::from_str)
+        .transpose()
+        .map_err(error::ErrorBadRequest)?;
+    let mut stats = import::ImportStats::default();
+    let mut size = 0;
+    while let Some(mut field) = payload.try_next().await? {
+        let filename = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .unwrap_or_default()
+            .to_string();
+        let format = format.or_else(|| import::Format::from_filename(&filename));
+        // Pass the upload to the import reader while receiving
+        let (tx, reader) = import::ChunkReader::channel();
+        let (size, config) = (&mut size, *config.get_ref());
+        let upload = async move {
+            loop {
+                // Abort the import of incomplete files
+                let chunk = match field.try_next().await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(io::Error::other("Upload aborted"))).await;
+                        return Err(e.into());
+                    }
+                };
+                *size += chunk.len();
+                if *size > config.max_size {
+                    let _ = tx.send(Err(io::Error::other("Upload too large"))).await;
+                    return Err(config.too_large());
+                }
+                if tx.send(Ok(chunk)).await.is_err() {
+                    // Import failed
+                    break;
+                }
+            }
+            Ok(())
+        };
+        let imported = import::import(&db, &params.u, &params.d, format, BufReader::new(reader));
+        let (uploaded, imported) = futures_util::join!(upload, imported);
+        uploaded?;
+        let file_stats = imported.map_err(|e| {
+            log::info!("Import of `{filename}` failed: {e}");
+            error::ErrorBadRequest(format!("Import of `{filename}` failed: {e}"))
+        })?;
+        log::info!(
+            "{filename}: {} positions read, {} inserted",
+            file_stats.read,
+            file_stats.inserted
+        );
+        stats.read += file_stats.read;
+        stats.inserted += file_stats.inserted;
+    }
+    Ok(web::Json(stats))
+}
+
+#[derive(Deserialize)]
+struct TimezoneParams {
+    u: String,
+    /// Device name. Sets the time zone of the user if missing.
+    d: Option<String>,
+    /// IANA time zone name like `Europe/Zurich`
+    tz: String,
+}
+
+/// Set time zone of a user or device
+#[post("/timezone")]
+async fn timezone(
+    db: web::Data<Db>,
+    params: web::Query<TimezoneParams>,
+) -> actix_web::Result<impl Responder> {
+    let tz = parse_tz(&params.tz).map_err(error::ErrorBadRequest)?;
+    let result = match &params.d {
+        Some(device) => match db.set_device_tz(&params.u, device, tz).await {
+            Ok(true) => Ok(()),
+            Ok(false) => return Err(error::ErrorNotFound("Unknown device")),
+            Err(e) => Err(e),
+        },
+        None => db.set_user_tz(&params.u, tz).await,
+    };
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to set time zone"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeviceParams {
+    u: String,
+    d: String,
+}
+
+/// Set point filter settings of a device
+#[post("/filter")]
+async fn set_filter(
+    db: web::Data<Db>,
+    params: web::Query<DeviceParams>,
+    filter: web::Json<FilterParams>,
+) -> actix_web::Result<impl Responder> {
+    match db.set_device_filter(&params.u, &params.d, &filter).await {
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Err(error::ErrorNotFound("Unknown device")),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to set filter"))
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct TracksParams {
-    date: String,
+    /// Calendar day in the time zone of the device, if `from` is missing
+    date: Option<String>,
+    /// Start time (UNIX timestamp or ISO 8601)
+    from: Option<String>,
+    /// End time (exclusive). Default: one day after `from`
+    to: Option<String>,
+}
+
+impl TracksParams {
+    fn period(&self) -> anyhow::Result<Period> {
+        Period::from_params(
+            self.from.as_deref(),
+            self.to.as_deref(),
+            self.date.as_deref(),
+        )
+    }
 }
 
 #[get("/trackinfos")]
@@ -74,7 +281,8 @@ async fn trackinfos(
     db: web::Data<Db>,
     params: web::Query<TracksParams>,
 ) -> actix_web::Result<impl Responder> {
-    match db.query_tracks_info(&params.date).await {
+    let period = params.period().map_err(error::ErrorBadRequest)?;
+    match db.query_tracks_info(period).await {
         Ok(track_infos) => Ok(web::Json(track_infos)),
         Err(e) => {
             log::error!("{e}");
@@ -85,18 +293,120 @@ async fn trackinfos(
     }
 }
 
+/// List trips, separated by stays and signal gaps
+#[get("/trips")]
+async fn trip_list(
+    db: web::Data<Db>,
+    params: web::Query<TracksParams>,
+) -> actix_web::Result<impl Responder> {
+    let period = params.period().map_err(error::ErrorBadRequest)?;
+    match trips::query_trips(&db, None, period).await {
+        Ok(trips) => Ok(web::Json(trips)),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to fetch trips"))
+        }
+    }
+}
+
+/// List visits of places
+#[get("/visits")]
+async fn visits(
+    db: web::Data<Db>,
+    params: web::Query<TracksParams>,
+) -> actix_web::Result<impl Responder> {
+    let period = params.period().map_err(error::ErrorBadRequest)?;
+    match places::query_visits(&db, period).await {
+        Ok(visits) => Ok(web::Json(visits)),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to fetch visits"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PlacesParams {
+    /// Month in format YYYY-MM
+    month: String,
+    /// User name. All users if missing.
+    u: Option<String>,
+    /// Maximal number of places per user. Default: 10
+    limit: Option<usize>,
+}
+
+/// List places with the longest visits of a month
+#[get("/places")]
+async fn top_places(
+    db: web::Data<Db>,
+    params: web::Query<PlacesParams>,
+) -> actix_web::Result<impl Responder> {
+    let period = Period::month(&params.month).map_err(error::ErrorBadRequest)?;
+    match places::top_places(&db, period, params.u.as_deref(), params.limit.unwrap_or(10)).await {
+        Ok(places) => Ok(web::Json(places)),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to fetch places"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PlaceNameParams {
+    name: String,
+}
+
+/// Rename a place
+#[post("/places/{id}")]
+async fn rename_place(
+    db: web::Data<Db>,
+    id: web::Path<i32>,
+    params: web::Query<PlaceNameParams>,
+) -> actix_web::Result<impl Responder> {
+    match db.rename_place(*id, &params.name).await {
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Err(error::ErrorNotFound("Unknown place")),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to rename place"))
+        }
+    }
+}
+
+/// Query the requested track, smoothed with `smooth=true`
+async fn fetch_track(db: &Db, track_ref: &TrackRef) -> Result<TrackData, HttpResponse> {
+    let period = track_ref
+        .period()
+        .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+    let mut track_ = db
+        .query_track(track_ref.device_id, period, &track_ref.filter())
+        .await
+        .map_err(|e| {
+            log::error!("Failed to fetch track: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to fetch track")
+                .finish()
+        })?;
+    if track_ref.smooth.unwrap_or(false) {
+        smooth::kalman(&mut track_.points);
+    }
+    Ok(track_)
+}
+
 /// Get GeoJSON track
 #[get("/track")]
 async fn track(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
-    let track = match db.query_track(&track_ref).await {
+    let simplification = match track_ref.simplification() {
+        Ok(simplification) => simplification,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let mut track = match fetch_track(&db, &track_ref).await {
         Ok(data) => data,
-        Err(e) => {
-            log::error!("Failed to fetch track: {e}");
-            return HttpResponse::InternalServerError()
-                .reason("Failed to fetch track")
-                .finish();
-        }
+        Err(response) => return response,
     };
+    if let Some(simplification) = simplification {
+        track.points = simplification.apply(track.points);
+    }
     let geojson = if track_ref.segmented.unwrap_or(false) {
         geojson::track_with_segments(&[track])
     } else {
@@ -105,17 +415,13 @@ async fn track(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpRespon
     let json = match geojson {
         Ok(json) => json,
         Err(e) => {
-            log::error!("Failed to fetch track: {e}");
+            log::error!("Failed to export track: {e}");
             return HttpResponse::InternalServerError()
-                .reason("Failed to fetch track")
+                .reason("Failed to export track")
                 .finish();
         }
     };
-    let filename = format!(
-        "track_{}_{}.json",
-        track_ref.device_id,
-        track_ref.ts_start.replace(" ", "_")
-    );
+    let filename = format!("track_{}_{}.json", track_ref.device_id, track_ref.label());
     HttpResponse::Ok()
         .content_type("application/geo+json")
         .insert_header((
@@ -128,29 +434,36 @@ async fn track(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpRespon
 /// Get GPX track
 #[get("/gpxtrack")]
 async fn gpxtrack(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
-    let track_ = match db.query_track(&track_ref).await {
+    let version = match gpx::Version::from_param(track_ref.gpx_version.as_deref()) {
+        Ok(version) => version,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let track_ = match fetch_track(&db, &track_ref).await {
         Ok(data) => data,
-        Err(e) => {
-            log::error!("Failed to fetch track: {e}");
-            return HttpResponse::InternalServerError()
-                .reason("Failed to fetch track")
-                .finish();
+        Err(response) => return response,
+    };
+    let gpx = match version {
+        gpx::Version::Gpx10 => gpx::tracks(&[track_]),
+        gpx::Version::Gpx11 => {
+            let device = db
+                .query_device_label(track_ref.device_id)
+                .await
+                .ok()
+                .flatten()
+                .map(|(name, _)| (track_ref.device_id, name));
+            gpx::tracks_v11(&[track_], &device.into_iter().collect())
         }
     };
-    let gpx = match gpx::tracks(&[track_]) {
+    let gpx = match gpx {
         Ok(gpx) => gpx,
         Err(e) => {
-            log::error!("Failed to fetch tracks: {e}");
+            log::error!("Failed to export track: {e}");
             return HttpResponse::InternalServerError()
-                .reason("Failed to fetch track")
+                .reason("Failed to export track")
                 .finish();
         }
     };
-    let filename = format!(
-        "track_{}_{}.gpx",
-        track_ref.device_id,
-        track_ref.ts_start.replace(" ", "_")
-    );
+    let filename = format!("track_{}_{}.gpx", track_ref.device_id, track_ref.label());
     HttpResponse::Ok()
         .content_type("application/gpx+xml")
         .insert_header((
@@ -160,71 +473,706 @@ async fn gpxtrack(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpRes
         .body(gpx)
 }
 
-/// Get track as CSV
-#[get("/csvtrack")]
-async fn csvtrack(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
-    let track_ = match db.query_track(&track_ref).await {
+/// Get KML track with time stamps, or KMZ with `kmz=true`
+#[get("/kmltrack")]
+async fn kmltrack(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
+    let track_ = match fetch_track(&db, &track_ref).await {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+    let device = db
+        .query_device_label(track_ref.device_id)
+        .await
+        .ok()
+        .flatten()
+        .map(|(name, tid)| (track_ref.device_id, kml::Device { name, tid }));
+    let kml = match kml::tracks(&[track_], &device.into_iter().collect()) {
+        Ok(kml) => kml,
+        Err(e) => {
+            log::error!("Failed to export track: {e}");
+            return HttpResponse::InternalServerError()
+                .reason("Failed to export track")
+                .finish();
+        }
+    };
+    let filename = format!("track_{}_{}", track_ref.device_id, track_ref.label());
+    if !track_ref.kmz.unwrap_or(false) {
+        return HttpResponse::Ok()
+            .content_type("application/vnd.google-earth.kml+xml")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{filename}.kml\""),
+            ))
+            .body(kml);
+    }
+    match kml::kmz(&kml) {
+        Ok(kmz) => HttpResponse::Ok()
+            .content_type("application/vnd.google-earth.kmz")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{filename}.kmz\""),
+            ))
+            .body(kmz),
+        Err(e) => {
+            log::error!("Failed to compress track: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to compress track")
+                .finish()
+        }
+    }
+}
+
+/// Get activity as TCX or FIT file
+async fn activity(db: web::Data<Db>, track_ref: web::Query<TrackRef>, fit: bool) -> HttpResponse {
+    let options = Sport::from_param(track_ref.sport.as_deref())
+        .and_then(|sport| Ok((sport, LapSplit::from_param(track_ref.laps.as_deref())?)));
+    let (sport, split) = match options {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let track_ = match fetch_track(&db, &track_ref).await {
         Ok(data) => data,
+        Err(response) => return response,
+    };
+    if track_.points.is_empty() {
+        return HttpResponse::NotFound().body("No track points");
+    }
+    let filename = format!("track_{}_{}", track_ref.device_id, track_ref.label());
+    let (data, content_type, extension) = if fit {
+        (
+            fit::tracks(&[track_], sport, split),
+            "application/vnd.ant.fit",
+            "fit",
+        )
+    } else {
+        (
+            tcx::tracks(&[track_], sport, split).map(String::into_bytes),
+            "application/vnd.garmin.tcx+xml",
+            "tcx",
+        )
+    };
+    match data {
+        Ok(data) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{filename}.{extension}\""),
+            ))
+            .body(data),
         Err(e) => {
+            log::error!("Failed to export activity: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to export activity")
+                .finish()
+        }
+    }
+}
+
+/// Get TCX activity
+#[get("/tcxtrack")]
+async fn tcxtrack(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
+    activity(db, track_ref, false).await
+}
+
+/// Get FIT activity
+#[get("/fittrack")]
+async fn fittrack(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
+    activity(db, track_ref, true).await
+}
+
+/// Stream track as CSV
+#[get("/csvtrack")]
+async fn csvtrack(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
+    let period = match track_ref.period() {
+        Ok(period) => period,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let options = match csv::CsvOptions::from_params(
+        track_ref.columns.as_deref(),
+        track_ref.annotations.as_deref(),
+        track_ref.time_format.as_deref(),
+    ) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let (tz, device) = match (
+        db.query_device_tz(track_ref.device_id).await,
+        db.query_device_label(track_ref.device_id).await,
+    ) {
+        (Ok(tz), Ok(device)) => (tz, device.map(|(name, _)| name)),
+        (Err(e), _) | (_, Err(e)) => {
             log::error!("Failed to fetch track: {e}");
             return HttpResponse::InternalServerError()
                 .reason("Failed to fetch track")
                 .finish();
         }
     };
+    let stream = csv::stream(
+        db.get_ref().clone(),
+        track_ref.device_id,
+        period.time_range(tz),
+        track_ref.filter(),
+        options,
+        device,
+    )
+    .map_ok(web::Bytes::from)
+    .inspect_err(|e| log::error!("Failed to stream track: {e}"));
 
-    let mut csv_data = String::new();
-    // CSV header
-    csv_data.push_str("timestamp,latitude,longitude,speed,elevation,accuracy,v_accuracy,cog\n");
+    let filename = format!("track_{}_{}.csv", track_ref.device_id, track_ref.label());
+    HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{filename}\""),
+        ))
+        .streaming(stream)
+}
 
-    // CSV data rows
-    for point in &track_.points {
-        csv_data.push_str(&format!(
-            "{},{:.7},{:.7},{},{},{},{},{}\n",
-            point.ts,
-            point.y,
-            point.x,
-            point.speed.map_or(String::new(), |s| s.to_string()),
-            point.elevation.map_or(String::new(), |e| e.to_string()),
-            point.accuracy.map_or(String::new(), |a| a.to_string()),
-            point.v_accuracy.map_or(String::new(), |va| va.to_string()),
-            point.cog.map_or(String::new(), |c| c.to_string()),
-        ));
+#[derive(Deserialize)]
+struct ExportParams {
+    /// Comma separated device ids, all devices if missing
+    devices: Option<String>,
+    date: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// First calendar day (YYYY-MM-DD)
+    start_date: Option<String>,
+    /// Last calendar day (YYYY-MM-DD), including
+    end_date: Option<String>,
+    /// gpx (default), geojson, csv, geoparquet or flatgeobuf
+    format: Option<String>,
+    /// GPX version: `1.0` (default) or `1.1`
+    gpx_version: Option<String>,
+    /// day (default) or trip
+    split: Option<String>,
+    /// ZIP archive with a file per track
+    zip: Option<bool>,
+}
+
+impl ExportParams {
+    fn period(&self) -> anyhow::Result<Period> {
+        days_period(
+            self.start_date.as_deref(),
+            self.end_date.as_deref(),
+            self.from.as_deref(),
+            self.to.as_deref(),
+            self.date.as_deref(),
+        )
+    }
+    fn device_ids(&self) -> anyhow::Result<Option<Vec<i32>>> {
+        parse_device_ids(self.devices.as_deref())
+    }
+    fn split(&self) -> anyhow::Result<export::Split> {
+        match self.split.as_deref() {
+            None | Some("day") => Ok(export::Split::Day),
+            Some("trip") => Ok(export::Split::Trip),
+            Some(split) => anyhow::bail!("Unsupported split `{split}`"),
+        }
     }
+    /// Period, device ids, split and format
+    fn options(&self) -> anyhow::Result<(Period, Option<Vec<i32>>, export::Split, export::Format)> {
+        let gpx_version = gpx::Version::from_param(self.gpx_version.as_deref())?;
+        let format =
+            export::Format::from_name(self.format.as_deref().unwrap_or("gpx"), gpx_version)?;
+        Ok((self.period()?, self.device_ids()?, self.split()?, format))
+    }
+    fn label(&self) -> String {
+        match (&self.start_date, &self.end_date) {
+            (Some(start), Some(end)) if start != end => format!("{start}_{end}"),
+            (Some(start), _) => start.clone(),
+            _ => self
+                .date
+                .clone()
+                .or(self.from.clone())
+                .unwrap_or_default()
+                .replace([' ', ':'], "_"),
+        }
+    }
+}
 
-    let filename = format!(
-        "track_{}_{}.csv",
-        track_ref.device_id,
-        track_ref.ts_start.replace(" ", "_")
-    );
+/// Period from `start_date` and `end_date` or from `from`, `to` and `date` parameters
+fn days_period(
+    start_date: Option<&str>,
+    end_date: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    date: Option<&str>,
+) -> anyhow::Result<Period> {
+    if let Some(start_date) = start_date {
+        Period::days(start_date, end_date.unwrap_or(start_date))
+    } else {
+        Period::from_params(from, to, date)
+    }
+}
+
+/// Comma separated device ids
+fn parse_device_ids(ids: Option<&str>) -> anyhow::Result<Option<Vec<i32>>> {
+    ids.map(|ids| {
+        ids.split(',')
+            .map(|id| {
+                id.trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid device id `{id}`"))
+            })
+            .collect()
+    })
+    .transpose()
+}
+
+/// Export tracks of multiple devices and days
+#[get("/export")]
+async fn export_tracks(db: web::Data<Db>, params: web::Query<ExportParams>) -> HttpResponse {
+    let (period, device_ids, split, format) = match params.options() {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if format.is_point_format() {
+        return export_points(db.get_ref().clone(), &params, device_ids, period, format).await;
+    }
+    if let (export::Format::Csv, false) = (format, params.zip.unwrap_or(false)) {
+        return export_csv(db.get_ref().clone(), &params, device_ids, period, split).await;
+    }
+    let tracks = match export::query_tracks(&db, device_ids.as_deref(), period, split).await {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            log::error!("Failed to fetch tracks: {e}");
+            return HttpResponse::InternalServerError()
+                .reason("Failed to fetch tracks")
+                .finish();
+        }
+    };
+    let label = params.label();
+    let response = if params.zip.unwrap_or(false) {
+        export::write_zip(tracks, format).map(|data| {
+            HttpResponse::Ok()
+                .content_type("application/zip")
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"tracks_{label}.zip\""),
+                ))
+                .body(data)
+        })
+    } else {
+        export::write(&tracks, format).map(|data| {
+            HttpResponse::Ok()
+                .content_type(format.content_type())
+                .insert_header((
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"tracks_{label}.{}\"",
+                        format.extension()
+                    ),
+                ))
+                .body(data)
+        })
+    };
+    response.unwrap_or_else(|e| {
+        log::error!("Failed to export tracks: {e}");
+        HttpResponse::InternalServerError()
+            .reason("Failed to export tracks")
+            .finish()
+    })
+}
+
+/// Stream tracks of multiple devices as CSV with a device column
+async fn export_csv(
+    db: Db,
+    params: &ExportParams,
+    device_ids: Option<Vec<i32>>,
+    period: Period,
+    split: export::Split,
+) -> HttpResponse {
+    let tracks = match export::query_track_ranges(&db, device_ids.as_deref(), period, split).await {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            log::error!("Failed to fetch tracks: {e}");
+            return HttpResponse::InternalServerError()
+                .reason("Failed to fetch tracks")
+                .finish();
+        }
+    };
+    let stream = csv::stream_devices(db, tracks)
+        .map_ok(web::Bytes::from)
+        .inspect_err(|e| log::error!("Failed to export tracks: {e}"));
     HttpResponse::Ok()
         .content_type("text/csv")
         .insert_header((
             "Content-Disposition",
-            format!("attachment; filename=\"{filename}\""),
+            format!("attachment; filename=\"tracks_{}.csv\"", params.label()),
         ))
-        .body(csv_data)
+        .streaming(stream)
 }
 
-/// Get GeoJSON track points
-#[get("/trackpoints")]
-async fn trackpoints(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
-    let track_ = match db.query_track(&track_ref).await {
-        Ok(data) => data,
+/// Stream positions of multiple devices as points
+async fn export_points(
+    db: Db,
+    params: &ExportParams,
+    device_ids: Option<Vec<i32>>,
+    period: Period,
+    format: export::Format,
+) -> HttpResponse {
+    if params.zip.unwrap_or(false) {
+        return HttpResponse::BadRequest().body("ZIP archives are not supported for point formats");
+    }
+    let export = match PointExport::query(&db, device_ids.as_deref(), period).await {
+        Ok(export) => export,
         Err(e) => {
-            log::error!("Failed to fetch track: {e}");
+            log::error!("Failed to fetch positions: {e}");
             return HttpResponse::InternalServerError()
-                .reason("Failed to fetch track")
+                .reason("Failed to fetch positions")
                 .finish();
         }
     };
-    let geojson = geojson::track_points(&[track_]);
+    let stream = match format {
+        export::Format::GeoParquet => match geoparquet::stream(db, export) {
+            Ok(stream) => stream.boxed_local(),
+            Err(e) => {
+                log::error!("Failed to export positions: {e}");
+                return HttpResponse::InternalServerError()
+                    .reason("Failed to export positions")
+                    .finish();
+            }
+        },
+        _ => flatgeobuf::stream(db, export).boxed_local(),
+    };
+    let stream = stream
+        .map_ok(web::Bytes::from)
+        .inspect_err(|e| log::error!("Failed to export positions: {e}"));
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"positions_{}.{}\"",
+                params.label(),
+                format.extension()
+            ),
+        ))
+        .streaming(stream)
+}
+
+#[derive(Deserialize)]
+struct TileParams {
+    /// Comma separated device ids, all devices if missing
+    devices: Option<String>,
+    date: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// First calendar day (YYYY-MM-DD)
+    start_date: Option<String>,
+    /// Last calendar day (YYYY-MM-DD), including
+    end_date: Option<String>,
+}
+
+impl TileParams {
+    /// Period of the parameters or all positions
+    fn period(&self) -> anyhow::Result<Period> {
+        if self.start_date.is_none() && self.from.is_none() && self.date.is_none() {
+            return Ok(Period::Range(TimeRange {
+                from: 0,
+                to: Utc::now().timestamp() + 24 * 3600,
+            }));
+        }
+        days_period(
+            self.start_date.as_deref(),
+            self.end_date.as_deref(),
+            self.from.as_deref(),
+            self.to.as_deref(),
+            self.date.as_deref(),
+        )
+    }
+}
+
+/// Vector tile with position density or positions
+#[get("/tiles/{z}/{x}/{y}.mvt")]
+async fn vector_tile(
+    db: web::Data<Db>,
+    path: web::Path<(u8, u32, u32)>,
+    params: web::Query<TileParams>,
+) -> HttpResponse {
+    let (z, x, y) = path.into_inner();
+    let options = TileId::new(z, x, y).and_then(|tile| {
+        let device_ids = parse_device_ids(params.devices.as_deref())?;
+        Ok((tile, device_ids, params.period()?))
+    });
+    let (tile, device_ids, period) = match options {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let tile = match points::device_ranges(&db, device_ids.as_deref(), period).await {
+        Ok(ranges) => mvt::tile(&db, tile, &ranges).await,
+        Err(e) => Err(e),
+    };
+    match tile {
+        Ok(data) => HttpResponse::Ok()
+            .content_type("application/vnd.mapbox-vector-tile")
+            .body(data),
+        Err(e) => {
+            log::error!("Failed to build vector tile: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to build vector tile")
+                .finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CoverageParams {
+    /// Comma separated device ids, all devices if missing
+    devices: Option<String>,
+    /// Month in format YYYY-MM
+    month: Option<String>,
+    date: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// First calendar day (YYYY-MM-DD)
+    start_date: Option<String>,
+    /// Last calendar day (YYYY-MM-DD), including
+    end_date: Option<String>,
+    /// Zoom level of grid cells. Default: 16
+    level: Option<u8>,
+}
+
+impl CoverageParams {
+    fn period(&self) -> anyhow::Result<Period> {
+        match &self.month {
+            Some(month) => Period::month(month),
+            None => days_period(
+                self.start_date.as_deref(),
+                self.end_date.as_deref(),
+                self.from.as_deref(),
+                self.to.as_deref(),
+                self.date.as_deref(),
+            ),
+        }
+    }
+}
+
+/// Get GeoJSON grid cells visited within a period
+#[get("/coverage")]
+async fn grid_coverage(db: web::Data<Db>, params: web::Query<CoverageParams>) -> HttpResponse {
+    let options = params.period().and_then(|period| {
+        let device_ids = parse_device_ids(params.devices.as_deref())?;
+        Ok((period, device_ids))
+    });
+    let (period, device_ids) = match options {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let level = params.level.unwrap_or(coverage::DEFAULT_LEVEL);
+    if level > mvt::MAX_ZOOM {
+        return HttpResponse::BadRequest().body(format!("Grid level above {}", mvt::MAX_ZOOM));
+    }
+    let cells = coverage::query_coverage(&db, device_ids.as_deref(), period, level).await;
+    match cells.and_then(|cells| geojson::coverage(&cells)) {
+        Ok(geojson) => HttpResponse::Ok()
+            .content_type("application/geo+json")
+            .body(geojson),
+        Err(e) => {
+            log::error!("Failed to fetch coverage: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to fetch coverage")
+                .finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GeofencesParams {
+    /// User name. All users if missing.
+    u: Option<String>,
+}
+
+/// List geofences as GeoJSON FeatureCollection
+#[get("/geofences")]
+async fn geofence_list(db: web::Data<Db>, params: web::Query<GeofencesParams>) -> HttpResponse {
+    let fences = db.query_geofences(params.u.as_deref()).await;
+    match fences.and_then(|fences| geojson::geofences(&fences)) {
+        Ok(geojson) => HttpResponse::Ok()
+            .content_type("application/geo+json")
+            .body(geojson),
+        Err(e) => {
+            log::error!("Failed to fetch geofences: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to fetch geofences")
+                .finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UserParams {
+    u: String,
+}
+
+/// Create a geofence from a GeoJSON Feature
+#[post("/geofences")]
+async fn create_geofence(
+    db: web::Data<Db>,
+    params: web::Query<UserParams>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let (name, shape) = match parse_geofence(&body) {
+        Ok(fence) => fence,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    match db.insert_geofence(&params.u, &name, &shape).await {
+        Ok(id) => geofence_response(&db, id).await,
+        Err(e) => {
+            log::error!("Failed to create geofence: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to create geofence")
+                .finish()
+        }
+    }
+}
+
+/// Name and shape of a geofence from a GeoJSON Feature.
+/// Polygons exceed the size limit of `Json` requests, so the body is parsed here.
+fn parse_geofence(body: &[u8]) -> anyhow::Result<(String, Shape)> {
+    let feature: ::geojson::Feature = serde_json::from_slice(body)?;
+    Shape::from_feature(&feature)
+}
+
+#[derive(Deserialize)]
+struct TransitionsParams {
+    /// Comma separated device ids, all devices if missing
+    devices: Option<String>,
+    date: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// First calendar day (YYYY-MM-DD)
+    start_date: Option<String>,
+    /// Last calendar day (YYYY-MM-DD), including
+    end_date: Option<String>,
+}
+
+/// List geofence transitions
+#[get("/geofences/transitions")]
+async fn geofence_transitions(
+    db: web::Data<Db>,
+    params: web::Query<TransitionsParams>,
+) -> actix_web::Result<impl Responder> {
+    let period = days_period(
+        params.start_date.as_deref(),
+        params.end_date.as_deref(),
+        params.from.as_deref(),
+        params.to.as_deref(),
+        params.date.as_deref(),
+    )
+    .map_err(error::ErrorBadRequest)?;
+    let device_ids = parse_device_ids(params.devices.as_deref()).map_err(error::ErrorBadRequest)?;
+    match geofences::query_transitions(&db, device_ids.as_deref(), period).await {
+        Ok(transitions) => Ok(web::Json(transitions)),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError(
+                "Failed to fetch transitions",
+            ))
+        }
+    }
+}
+
+/// Get a geofence as GeoJSON Feature
+#[get("/geofences/{id}")]
+async fn get_geofence(db: web::Data<Db>, id: web::Path<i32>) -> HttpResponse {
+    geofence_response(&db, *id).await
+}
+
+async fn geofence_response(db: &Db, id: i32) -> HttpResponse {
+    let feature = match db.query_geofence(id).await {
+        Ok(Some(fence)) => geojson::geofence_feature(&fence),
+        Ok(None) => return HttpResponse::NotFound().body("Unknown geofence"),
+        Err(e) => Err(e),
+    };
+    match feature {
+        Ok(feature) => HttpResponse::Ok()
+            .content_type("application/geo+json")
+            .body(feature.to_string()),
+        Err(e) => {
+            log::error!("Failed to fetch geofence: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to fetch geofence")
+                .finish()
+        }
+    }
+}
+
+/// Replace name and geometry of a geofence
+#[put("/geofences/{id}")]
+async fn update_geofence(db: web::Data<Db>, id: web::Path<i32>, body: web::Bytes) -> HttpResponse {
+    let (name, shape) = match parse_geofence(&body) {
+        Ok(fence) => fence,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    match db.update_geofence(*id, &name, &shape).await {
+        Ok(true) => geofence_response(&db, *id).await,
+        Ok(false) => HttpResponse::NotFound().body("Unknown geofence"),
+        Err(e) => {
+            log::error!("Failed to update geofence: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to update geofence")
+                .finish()
+        }
+    }
+}
+
+/// Delete a geofence and its transitions
+#[delete("/geofences/{id}")]
+async fn delete_geofence(
+    db: web::Data<Db>,
+    id: web::Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    match db.delete_geofence(*id).await {
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Err(error::ErrorNotFound("Unknown geofence")),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to delete geofence"))
+        }
+    }
+}
+
+/// Server-sent events of geofence transitions
+#[get("/events")]
+async fn events(db: web::Data<Db>) -> HttpResponse {
+    let events = futures_util::stream::unfold(db.subscribe_events(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).unwrap_or_default();
+                    let message = web::Bytes::from(format!("data: {data}\n\n"));
+                    return Some((Ok::<_, actix_web::Error>(message), rx));
+                }
+                Err(RecvError::Lagged(count)) => log::warn!("Skipped {count} events"),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// Get GeoJSON track points
+#[get("/trackpoints")]
+async fn trackpoints(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
+    let simplification = match track_ref.simplification() {
+        Ok(simplification) => simplification,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let track_ = match fetch_track(&db, &track_ref).await {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+    let geojson = geojson::track_points(&[track_], simplification.as_ref());
     let json = match geojson {
         Ok(json) => json,
         Err(e) => {
-            log::error!("Failed to fetch track: {e}");
+            log::error!("Failed to export track: {e}");
             return HttpResponse::InternalServerError()
-                .reason("Failed to fetch track")
+                .reason("Failed to export track")
                 .finish();
         }
     };
@@ -233,10 +1181,24 @@ async fn trackpoints(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> Http
         .body(json)
 }
 
+/// Get columnar distance, time, elevation and speed profile
+#[get("/profile")]
+async fn track_profile(db: web::Data<Db>, track_ref: web::Query<TrackRef>) -> HttpResponse {
+    let track_ = match fetch_track(&db, &track_ref).await {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+    HttpResponse::Ok().json(profile::profile(&track_, track_ref.samples.unwrap_or(500)))
+}
+
 /// Get GeoJSON with current device positions
 #[get("/positions")]
 async fn positions(db: web::Data<Db>, params: web::Query<TracksParams>) -> HttpResponse {
-    let positions = match db.query_positions(&params.date).await {
+    let period = match params.period() {
+        Ok(period) => period,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let positions = match db.query_positions(period).await {
         Ok(data) => data,
         Err(e) => {
             log::error!("Failed to fetch positions: {e}");
@@ -291,6 +1253,7 @@ async fn serve_assets(path: web::Path<String>) -> EmbedResponse<EmbedableFileRes
 pub async fn webserver(db: Db) -> std::io::Result<()> {
     let bind_addr = dotenvy::var("HTTP_LISTEN").unwrap_or("0.0.0.0:8083".to_string());
     log::info!("Listening on http://{bind_addr}/");
+    let import_config = ImportConfig::from_env();
     HttpServer::new(move || {
         let cors = if cfg!(debug_assertions) {
             Cors::permissive()
@@ -320,15 +1283,38 @@ pub async fn webserver(db: Db) -> std::io::Result<()> {
             .wrap(middleware::Compress::default())
             .wrap(cors)
             .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::new(import_config))
             .service(owntracks)
             .service(rawjson)
+            .service(import_files)
+            .service(timezone)
             .service(trackinfos)
+            .service(trip_list)
+            .service(set_filter)
+            .service(visits)
+            .service(top_places)
+            .service(rename_place)
             .service(gpxtrack)
+            .service(kmltrack)
+            .service(tcxtrack)
+            .service(fittrack)
             .service(csvtrack)
+            .service(export_tracks)
+            .service(vector_tile)
+            .service(grid_coverage)
+            .service(geofence_list)
+            .service(create_geofence)
+            .service(geofence_transitions)
+            .service(get_geofence)
+            .service(update_geofence)
+            .service(delete_geofence)
+            .service(events)
             .service(track)
             .service(trackpoints)
+            .service(track_profile)
             .service(positions)
             .service(otrc)
+            .configure(recorder_api::config)
             .service(serve_assets)
     })
     .bind(bind_addr)?
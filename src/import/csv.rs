@@ -0,0 +1,41 @@
+use crate::position::Position;
+use crate::timestamp::parse_timestamp;
+use serde::Deserialize;
+use std::io::BufRead;
+
+/// Row of CSV track export
+#[derive(Deserialize)]
+struct CsvPoint {
+    timestamp: String,
+    latitude: f64,
+    longitude: f64,
+    speed: Option<u16>,
+    elevation: Option<i16>,
+    accuracy: Option<u32>,
+    v_accuracy: Option<i16>,
+    cog: Option<i16>,
+}
+
+/// Read positions from CSV in the format of the track export
+pub fn read_positions(reader: impl BufRead, tid: &str) -> anyhow::Result<Vec<Position>> {
+    let mut rdr = csv::Reader::from_reader(reader);
+    let mut positions = Vec::new();
+    for record in rdr.deserialize() {
+        let point: CsvPoint = record?;
+        let Some(ts) = parse_timestamp(&point.timestamp) else {
+            log::warn!(
+                "Skipping CSV row with invalid timestamp `{}`",
+                point.timestamp
+            );
+            continue;
+        };
+        let mut pos = Position::new(tid, ts, point.latitude, point.longitude);
+        pos.velocity = point.speed;
+        pos.alt = point.elevation;
+        pos.accuracy = point.accuracy;
+        pos.v_accuracy = point.v_accuracy;
+        pos.cog = point.cog;
+        positions.push(pos);
+    }
+    Ok(positions)
+}
@@ -0,0 +1,125 @@
+use crate::position::Position;
+use crate::timestamp::{epoch_seconds, parse_timestamp};
+use geojson::{Feature, GeoJson, JsonObject, JsonValue, Value};
+use std::io::BufRead;
+
+/// Properties mapped to position attributes
+const POSITION_PROPERTIES: &[&str] = &[
+    "time",
+    "timestamp",
+    "tst",
+    "speed",
+    "elevation",
+    "accuracy",
+    "v_accuracy",
+    "cog",
+    "trackno",
+    "coordTimes",
+    "coordinateProperties",
+];
+
+/// Read positions from GeoJSON points with time property
+/// and lines with `coordTimes` or `coordinateProperties.times`
+pub fn read_positions(reader: impl BufRead, tid: &str) -> anyhow::Result<Vec<Position>> {
+    let geojson = GeoJson::from_reader(reader)?;
+    let features = match geojson {
+        GeoJson::FeatureCollection(fc) => fc.features,
+        GeoJson::Feature(feature) => vec![feature],
+        GeoJson::Geometry(geometry) => vec![Feature::from(geometry)],
+    };
+    let mut positions = Vec::new();
+    for feature in &features {
+        let Some(geometry) = &feature.geometry else {
+            continue;
+        };
+        let empty = JsonObject::new();
+        let props = feature.properties.as_ref().unwrap_or(&empty);
+        match &geometry.value {
+            Value::Point(coord) => {
+                let Some(ts) = time_value(props.get("time"))
+                    .or_else(|| time_value(props.get("timestamp")))
+                    .or_else(|| time_value(props.get("tst")))
+                else {
+                    log::warn!("Skipping GeoJSON point without time");
+                    continue;
+                };
+                let mut pos = position(coord, ts, tid);
+                pos.velocity = int_prop(props, "speed");
+                pos.alt = int_prop(props, "elevation").or(pos.alt);
+                pos.accuracy = int_prop(props, "accuracy");
+                pos.v_accuracy = int_prop(props, "v_accuracy");
+                pos.cog = int_prop(props, "cog");
+                let annotations: JsonObject = props
+                    .iter()
+                    .filter(|(key, _)| !POSITION_PROPERTIES.contains(&key.as_str()))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                pos.annotations = JsonValue::from(annotations).to_string();
+                positions.push(pos);
+            }
+            Value::LineString(line) => {
+                positions.extend(line_positions(line, coord_times(props, 0), tid));
+            }
+            Value::MultiLineString(lines) => {
+                for (idx, line) in lines.iter().enumerate() {
+                    positions.extend(line_positions(line, coord_times(props, idx), tid));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(positions)
+}
+
+fn position(coord: &[f64], ts: i64, tid: &str) -> Position {
+    let mut pos = Position::new(tid, ts, coord[1], coord[0]);
+    pos.alt = coord.get(2).map(|val| val.round() as i16);
+    pos
+}
+
+fn line_positions(line: &[Vec<f64>], times: Option<Vec<Option<i64>>>, tid: &str) -> Vec<Position> {
+    let Some(times) = times.filter(|times| times.len() == line.len()) else {
+        log::warn!("Skipping GeoJSON line without coordinate times");
+        return Vec::new();
+    };
+    line.iter()
+        .zip(times)
+        .filter_map(|(coord, ts)| Some(position(coord, ts?, tid)))
+        .collect()
+}
+
+/// Coordinate times of a line (index > 0 for MultiLineString)
+fn coord_times(props: &JsonObject, idx: usize) -> Option<Vec<Option<i64>>> {
+    let times = props.get("coordTimes").or_else(|| {
+        props
+            .get("coordinateProperties")
+            .and_then(|coord_props| coord_props.get("times"))
+    })?;
+    let times = match times.as_array()?.first() {
+        Some(JsonValue::Array(_)) => times.get(idx)?,
+        _ => times,
+    };
+    Some(
+        times
+            .as_array()?
+            .iter()
+            .map(|ts| time_value(Some(ts)))
+            .collect(),
+    )
+}
+
+fn time_value(value: Option<&JsonValue>) -> Option<i64> {
+    match value? {
+        JsonValue::String(ts) => parse_timestamp(ts),
+        JsonValue::Number(ts) => ts.as_i64().map(epoch_seconds),
+        _ => None,
+    }
+}
+
+fn int_prop<T: TryFrom<i64>>(props: &JsonObject, key: &str) -> Option<T> {
+    let value = props.get(key)?;
+    let value = value
+        .as_i64()
+        .or_else(|| value.as_f64().map(|v| v.round() as i64))?;
+    T::try_from(value).ok()
+}
@@ -0,0 +1,48 @@
+use crate::position::Position;
+use gpx::Waypoint;
+use serde_json::json;
+use std::io::BufRead;
+use time::OffsetDateTime;
+
+/// Read GPX track points, route points and waypoints
+pub fn read_positions(reader: impl BufRead, tid: &str) -> anyhow::Result<Vec<Position>> {
+    let gpx = gpx::read(reader)?;
+    let track_points = gpx
+        .tracks
+        .iter()
+        .flat_map(|track| track.segments.iter())
+        .flat_map(|segment| segment.points.iter());
+    let route_points = gpx.routes.iter().flat_map(|route| route.points.iter());
+    let points: Vec<(&Waypoint, bool)> = track_points
+        .chain(route_points)
+        .map(|wpt| (wpt, false))
+        .chain(gpx.waypoints.iter().map(|wpt| (wpt, true)))
+        .collect();
+    let positions: Vec<Position> = points
+        .iter()
+        .filter_map(|(wpt, is_waypoint)| {
+            let mut pos = position(wpt, tid)?;
+            // Named waypoints are stored as points of interest
+            if let (true, Some(name)) = (*is_waypoint, &wpt.name) {
+                pos.annotations = json!({ "poi": name }).to_string();
+            }
+            Some(pos)
+        })
+        .collect();
+    let skipped = points.len() - positions.len();
+    if skipped > 0 {
+        log::warn!("Skipping {skipped} GPX points without time");
+    }
+    Ok(positions)
+}
+
+fn position(wpt: &Waypoint, tid: &str) -> Option<Position> {
+    let time: OffsetDateTime = wpt.time?.into();
+    let point = wpt.point();
+    let mut pos = Position::new(tid, time.unix_timestamp(), point.y(), point.x());
+    pos.alt = wpt.elevation.map(|val| val.round() as i16);
+    pos.velocity = wpt.speed.map(|val| (val * 3.6).round() as u16);
+    // Rough estimation of accuracy from horizontal dilution of precision
+    pos.accuracy = wpt.hdop.map(|val| (val * 5.0).round() as u32);
+    Some(pos)
+}
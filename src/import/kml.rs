@@ -0,0 +1,106 @@
+use crate::position::Position;
+use crate::timestamp::parse_timestamp;
+use serde_json::json;
+use std::io::BufRead;
+use xml::reader::{EventReader, XmlEvent};
+
+/// Placemark content collected while parsing
+#[derive(Default)]
+struct Placemark {
+    name: Option<String>,
+    /// `TimeStamp` of a point placemark
+    when: Option<i64>,
+    /// Coordinates of a point placemark
+    point: Option<(f64, f64, Option<f64>)>,
+    /// `when` elements of a `gx:Track`
+    track_whens: Vec<Option<i64>>,
+    /// `gx:coord` elements of a `gx:Track`
+    track_coords: Vec<(f64, f64, Option<f64>)>,
+}
+
+/// Read positions from KML point placemarks with `TimeStamp` and `gx:Track` elements
+pub fn read_positions(reader: impl BufRead, tid: &str) -> anyhow::Result<Vec<Position>> {
+    let mut positions = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut placemark = Placemark::default();
+    for event in EventReader::new(reader) {
+        match event? {
+            XmlEvent::StartElement { name, .. } => {
+                if name.local_name == "Placemark" {
+                    placemark = Placemark::default();
+                }
+                path.push(name.local_name);
+            }
+            XmlEvent::EndElement { name } => {
+                path.pop();
+                match name.local_name.as_str() {
+                    "Track" => {
+                        let whens = std::mem::take(&mut placemark.track_whens);
+                        let coords = std::mem::take(&mut placemark.track_coords);
+                        if whens.len() != coords.len() {
+                            log::warn!(
+                                "Skipping gx:Track with different number of times and coordinates"
+                            );
+                            continue;
+                        }
+                        positions.extend(
+                            whens
+                                .into_iter()
+                                .zip(coords)
+                                .filter_map(|(ts, coord)| Some(position(coord, ts?, tid))),
+                        );
+                    }
+                    "Placemark" => {
+                        if let (Some(ts), Some(coord)) = (placemark.when, placemark.point) {
+                            let mut pos = position(coord, ts, tid);
+                            if let Some(name) = &placemark.name {
+                                pos.annotations = json!({ "poi": name }).to_string();
+                            }
+                            positions.push(pos);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::Characters(text) => {
+                let parent = path.iter().rev().nth(1).map(String::as_str);
+                match (parent, path.last().map(String::as_str)) {
+                    (Some("Placemark"), Some("name")) => placemark.name = Some(text),
+                    (Some("TimeStamp"), Some("when")) => placemark.when = parse_timestamp(&text),
+                    (Some("Track"), Some("when")) => {
+                        placemark.track_whens.push(parse_timestamp(&text))
+                    }
+                    (Some("Track"), Some("coord")) => {
+                        if let Some(coord) = parse_coord(&text, ' ') {
+                            placemark.track_coords.push(coord);
+                        }
+                    }
+                    (Some("Point"), Some("coordinates")) => {
+                        placemark.point = parse_coord(&text, ',');
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(positions)
+}
+
+/// Parse `lon,lat[,alt]` (KML) or `lon lat [alt]` (gx:coord) coordinate
+fn parse_coord(text: &str, separator: char) -> Option<(f64, f64, Option<f64>)> {
+    let mut values = text
+        .trim()
+        .split(separator)
+        .map(|val| val.trim().parse::<f64>());
+    let lon = values.next()?.ok()?;
+    let lat = values.next()?.ok()?;
+    let alt = values.next().and_then(Result::ok);
+    Some((lon, lat, alt))
+}
+
+fn position((lon, lat, alt): (f64, f64, Option<f64>), ts: i64, tid: &str) -> Position {
+    let mut pos = Position::new(tid, ts, lat, lon);
+    pos.alt = alt.map(|val| val.round() as i16);
+    pos
+}
@@ -0,0 +1,330 @@
+//! Import of location history files
+
+mod csv;
+mod geojson;
+mod gpx;
+mod kml;
+mod recorder;
+mod takeout;
+
+use crate::db::Db;
+use crate::position::Position;
+use bytes::{Buf, Bytes};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+use tokio::sync::mpsc;
+
+/// Number of positions inserted within one transaction
+const BATCH_SIZE: usize = 1000;
+
+/// Supported import file formats
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Gpx,
+    GeoJson,
+    Kml,
+    Csv,
+    /// Google Takeout `Records.json` or on-device `Timeline.json`
+    Takeout,
+    /// OwnTracks Recorder `.rec` file
+    Recorder,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gpx" => Ok(Format::Gpx),
+            "geojson" => Ok(Format::GeoJson),
+            "kml" => Ok(Format::Kml),
+            "csv" => Ok(Format::Csv),
+            "takeout" | "records" | "timeline" => Ok(Format::Takeout),
+            "rec" | "recorder" => Ok(Format::Recorder),
+            _ => anyhow::bail!("Unsupported import format `{s}`"),
+        }
+    }
+}
+
+impl Format {
+    /// Detect format from file extension.
+    /// `.json` files are GeoJSON or Google location history and detected from content.
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        let ext = Path::new(filename).extension()?.to_str()?;
+        Format::from_str(ext).ok()
+    }
+
+    /// Detect format from the beginning of the file content
+    pub fn from_content(reader: &mut impl BufRead) -> Option<Self> {
+        let buf = String::from_utf8_lossy(reader.fill_buf().ok()?);
+        if buf.contains("<gpx") {
+            Some(Format::Gpx)
+        } else if buf.contains("<kml") {
+            Some(Format::Kml)
+        } else if buf.contains("\"FeatureCollection\"") || buf.contains("\"Feature\"") {
+            Some(Format::GeoJson)
+        } else if buf.contains("\"semanticSegments\"")
+            || buf.contains("\"rawSignals\"")
+            || buf.contains("\"latitudeE7\"")
+        {
+            Some(Format::Takeout)
+        } else if buf.contains("\"type\"") {
+            Some(Format::GeoJson)
+        } else if is_csv_header(buf.lines().next().unwrap_or_default()) {
+            Some(Format::Csv)
+        } else if buf.lines().next().is_some_and(|line| line.contains("\t{")) {
+            Some(Format::Recorder)
+        } else {
+            None
+        }
+    }
+}
+
+/// CSV header with the required columns of the track export
+fn is_csv_header(line: &str) -> bool {
+    let columns: Vec<&str> = line
+        .trim_start_matches('\u{feff}')
+        .split(',')
+        .map(|column| column.trim().trim_matches('"'))
+        .collect();
+    ["timestamp", "latitude", "longitude"]
+        .iter()
+        .all(|required| columns.contains(required))
+}
+
+/// Import result
+#[derive(Serialize, Default, Debug)]
+pub struct ImportStats {
+    /// Number of positions read from file
+    pub read: usize,
+    /// Number of new positions stored in the database
+    pub inserted: usize,
+}
+
+/// Collects positions and passes them in batches to the database writer
+pub struct BatchSender {
+    tx: mpsc::Sender<Vec<Position>>,
+    batch: Vec<Position>,
+}
+
+impl BatchSender {
+    fn new(tx: mpsc::Sender<Vec<Position>>) -> Self {
+        BatchSender {
+            tx,
+            batch: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    pub fn push(&mut self, pos: Position) -> anyhow::Result<()> {
+        self.batch.push(pos);
+        if self.batch.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if !self.batch.is_empty() {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+            self.tx
+                .blocking_send(batch)
+                .map_err(|_| anyhow::anyhow!("Import aborted"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Read positions from a file in the given format
+pub fn read_positions(
+    format: Format,
+    reader: impl BufRead,
+    tid: &str,
+    sender: &mut BatchSender,
+) -> anyhow::Result<()> {
+    let mut positions = match format {
+        Format::Gpx => gpx::read_positions(reader, tid)?,
+        Format::GeoJson => geojson::read_positions(reader, tid)?,
+        Format::Kml => kml::read_positions(reader, tid)?,
+        Format::Csv => csv::read_positions(reader, tid)?,
+        // Streaming readers for large files
+        Format::Takeout => return takeout::read_positions(reader, tid, sender),
+        Format::Recorder => return recorder::read_positions(reader, tid, sender),
+    };
+    positions.sort_by_key(|pos| pos.ts);
+    for pos in positions {
+        sender.push(pos)?;
+    }
+    Ok(())
+}
+
+/// Read positions in a blocking thread and store them in batches
+pub async fn import(
+    db: &Db,
+    user: &str,
+    device: &str,
+    format: Option<Format>,
+    mut reader: impl BufRead + Send + 'static,
+) -> anyhow::Result<ImportStats> {
+    let tid = default_tid(device);
+    let (tx, mut rx) = mpsc::channel(4);
+    let reader_task = tokio::task::spawn_blocking(move || {
+        // Detection reads from the blocking reader
+        let Some(format) = format.or_else(|| Format::from_content(&mut reader)) else {
+            anyhow::bail!("Unknown file format");
+        };
+        let mut sender = BatchSender::new(tx);
+        read_positions(format, reader, &tid, &mut sender)?;
+        sender.flush()
+    });
+    let mut stats = ImportStats::default();
+    while let Some(batch) = rx.recv().await {
+        stats.read += batch.len();
+        stats.inserted += db.insert_positions(user, device, &batch).await?;
+    }
+    reader_task.await??;
+    Ok(stats)
+}
+
+/// Blocking reader of chunks sent from an async upload stream
+pub struct ChunkReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl ChunkReader {
+    /// Sender of chunks and reader for an import
+    pub fn channel() -> (mpsc::Sender<io::Result<Bytes>>, Self) {
+        let (tx, rx) = mpsc::channel(16);
+        let reader = ChunkReader {
+            rx,
+            chunk: Bytes::new(),
+        };
+        (tx, reader)
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk[..len]);
+        self.chunk.advance(len);
+        Ok(len)
+    }
+}
+
+/// Tracker ID derived from device name
+pub fn default_tid(device: &str) -> String {
+    device.chars().take(2).collect()
+}
+
+const USAGE: &str = "Usage: owntrack-rs import --user <USER> --device <DEVICE> [--format <gpx|geojson|kml|csv|takeout|rec>] <FILE>...";
+const RECORDER_USAGE: &str = "Usage: owntrack-rs import-recorder <STORE_DIR>";
+
+/// Command line import
+pub async fn cli(db: &Db, args: &[String]) -> anyhow::Result<()> {
+    let mut user = None;
+    let mut device = None;
+    let mut format = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-u" | "--user" => user = args.next().cloned(),
+            "-d" | "--device" => device = args.next().cloned(),
+            "-f" | "--format" => format = args.next().map(|f| Format::from_str(f)).transpose()?,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            opt if opt.starts_with('-') => anyhow::bail!("Unknown option `{opt}`\n{USAGE}"),
+            file => files.push(file.to_string()),
+        }
+    }
+    let (Some(user), Some(device)) = (user, device) else {
+        anyhow::bail!("User and device required\n{USAGE}");
+    };
+    if files.is_empty() {
+        anyhow::bail!("No input files\n{USAGE}");
+    }
+    for file in files {
+        let reader = BufReader::new(File::open(&file)?);
+        let format = format.or_else(|| Format::from_filename(&file));
+        let stats = import(db, &user, &device, format, reader)
+            .await
+            .map_err(|e| anyhow::anyhow!("{file}: {e}"))?;
+        log::info!(
+            "{file}: {} positions read, {} inserted",
+            stats.read,
+            stats.inserted
+        );
+    }
+    Ok(())
+}
+
+/// Command line import of an OwnTracks Recorder store
+pub async fn recorder_cli(db: &Db, args: &[String]) -> anyhow::Result<()> {
+    let [store_dir] = args else {
+        anyhow::bail!("{RECORDER_USAGE}");
+    };
+    if store_dir == "-h" || store_dir == "--help" {
+        println!("{RECORDER_USAGE}");
+        return Ok(());
+    }
+    let stats = recorder::import_store(db, Path::new(store_dir)).await?;
+    log::info!(
+        "{store_dir}: {} positions read, {} inserted",
+        stats.read,
+        stats.inserted
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(content: &str) -> Option<Format> {
+        Format::from_content(&mut content.as_bytes())
+    }
+
+    #[test]
+    fn detect_json_formats() {
+        assert_eq!(Format::from_filename("track.json"), None);
+        assert_eq!(
+            detect(r#"{"type": "FeatureCollection", "features": []}"#),
+            Some(Format::GeoJson)
+        );
+        assert_eq!(
+            detect(
+                r#"{"locations": [{"latitudeE7": 473000000, "activity": [{"type": "STILL"}]}]}"#
+            ),
+            Some(Format::Takeout)
+        );
+        assert_eq!(
+            detect(r#"{"semanticSegments": [{"startTime": "2024-01-01T00:00:00Z"}]}"#),
+            Some(Format::Takeout)
+        );
+    }
+
+    #[test]
+    fn detect_csv() {
+        assert_eq!(
+            detect("timestamp,latitude,longitude,speed\n1717369260,47.0,8.0,15\n"),
+            Some(Format::Csv)
+        );
+        assert_eq!(
+            detect("device,timestamp,latitude,longitude\nphone,1717369260,47.0,8.0\n"),
+            Some(Format::Csv)
+        );
+        assert_eq!(detect("timestamp,value\n1717369260,1\n"), None);
+    }
+}
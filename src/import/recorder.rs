@@ -0,0 +1,106 @@
+//! [OwnTracks Recorder](https://github.com/owntracks/recorder) store import
+
+use crate::db::Db;
+use crate::import::{import, BatchSender, Format, ImportStats};
+use crate::owntracks::Message;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Read locations from a Recorder `.rec` file.
+///
+/// Lines contain a timestamp, the record type (`*` for publishes) and the JSON payload separated by tabs:
+/// `2025-02-19T06:46:54Z<TAB>*<TAB>{"_type":"location",...}`
+pub fn read_positions(
+    reader: impl BufRead,
+    tid: &str,
+    sender: &mut BatchSender,
+) -> anyhow::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        let Some(json) = line.splitn(3, '\t').nth(2) else {
+            continue;
+        };
+        match serde_json::from_str::<Message>(json) {
+            Ok(Message::Location(mut pos)) => {
+                if pos.tid.is_empty() {
+                    pos.tid = tid.to_string();
+                }
+                sender.push(pos)?;
+            }
+            Ok(_) => {}
+            Err(e) => log::debug!("Skipping Recorder line `{line}`: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Import all `.rec` files of a Recorder store with layout `rec/<user>/<device>/YYYY-MM.rec`.
+/// Unreadable files are skipped.
+pub async fn import_store(db: &Db, store_dir: &Path) -> anyhow::Result<ImportStats> {
+    let rec_dir = if store_dir.join("rec").is_dir() {
+        store_dir.join("rec")
+    } else {
+        store_dir.to_path_buf()
+    };
+    let mut stats = ImportStats::default();
+    let mut skipped = 0;
+    for user_dir in sorted_entries(&rec_dir)? {
+        if !user_dir.is_dir() {
+            continue;
+        }
+        let user = file_name(&user_dir);
+        for device_dir in sorted_entries(&user_dir)? {
+            if !device_dir.is_dir() {
+                continue;
+            }
+            let device = file_name(&device_dir);
+            for rec_file in sorted_entries(&device_dir)? {
+                if rec_file.extension().is_none_or(|ext| ext != "rec") {
+                    continue;
+                }
+                let file_stats = match File::open(&rec_file) {
+                    Ok(file) => {
+                        let reader = BufReader::new(file);
+                        import(db, &user, &device, Some(Format::Recorder), reader).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                let file_stats = match file_stats {
+                    Ok(file_stats) => file_stats,
+                    Err(e) => {
+                        log::warn!("Skipping {}: {e}", rec_file.display());
+                        skipped += 1;
+                        continue;
+                    }
+                };
+                log::info!(
+                    "{}: {} positions read, {} inserted",
+                    rec_file.display(),
+                    file_stats.read,
+                    file_stats.inserted
+                );
+                stats.read += file_stats.read;
+                stats.inserted += file_stats.inserted;
+            }
+        }
+    }
+    if skipped > 0 {
+        log::warn!("{skipped} files skipped");
+    }
+    Ok(stats)
+}
+
+fn sorted_entries(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,309 @@
+//! Google location history: Takeout `Records.json` and on-device `Timeline.json` exports
+
+use crate::import::BatchSender;
+use crate::position::Position;
+use crate::timestamp::parse_timestamp;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+use std::io::BufRead;
+use std::marker::PhantomData;
+
+/// Takeout `Records.json` location
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    latitude_e7: Option<i64>,
+    longitude_e7: Option<i64>,
+    /// Accuracy in meters
+    accuracy: Option<u32>,
+    /// Velocity in m/s
+    velocity: Option<f64>,
+    /// Heading in degrees
+    heading: Option<i16>,
+    /// Altitude in meters
+    altitude: Option<f64>,
+    vertical_accuracy: Option<i16>,
+    timestamp: Option<String>,
+    /// Timestamp of older exports
+    timestamp_ms: Option<String>,
+    source: Option<String>,
+}
+
+/// `Timeline.json` segment
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SemanticSegment {
+    start_time: Option<String>,
+    end_time: Option<String>,
+    timeline_path: Option<Vec<PathPoint>>,
+    visit: Option<Visit>,
+    activity: Option<Activity>,
+}
+
+#[derive(Deserialize)]
+struct PathPoint {
+    /// Location like `47.0500077°, 9.4374692°`
+    point: String,
+    time: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Visit {
+    top_candidate: Option<VisitCandidate>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VisitCandidate {
+    place_id: Option<String>,
+    semantic_type: Option<String>,
+    place_location: Option<LatLng>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LatLng {
+    lat_lng: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Activity {
+    start: Option<LatLng>,
+    end: Option<LatLng>,
+    top_candidate: Option<ActivityCandidate>,
+}
+
+#[derive(Deserialize)]
+struct ActivityCandidate {
+    #[serde(rename = "type")]
+    type_: Option<String>,
+}
+
+/// `Timeline.json` raw signal (position, wifi scan or activity record)
+#[derive(Deserialize)]
+struct RawSignal {
+    position: Option<RawPosition>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawPosition {
+    #[serde(rename = "LatLng")]
+    lat_lng: String,
+    accuracy_meters: Option<f64>,
+    altitude_meters: Option<f64>,
+    speed_meters_per_second: Option<f64>,
+    timestamp: String,
+    source: Option<String>,
+}
+
+/// Read positions from `Records.json` or `Timeline.json` without loading the whole file
+pub fn read_positions(
+    reader: impl BufRead,
+    tid: &str,
+    sender: &mut BatchSender,
+) -> anyhow::Result<()> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    deserializer.deserialize_map(TakeoutVisitor { tid, sender })?;
+    Ok(())
+}
+
+/// Visitor of the top level object, streaming the known arrays
+struct TakeoutVisitor<'a> {
+    tid: &'a str,
+    sender: &'a mut BatchSender,
+}
+
+impl<'de> Visitor<'de> for TakeoutVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a Google location history object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "locations" => map.next_value_seed(SeqStream::new(
+                    self.tid,
+                    &mut *self.sender,
+                    record_positions,
+                ))?,
+                "semanticSegments" => map.next_value_seed(SeqStream::new(
+                    self.tid,
+                    &mut *self.sender,
+                    segment_positions,
+                ))?,
+                "rawSignals" => map.next_value_seed(SeqStream::new(
+                    self.tid,
+                    &mut *self.sender,
+                    signal_positions,
+                ))?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes array elements one by one and passes the converted positions to the sender
+struct SeqStream<'a, T> {
+    tid: &'a str,
+    sender: &'a mut BatchSender,
+    convert: fn(T, &str) -> Vec<Position>,
+    element: PhantomData<T>,
+}
+
+impl<'a, T> SeqStream<'a, T> {
+    fn new(
+        tid: &'a str,
+        sender: &'a mut BatchSender,
+        convert: fn(T, &str) -> Vec<Position>,
+    ) -> Self {
+        SeqStream {
+            tid,
+            sender,
+            convert,
+            element: PhantomData,
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for SeqStream<'_, T> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for SeqStream<'_, T> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(element) = seq.next_element::<T>()? {
+            for pos in (self.convert)(element, self.tid) {
+                self.sender.push(pos).map_err(de::Error::custom)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn record_positions(record: Record, tid: &str) -> Vec<Position> {
+    let ts = record
+        .timestamp
+        .as_deref()
+        .or(record.timestamp_ms.as_deref())
+        .and_then(parse_timestamp);
+    let (Some(ts), Some(lat), Some(lon)) = (ts, record.latitude_e7, record.longitude_e7) else {
+        return Vec::new();
+    };
+    let mut pos = Position::new(tid, ts, e7_degrees(lat), e7_degrees(lon));
+    pos.accuracy = record.accuracy;
+    pos.velocity = record.velocity.map(|val| (val * 3.6).round() as u16);
+    pos.cog = record.heading;
+    pos.alt = record.altitude.map(|val| val.round() as i16);
+    pos.v_accuracy = record.vertical_accuracy;
+    if let Some(source) = record.source {
+        pos.annotations = json!({ "source": source }).to_string();
+    }
+    vec![pos]
+}
+
+fn segment_positions(segment: SemanticSegment, tid: &str) -> Vec<Position> {
+    let start_time = segment.start_time.as_deref().and_then(parse_timestamp);
+    let end_time = segment.end_time.as_deref().and_then(parse_timestamp);
+    let mut positions = Vec::new();
+    for point in segment.timeline_path.unwrap_or_default() {
+        if let (Some(ts), Some((lat, lon))) =
+            (parse_timestamp(&point.time), parse_lat_lng(&point.point))
+        {
+            positions.push(Position::new(tid, ts, lat, lon));
+        }
+    }
+    if let Some(candidate) = segment.visit.and_then(|visit| visit.top_candidate) {
+        let location = candidate
+            .place_location
+            .and_then(|loc| loc.lat_lng)
+            .and_then(|lat_lng| parse_lat_lng(&lat_lng));
+        if let Some((lat, lon)) = location {
+            // Store arrival and departure of the visit
+            for (ts, event) in [(start_time, "arrival"), (end_time, "departure")] {
+                if let Some(ts) = ts {
+                    let mut pos = Position::new(tid, ts, lat, lon);
+                    pos.annotations = json!({
+                        "visit": event,
+                        "place_id": candidate.place_id,
+                        "semantic_type": candidate.semantic_type,
+                    })
+                    .to_string();
+                    positions.push(pos);
+                }
+            }
+        }
+    }
+    if let Some(activity) = segment.activity {
+        let activity_type = activity.top_candidate.and_then(|candidate| candidate.type_);
+        for (ts, location) in [(start_time, activity.start), (end_time, activity.end)] {
+            let location = location
+                .and_then(|loc| loc.lat_lng)
+                .and_then(|lat_lng| parse_lat_lng(&lat_lng));
+            if let (Some(ts), Some((lat, lon))) = (ts, location) {
+                let mut pos = Position::new(tid, ts, lat, lon);
+                pos.annotations = json!({ "activity": activity_type }).to_string();
+                positions.push(pos);
+            }
+        }
+    }
+    positions
+}
+
+fn signal_positions(signal: RawSignal, tid: &str) -> Vec<Position> {
+    let Some(raw) = signal.position else {
+        return Vec::new();
+    };
+    let (Some(ts), Some((lat, lon))) =
+        (parse_timestamp(&raw.timestamp), parse_lat_lng(&raw.lat_lng))
+    else {
+        return Vec::new();
+    };
+    let mut pos = Position::new(tid, ts, lat, lon);
+    pos.accuracy = raw.accuracy_meters.map(|val| val.round() as u32);
+    pos.alt = raw.altitude_meters.map(|val| val.round() as i16);
+    pos.velocity = raw
+        .speed_meters_per_second
+        .map(|val| (val * 3.6).round() as u16);
+    if let Some(source) = raw.source {
+        pos.annotations = json!({ "source": source }).to_string();
+    }
+    vec![pos]
+}
+
+/// Convert E7 coordinate to degrees
+fn e7_degrees(val: i64) -> f64 {
+    // Some exports contain values overflowed as unsigned 32 bit integer
+    let val = if val > 1_800_000_000 {
+        val - 4_294_967_296
+    } else {
+        val
+    };
+    val as f64 / 1e7
+}
+
+/// Parse `47.0500077°, 9.4374692°` or `geo:47.0500077,9.4374692`
+fn parse_lat_lng(lat_lng: &str) -> Option<(f64, f64)> {
+    let lat_lng = lat_lng.trim_start_matches("geo:").replace('°', "");
+    let (lat, lon) = lat_lng.split_once(',')?;
+    Some((lat.trim().parse().ok()?, lon.trim().parse().ok()?))
+}
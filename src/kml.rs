@@ -0,0 +1,194 @@
+//! KML and KMZ export with time-enabled tracks
+
+use crate::db::TrackData;
+use crate::filter::{Filter, FilterParams};
+use crate::gpx::waypoint_name;
+use crate::timestamp::format_timestamp;
+use crate::trips;
+use crate::xml_writer::{text_element, writer, Writer};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use xml::writer::XmlEvent;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+const KML_NS: &str = "http://www.opengis.net/kml/2.2";
+const GX_NS: &str = "http://www.google.com/kml/ext/2.2";
+/// Track line colors in KML format (aabbggrr)
+const TRACK_COLORS: &[&str] = &[
+    "ffff0000", "ff0000ff", "ff00a000", "ff00a5ff", "ffa000a0", "ffa0a000", "ff0050a0", "ff800080",
+];
+const TRACK_ICON: &str = "http://maps.google.com/mapfiles/kml/shapes/track.png";
+const STOP_ICON: &str = "http://maps.google.com/mapfiles/kml/paddle/red-circle.png";
+const EVENT_ICON: &str = "http://maps.google.com/mapfiles/kml/paddle/ylw-stars.png";
+
+/// Device name and tracker ID used for labels and styles
+pub struct Device {
+    /// `user/device`
+    pub name: String,
+    pub tid: String,
+}
+
+/// Build KML with a `gx:Track` per track and placemarks for stops, POIs and transitions.
+pub fn tracks(tracks: &[TrackData], devices: &HashMap<i32, Device>) -> anyhow::Result<String> {
+    let mut w = writer();
+    w.write(
+        XmlEvent::start_element("kml")
+            .default_ns(KML_NS)
+            .ns("gx", GX_NS),
+    )?;
+    w.write(XmlEvent::start_element("Document"))?;
+    text_element(&mut w, "name", "owntrack-rs")?;
+    write_icon_style(&mut w, "stop", STOP_ICON)?;
+    write_icon_style(&mut w, "event", EVENT_ICON)?;
+    let mut styles = Vec::new();
+    for track in tracks {
+        let style = format!("track-{}", track.device_id);
+        if !styles.contains(&style) {
+            let tid = devices
+                .get(&track.device_id)
+                .map(|device| device.tid.as_str())
+                .unwrap_or_default();
+            write_track_style(&mut w, &style, track_color(tid))?;
+            styles.push(style);
+        }
+    }
+
+    for track in tracks {
+        let device = devices.get(&track.device_id);
+        let name = match device {
+            Some(device) => format!("{} {}", device.name, track.date),
+            None => format!("Track {}-{}", track.date, track.device_id),
+        };
+        w.write(XmlEvent::start_element("Folder"))?;
+        text_element(&mut w, "name", &name)?;
+        write_track(&mut w, track, device)?;
+        write_stops(&mut w, track)?;
+        write_events(&mut w, track)?;
+        w.write(XmlEvent::end_element())?;
+    }
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    Ok(String::from_utf8(w.into_inner())?)
+}
+
+/// Compress KML into a KMZ archive
+pub fn kmz(kml: &str) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("doc.kml", SimpleFileOptions::default())?;
+    zip.write_all(kml.as_bytes())?;
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Placemark with timestamps and coordinates for the Google Earth time slider
+fn write_track(w: &mut Writer, track: &TrackData, device: Option<&Device>) -> anyhow::Result<()> {
+    w.write(XmlEvent::start_element("Placemark"))?;
+    let label = device.map(|device| device.tid.as_str()).unwrap_or("Track");
+    text_element(w, "name", label)?;
+    text_element(w, "styleUrl", &format!("#track-{}", track.device_id))?;
+    w.write(XmlEvent::start_element("gx:Track"))?;
+    // Altitudes are only used if all points have one
+    let absolute = !track.points.is_empty() && track.points.iter().all(|pt| pt.elevation.is_some());
+    let altitude_mode = if absolute {
+        "absolute"
+    } else {
+        "clampToGround"
+    };
+    text_element(w, "altitudeMode", altitude_mode)?;
+    for pt in &track.points {
+        text_element(w, "when", &format_timestamp(pt.ts, track.tz))?;
+    }
+    for pt in &track.points {
+        let coord = match pt.elevation {
+            Some(elevation) if absolute => format!("{:.7} {:.7} {elevation}", pt.x, pt.y),
+            _ => format!("{:.7} {:.7} 0", pt.x, pt.y),
+        };
+        text_element(w, "gx:coord", &coord)?;
+    }
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+/// Placemarks of detected stays
+fn write_stops(w: &mut Writer, track: &TrackData) -> anyhow::Result<()> {
+    let filter = Filter::from(&FilterParams::default());
+    for stay in trips::segment(&track.points, &filter, true).stays {
+        let minutes = (stay.departure - stay.arrival) / 60;
+        w.write(XmlEvent::start_element("Placemark"))?;
+        text_element(w, "name", stay.name.as_deref().unwrap_or("Stop"))?;
+        text_element(w, "description", &format!("{minutes} min"))?;
+        w.write(XmlEvent::start_element("TimeSpan"))?;
+        text_element(w, "begin", &format_timestamp(stay.arrival, track.tz))?;
+        text_element(w, "end", &format_timestamp(stay.departure, track.tz))?;
+        w.write(XmlEvent::end_element())?;
+        text_element(w, "styleUrl", "#stop")?;
+        write_point(w, stay.lon, stay.lat)?;
+        w.write(XmlEvent::end_element())?;
+    }
+    Ok(())
+}
+
+/// Placemarks of POIs, visits and region events
+fn write_events(w: &mut Writer, track: &TrackData) -> anyhow::Result<()> {
+    for pt in &track.points {
+        let annotations: Map<String, Value> =
+            serde_json::from_str(&pt.annotations).unwrap_or_default();
+        let Some((name, type_)) = waypoint_name(&annotations) else {
+            continue;
+        };
+        w.write(XmlEvent::start_element("Placemark"))?;
+        text_element(w, "name", &name)?;
+        text_element(w, "description", type_)?;
+        w.write(XmlEvent::start_element("TimeStamp"))?;
+        text_element(w, "when", &format_timestamp(pt.ts, track.tz))?;
+        w.write(XmlEvent::end_element())?;
+        text_element(w, "styleUrl", "#event")?;
+        write_point(w, pt.x, pt.y)?;
+        w.write(XmlEvent::end_element())?;
+    }
+    Ok(())
+}
+
+fn write_point(w: &mut Writer, x: f64, y: f64) -> anyhow::Result<()> {
+    w.write(XmlEvent::start_element("Point"))?;
+    text_element(w, "coordinates", &format!("{x:.7},{y:.7}"))?;
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_track_style(w: &mut Writer, id: &str, color: &str) -> anyhow::Result<()> {
+    w.write(XmlEvent::start_element("Style").attr("id", id))?;
+    w.write(XmlEvent::start_element("IconStyle"))?;
+    text_element(w, "color", color)?;
+    w.write(XmlEvent::start_element("Icon"))?;
+    text_element(w, "href", TRACK_ICON)?;
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::start_element("LineStyle"))?;
+    text_element(w, "color", color)?;
+    text_element(w, "width", "4")?;
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_icon_style(w: &mut Writer, id: &str, icon: &str) -> anyhow::Result<()> {
+    w.write(XmlEvent::start_element("Style").attr("id", id))?;
+    w.write(XmlEvent::start_element("IconStyle"))?;
+    w.write(XmlEvent::start_element("Icon"))?;
+    text_element(w, "href", icon)?;
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+/// Stable color of a tracker ID
+fn track_color(tid: &str) -> &'static str {
+    let hash = tid.bytes().fold(0usize, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as usize)
+    });
+    TRACK_COLORS[hash % TRACK_COLORS.len()]
+}
@@ -1,12 +1,36 @@
+mod activity;
+mod coverage;
+mod csv;
 pub mod db;
+mod dem;
+mod events;
+mod export;
+mod filter;
+mod fit;
+mod flatgeobuf;
+mod geofences;
 mod geojson;
+mod geoparquet;
 mod gpx;
 mod http;
+mod import;
+mod kml;
 mod meshtastic;
 mod mqtt;
+mod mvt;
 mod owntracks;
+mod places;
+mod points;
 mod position;
+mod profile;
+mod recorder_api;
+mod simplify;
+mod smooth;
 mod stats;
+mod tcx;
+mod timestamp;
+mod trips;
+mod xml_writer;
 
 use db::Db;
 use env_logger::Env;
@@ -21,10 +45,22 @@ async fn main() -> anyhow::Result<()> {
 
     let db = Db::connect().await?;
     db.run_migrations().await?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("import") => return import::cli(&db, &args[1..]).await,
+        Some("import-recorder") => return import::recorder_cli(&db, &args[1..]).await,
+        Some(cmd) => {
+            anyhow::bail!("Unknown command `{cmd}`. Available commands: import, import-recorder")
+        }
+    }
+
     let mqtt_db = db.clone();
     let _handler = tokio::spawn(async move {
         mqtt::subscribe(&mqtt_db).await.unwrap();
     });
+    tokio::spawn(trips::run_segmentation(db.clone()));
     http::webserver(db).await?;
     Ok(())
 }
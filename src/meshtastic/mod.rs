@@ -1,5 +1,6 @@
 //! Meshtastic integration via MQTT: <https://meshtastic.org/docs/software/integrations/mqtt/>
 
+#[allow(dead_code)]
 pub(crate) mod protobufs;
 
 use crate::db::Db;
@@ -1,4 +1,5 @@
 use crate::db::Db;
+use crate::events;
 use crate::meshtastic;
 use crate::owntracks;
 use gethostname::gethostname;
@@ -6,6 +7,7 @@ use prost::Message;
 use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
 use std::process;
 use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::time;
 
 pub async fn subscribe(db: &Db) -> anyhow::Result<()> {
@@ -27,6 +29,7 @@ pub async fn subscribe(db: &Db) -> anyhow::Result<()> {
 
     let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
     client.subscribe("owntracks/#", QoS::AtMostOnce).await?;
+    tokio::spawn(publish_events(client.clone(), db.subscribe_events()));
 
     loop {
         let notification = eventloop.poll().await;
@@ -80,3 +83,37 @@ pub fn get_user_device_from_topic(topic: &str) -> Option<(String, String)> {
     let device = parts[parts.len() - 1].to_string();
     Some((user, device))
 }
+
+/// Publish geofence transitions as OwnTracks transition messages
+/// to `owntracks/{user}/{device}/event`
+async fn publish_events(client: AsyncClient, mut events: broadcast::Receiver<events::Event>) {
+    loop {
+        match events.recv().await {
+            Ok(events::Event::Transition(transition)) => {
+                let topic = format!(
+                    "owntracks/{}/{}/event",
+                    transition.user_id, transition.device
+                );
+                let payload = serde_json::json!({
+                    "_type": "transition",
+                    "tid": transition.tid,
+                    "tst": transition.ts,
+                    "lat": transition.lat,
+                    "lon": transition.lon,
+                    "acc": transition.accuracy,
+                    "event": transition.event,
+                    "desc": transition.geofence,
+                    "rid": transition.geofence_id.to_string(),
+                });
+                if let Err(e) = client
+                    .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+                    .await
+                {
+                    log::error!("{e}");
+                }
+            }
+            Err(RecvError::Lagged(count)) => log::warn!("Skipped {count} events"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
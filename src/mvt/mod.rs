@@ -0,0 +1,384 @@
+//! Mapbox Vector Tiles (MVT) of positions: <https://github.com/mapbox/vector-tile-spec>
+
+pub(crate) mod vector_tile;
+
+use crate::db::Db;
+use crate::timestamp::TimeRange;
+use futures_util::TryStreamExt;
+use prost::Message;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::f64::consts::PI;
+use std::ops::RangeInclusive;
+use vector_tile::{tile, Tile};
+
+/// Size of a tile in tile coordinates
+const EXTENT: u32 = 4096;
+/// Positions outside of the tile included for rendering heatmaps without edge artifacts
+const BUFFER: i32 = 64;
+/// Size of density grid cells in tile coordinates
+const CELL_SIZE: i32 = 16;
+/// Highest zoom level with density grid instead of single positions
+const MAX_DENSITY_ZOOM: u8 = 11;
+/// Maximal number of positions in a tile. Tiles with more positions contain a density grid.
+const MAX_FEATURES: usize = 20_000;
+/// Maximal number of tile rows counted in one database query
+const MAX_QUERY_ROWS: usize = 1000;
+pub const MAX_ZOOM: u8 = 22;
+/// Latitude limit of Web Mercator
+const MAX_LAT: f64 = 85.051_128_78;
+
+/// Tile in the XYZ (Web Mercator) tiling scheme
+#[derive(Clone, Copy, Debug)]
+pub struct TileId {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileId {
+    pub fn new(z: u8, x: u32, y: u32) -> anyhow::Result<Self> {
+        if z > MAX_ZOOM {
+            anyhow::bail!("Zoom level above {MAX_ZOOM}");
+        }
+        if x >= 1 << z || y >= 1 << z {
+            anyhow::bail!("Invalid tile {z}/{x}/{y}");
+        }
+        Ok(TileId { z, x, y })
+    }
+
+    /// Tile containing a position
+    pub fn at(z: u8, lon: f64, lat: f64) -> Self {
+        let max = (1u32 << z) - 1;
+        let (x, y) = mercator(z, lon, lat);
+        TileId {
+            z,
+            x: (x.floor().max(0.0) as u32).min(max),
+            y: (y.floor().max(0.0) as u32).min(max),
+        }
+    }
+
+    /// Bounding box `[min_lon, min_lat, max_lon, max_lat]`
+    pub fn bounds(&self) -> [f64; 4] {
+        self.bounds_with_buffer(0.0)
+    }
+
+    /// Bing Maps quadkey
+    pub fn quadkey(&self) -> String {
+        (1..=self.z)
+            .rev()
+            .map(|i| {
+                let mask = 1 << (i - 1);
+                let digit = (self.x & mask != 0) as u8 + 2 * (self.y & mask != 0) as u8;
+                char::from(b'0' + digit)
+            })
+            .collect()
+    }
+
+    /// Bounding box including the tile buffer
+    fn bbox(&self) -> [f64; 4] {
+        self.bounds_with_buffer(BUFFER as f64 / EXTENT as f64)
+    }
+
+    /// Bounding box extended by a fraction of the tile size
+    fn bounds_with_buffer(&self, buffer: f64) -> [f64; 4] {
+        let lon = |x: f64| longitude(self.z, x).clamp(-180.0, 180.0);
+        let lat = |y: f64| latitude(self.z, y).clamp(-MAX_LAT, MAX_LAT);
+        let (x, y) = (self.x as f64, self.y as f64);
+        [
+            lon(x - buffer),
+            lat(y + 1.0 + buffer),
+            lon(x + 1.0 + buffer),
+            lat(y - buffer),
+        ]
+    }
+
+    /// Tile coordinates of a position
+    fn project(&self, lon: f64, lat: f64) -> (i32, i32) {
+        let (x, y) = mercator(self.z, lon, lat);
+        let extent = EXTENT as f64;
+        (
+            ((x - self.x as f64) * extent).floor() as i32,
+            ((y - self.y as f64) * extent).floor() as i32,
+        )
+    }
+}
+
+/// Longitude of a fractional tile number
+fn longitude(z: u8, x: f64) -> f64 {
+    x / (1u32 << z) as f64 * 360.0 - 180.0
+}
+
+/// Latitude of a fractional tile number
+fn latitude(z: u8, y: f64) -> f64 {
+    let n = (1u32 << z) as f64;
+    (PI * (1.0 - 2.0 * y / n)).sinh().atan().to_degrees()
+}
+
+/// Fractional tile numbers of a position at a zoom level
+fn mercator(z: u8, lon: f64, lat: f64) -> (f64, f64) {
+    let n = (1u32 << z) as f64;
+    let lat = lat.clamp(-MAX_LAT, MAX_LAT).to_radians();
+    let x = (lon + 180.0) / 360.0 * n;
+    let y = (1.0 - lat.tan().asinh() / PI) / 2.0 * n;
+    (x, y)
+}
+
+/// Build a vector tile of the positions of devices within their time ranges.
+///
+/// Up to zoom level 11, the tile has a `density` layer with a point per grid cell
+/// and the number of positions as `count`.
+/// At higher zoom levels, the `positions` layer contains a point per position
+/// with `device_id` and `ts` (UNIX timestamp), unless the tile contains more than
+/// 20,000 positions, which are returned as `density` layer.
+pub async fn tile(db: &Db, tile: TileId, ranges: &[(i32, TimeRange)]) -> anyhow::Result<Vec<u8>> {
+    let layer = if tile.z <= MAX_DENSITY_ZOOM {
+        density(db, tile, ranges).await?
+    } else {
+        match positions(db, tile, ranges).await? {
+            Some(layer) => layer,
+            None => density(db, tile, ranges).await?,
+        }
+    };
+    let mut mvt = Tile::default();
+    if !layer.layer.features.is_empty() {
+        mvt.layers.push(layer.finish());
+    }
+    Ok(mvt.encode_to_vec())
+}
+
+/// Layer with a point per position, `None` with more than `MAX_FEATURES` positions
+async fn positions(
+    db: &Db,
+    tile: TileId,
+    ranges: &[(i32, TimeRange)],
+) -> anyhow::Result<Option<LayerBuilder>> {
+    let mut layer = LayerBuilder::new("positions", &["device_id", "ts"]);
+    for (device_id, range) in ranges {
+        let mut positions = db.stream_positions_in_bbox(*device_id, *range, tile.bbox());
+        while let Some((lon, lat, ts)) = positions.try_next().await? {
+            if layer.layer.features.len() >= MAX_FEATURES {
+                return Ok(None);
+            }
+            layer.add_point(tile.project(lon, lat), &[*device_id as i64, ts]);
+        }
+    }
+    Ok(Some(layer))
+}
+
+/// Layer with a point per grid cell and the number of positions.
+/// Grid cells are tiles at a zoom level 8 levels below the tile.
+async fn density(
+    db: &Db,
+    tile: TileId,
+    ranges: &[(i32, TimeRange)],
+) -> anyhow::Result<LayerBuilder> {
+    let cells_per_tile = EXTENT as i64 / CELL_SIZE as i64;
+    let z = tile.z + cells_per_tile.ilog2() as u8;
+    let n = cells_per_tile << tile.z;
+    let buffer = (BUFFER / CELL_SIZE) as i64;
+    let (x, y) = (
+        tile.x as i64 * cells_per_tile,
+        tile.y as i64 * cells_per_tile,
+    );
+    let cols = x - buffer..=x + cells_per_tile + buffer - 1;
+    let rows = (y - buffer).max(0)..=(y + cells_per_tile + buffer - 1).min(n - 1);
+    let rows = rows.collect();
+
+    let mut cells: BTreeMap<(i32, i32), i64> = BTreeMap::new();
+    for (device_id, range) in ranges {
+        let counts = count_positions(db, *device_id, *range, z, cols.clone(), &rows).await?;
+        for ((col, row), count) in counts {
+            *cells
+                .entry(((col - x) as i32, (row - y) as i32))
+                .or_default() += count;
+        }
+    }
+    let mut layer = LayerBuilder::new("density", &["count"]);
+    for ((cx, cy), count) in cells {
+        let center = (
+            cx * CELL_SIZE + CELL_SIZE / 2,
+            cy * CELL_SIZE + CELL_SIZE / 2,
+        );
+        layer.add_point(center, &[count]);
+    }
+    Ok(layer)
+}
+
+/// Number of positions of a device per tile `(x, y)` at a zoom level within columns and rows
+/// of tiles, counted in the database
+pub async fn count_positions(
+    db: &Db,
+    device_id: i32,
+    range: TimeRange,
+    z: u8,
+    cols: RangeInclusive<i64>,
+    rows: &BTreeSet<i64>,
+) -> anyhow::Result<BTreeMap<(i64, i64), i64>> {
+    let width = 360.0 / (1u64 << z) as f64;
+    let rows: Vec<i64> = rows.iter().copied().collect();
+    let mut counts = BTreeMap::new();
+    // Row boundaries are passed as query parameters, whose number is limited
+    for rows in rows.chunks(MAX_QUERY_ROWS) {
+        let (first, last) = (rows[0], rows[rows.len() - 1]);
+        let bbox = [
+            longitude(z, *cols.start() as f64),
+            latitude(z, (last + 1) as f64),
+            longitude(z, (cols.end() + 1) as f64),
+            latitude(z, first as f64),
+        ];
+        let bins = LatBins::new(z, rows);
+        let boundaries = bins
+            .rows
+            .iter()
+            .filter_map(|(bin, (_, _, boundary))| Some((*bin, (*boundary)?)))
+            .collect();
+        for (col, bin, north, count) in db
+            .query_position_grid(
+                device_id,
+                range,
+                bbox,
+                (-180.0, bins.origin),
+                (width, bins.height),
+                &boundaries,
+            )
+            .await?
+        {
+            // Positions of other rows in the bounding box
+            let Some((south_row, north_row, _)) = bins.rows.get(&bin) else {
+                continue;
+            };
+            let row = if north == 1 { north_row } else { south_row };
+            *counts.entry((col, *row)).or_default() += count;
+        }
+    }
+    Ok(counts)
+}
+
+/// Latitude bins of equal height for counting positions per row of tiles in the database.
+/// Bins are half as high as the lowest row, so that they contain at most one row boundary.
+struct LatBins {
+    /// Latitude of the southern edge of bin 0
+    origin: f64,
+    height: f64,
+    /// Rows south and north of the boundary latitude within a bin
+    rows: BTreeMap<i64, (i64, i64, Option<f64>)>,
+}
+
+impl LatBins {
+    /// Bins covering rows of tiles at a zoom level
+    fn new(z: u8, rows: &[i64]) -> Self {
+        let south = |row: i64| latitude(z, (row + 1) as f64);
+        let north = |row: i64| latitude(z, row as f64);
+        let origin = rows.last().map(|row| south(*row)).unwrap_or_default();
+        let height = rows
+            .iter()
+            .map(|row| north(*row) - south(*row))
+            .fold(f64::INFINITY, f64::min)
+            / 2.0;
+        let row_at = |lat: f64| mercator(z, 0.0, lat).1.floor() as i64;
+        let mut bins = BTreeMap::new();
+        for row in rows {
+            let first = ((south(*row) - origin) / height).floor() as i64;
+            let last = ((north(*row) - origin) / height).floor() as i64;
+            for bin in first..=last {
+                let lat = origin + bin as f64 * height;
+                let (south_row, north_row) = (row_at(lat), row_at(lat + height));
+                let boundary = (north_row < south_row).then(|| north(south_row));
+                bins.insert(bin, (south_row, north_row, boundary));
+            }
+        }
+        LatBins {
+            origin,
+            height,
+            rows: bins,
+        }
+    }
+}
+
+/// Layer with point features and integer properties
+struct LayerBuilder {
+    layer: tile::Layer,
+    /// Indices of values
+    values: HashMap<i64, u32>,
+}
+
+impl LayerBuilder {
+    fn new(name: &str, keys: &[&str]) -> Self {
+        LayerBuilder {
+            layer: tile::Layer {
+                version: 2,
+                name: name.to_string(),
+                keys: keys.iter().map(|key| key.to_string()).collect(),
+                extent: Some(EXTENT),
+                ..Default::default()
+            },
+            values: HashMap::new(),
+        }
+    }
+
+    /// Add a point with a value for each key
+    fn add_point(&mut self, (x, y): (i32, i32), values: &[i64]) {
+        let mut tags = Vec::with_capacity(2 * values.len());
+        for (key, value) in values.iter().enumerate() {
+            let next = self.values.len() as u32;
+            tags.push(key as u32);
+            tags.push(*self.values.entry(*value).or_insert(next));
+        }
+        self.layer.features.push(tile::Feature {
+            tags,
+            r#type: Some(tile::GeomType::Point.into()),
+            // MoveTo command with a single point
+            geometry: vec![command(1, 1), zigzag(x), zigzag(y)],
+            ..Default::default()
+        });
+    }
+
+    fn finish(mut self) -> tile::Layer {
+        let mut values: Vec<_> = self.values.into_iter().collect();
+        values.sort_by_key(|(_, index)| *index);
+        self.layer.values = values
+            .into_iter()
+            .map(|(value, _)| tile::Value {
+                int_value: Some(value),
+                ..Default::default()
+            })
+            .collect();
+        self.layer
+    }
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lat_bins() {
+        for (z, rows) in [
+            (8, vec![0, 1, 2, 255]),
+            (19, vec![183_000, 183_001, 183_010]),
+        ] {
+            let bins = LatBins::new(z, &rows);
+            for row in &rows {
+                for step in 1..100 {
+                    let lat = latitude(z, *row as f64 + step as f64 / 100.0);
+                    let bin = ((lat - bins.origin) / bins.height).floor() as i64;
+                    let (south_row, north_row, boundary) = bins.rows[&bin];
+                    let binned = if boundary.is_some_and(|boundary| lat > boundary) {
+                        north_row
+                    } else {
+                        south_row
+                    };
+                    assert_eq!(binned, mercator(z, 0.0, lat).1.floor() as i64);
+                }
+            }
+        }
+    }
+}
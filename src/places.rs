@@ -0,0 +1,107 @@
+//! Visited places detected from stays
+
+use crate::db::{Db, Place, PlaceStats, VisitInfo};
+use crate::stats;
+use crate::timestamp::{format_timestamp, Period};
+use crate::trips::{pending_segmentation, Stay};
+use std::collections::HashMap;
+
+/// Maximal distance of a stay from a known place in meters
+const PLACE_RADIUS: f64 = 150.0;
+
+/// Nearest place within radius
+fn nearest_place(places: &[Place], lat: f64, lon: f64) -> Option<&Place> {
+    places
+        .iter()
+        .map(|place| (stats::distance((place.lon, place.lat), (lon, lat)), place))
+        .filter(|(distance, _)| *distance <= PLACE_RADIUS)
+        .min_by(|(d1, _), (d2, _)| d1.total_cmp(d2))
+        .map(|(_, place)| place)
+}
+
+/// Return the place of a stay, creating a new place if there is no known place nearby
+pub async fn assign_place(
+    db: &Db,
+    user: &str,
+    places: &mut Vec<Place>,
+    stay: &Stay,
+) -> anyhow::Result<i32> {
+    if let Some(place) = nearest_place(places, stay.lat, stay.lon) {
+        return Ok(place.id);
+    }
+    let place = db
+        .insert_place(user, stay.name.as_deref(), stay.lat, stay.lon)
+        .await?;
+    let id = place.id;
+    places.push(place);
+    Ok(id)
+}
+
+/// Stored visits and visits of not yet segmented positions within a period
+pub async fn query_visits(db: &Db, period: Period) -> anyhow::Result<Vec<VisitInfo>> {
+    let mut visits = Vec::new();
+    for device in db.query_last_positions(None, None).await? {
+        let range = period.time_range(device.tz);
+        visits.extend(db.query_visits(&device, range).await?);
+        // Pending visits, including the current stay
+        let places = db.query_places(&device.user_id).await?;
+        for stay in pending_segmentation(db, device.device_id, range)
+            .await?
+            .stays
+        {
+            if stay.departure >= range.from && stay.arrival < range.to {
+                let place = nearest_place(&places, stay.lat, stay.lon);
+                visits.push(VisitInfo {
+                    id: None,
+                    device_id: device.device_id,
+                    user_id: device.user_id.clone(),
+                    device: device.device.clone(),
+                    place_id: place.map(|place| place.id),
+                    place: place.and_then(|place| place.name.clone()).or(stay.name),
+                    lat: stay.lat,
+                    lon: stay.lon,
+                    arrival: format_timestamp(stay.arrival, device.tz),
+                    departure: format_timestamp(stay.departure, device.tz),
+                    arrival_ts: stay.arrival,
+                    departure_ts: stay.departure,
+                    duration: stay.departure - stay.arrival,
+                    open: stay.open,
+                });
+            }
+        }
+    }
+    visits.sort_by_key(|visit| visit.arrival_ts);
+    Ok(visits)
+}
+
+/// Places with the longest visits within a period, per user
+pub async fn top_places(
+    db: &Db,
+    period: Period,
+    user: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<Vec<PlaceStats>> {
+    // Sum up visits of all devices of a user
+    let mut places: HashMap<i32, PlaceStats> = HashMap::new();
+    for device in db.query_last_positions(user, None).await? {
+        let range = period.time_range(device.tz);
+        for stats in db.query_place_stats(device.device_id, range).await? {
+            places
+                .entry(stats.place_id)
+                .and_modify(|place| {
+                    place.visits += stats.visits;
+                    place.duration += stats.duration;
+                })
+                .or_insert(stats);
+        }
+    }
+    let mut places: Vec<PlaceStats> = places.into_values().collect();
+    places.sort_by(|a, b| a.user_id.cmp(&b.user_id).then(b.duration.cmp(&a.duration)));
+    let mut count: HashMap<String, usize> = HashMap::new();
+    places.retain(|place| {
+        let count = count.entry(place.user_id.clone()).or_default();
+        *count += 1;
+        *count <= limit
+    });
+    Ok(places)
+}
@@ -0,0 +1,206 @@
+//! Point exports with a row per stored position (GeoParquet, FlatGeobuf)
+
+use crate::db::{Db, PositionRow};
+use crate::timestamp::{Period, TimeRange};
+use futures_util::stream::{self, Stream};
+use futures_util::TryStreamExt;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Number of positions queried at once
+const BATCH_SIZE: usize = 10_000;
+
+/// Names of the position columns, which are not used for annotation columns
+const POSITION_COLUMNS: &[&str] = &[
+    "id",
+    "device_id",
+    "user_id",
+    "device",
+    "tid",
+    "ts",
+    "lat",
+    "lon",
+    "velocity",
+    "alt",
+    "accuracy",
+    "v_accuracy",
+    "cog",
+    "annotations",
+    "geometry",
+];
+
+/// Value type of an annotation column
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueType {
+    Bool,
+    Int,
+    Float,
+    /// Strings and JSON of objects or arrays
+    String,
+}
+
+impl ValueType {
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => None,
+            Value::Bool(_) => Some(ValueType::Bool),
+            Value::Number(number) if number.is_i64() => Some(ValueType::Int),
+            Value::Number(_) => Some(ValueType::Float),
+            _ => Some(ValueType::String),
+        }
+    }
+    /// Common type of mixed values
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ValueType::Int, ValueType::Float) | (ValueType::Float, ValueType::Int) => {
+                ValueType::Float
+            }
+            _ => ValueType::String,
+        }
+    }
+}
+
+/// Typed column of an annotation key
+#[derive(Clone, Debug)]
+pub struct AnnotationColumn {
+    pub name: String,
+    pub key: String,
+    pub value_type: ValueType,
+}
+
+impl AnnotationColumn {
+    pub fn bool(&self, annotations: &Map<String, Value>) -> Option<bool> {
+        annotations.get(&self.key).and_then(Value::as_bool)
+    }
+    pub fn int(&self, annotations: &Map<String, Value>) -> Option<i64> {
+        annotations.get(&self.key).and_then(Value::as_i64)
+    }
+    pub fn float(&self, annotations: &Map<String, Value>) -> Option<f64> {
+        annotations.get(&self.key).and_then(Value::as_f64)
+    }
+    pub fn string(&self, annotations: &Map<String, Value>) -> Option<String> {
+        match annotations.get(&self.key) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(value)) => Some(value.clone()),
+            Some(value) => Some(value.to_string()),
+        }
+    }
+}
+
+/// Time ranges of the exported devices and annotation columns found in their positions
+pub struct PointExport {
+    pub ranges: Vec<(i32, TimeRange)>,
+    pub annotations: Vec<AnnotationColumn>,
+}
+
+impl PointExport {
+    /// Collect annotation keys and their types of all or selected devices within a period
+    pub async fn query(
+        db: &Db,
+        device_ids: Option<&[i32]>,
+        period: Period,
+    ) -> anyhow::Result<Self> {
+        let ranges = device_ranges(db, device_ids, period).await?;
+        let mut types: BTreeMap<String, ValueType> = BTreeMap::new();
+        let mut batches = Box::pin(position_batches(db.clone(), ranges.clone()));
+        while let Some(rows) = batches.try_next().await? {
+            for row in rows {
+                for (key, value) in parse_annotations(&row) {
+                    if let Some(value_type) = ValueType::of(&value) {
+                        types
+                            .entry(key)
+                            .and_modify(|t| *t = t.merge(value_type))
+                            .or_insert(value_type);
+                    }
+                }
+            }
+        }
+        let annotations = types
+            .into_iter()
+            .map(|(key, value_type)| AnnotationColumn {
+                name: if POSITION_COLUMNS.contains(&key.as_str()) {
+                    format!("annotation_{key}")
+                } else {
+                    key.clone()
+                },
+                key,
+                value_type,
+            })
+            .collect();
+        Ok(PointExport {
+            ranges,
+            annotations,
+        })
+    }
+}
+
+/// Time ranges of all or selected devices. Day boundaries depend on the time zone of each device.
+pub async fn device_ranges(
+    db: &Db,
+    device_ids: Option<&[i32]>,
+    period: Period,
+) -> anyhow::Result<Vec<(i32, TimeRange)>> {
+    Ok(db
+        .query_last_positions(None, None)
+        .await?
+        .into_iter()
+        .filter(|device| device_ids.is_none_or(|ids| ids.contains(&device.device_id)))
+        .map(|device| (device.device_id, period.time_range(device.tz)))
+        .collect())
+}
+
+/// Annotations of a position as JSON object
+pub fn parse_annotations(row: &PositionRow) -> Map<String, Value> {
+    serde_json::from_str(&row.annotations).unwrap_or_default()
+}
+
+/// Stream positions in batches, device after device
+pub fn position_batches(
+    db: Db,
+    ranges: Vec<(i32, TimeRange)>,
+) -> impl Stream<Item = anyhow::Result<Vec<PositionRow>>> {
+    stream::try_unfold((0, None), move |(mut idx, mut after)| {
+        let (db, ranges) = (db.clone(), ranges.clone());
+        async move {
+            while let Some(&(device_id, range)) = ranges.get(idx) {
+                let rows = db
+                    .query_position_rows(
+                        device_id,
+                        range,
+                        after.unwrap_or((range.from, 0)),
+                        BATCH_SIZE,
+                    )
+                    .await?;
+                let next = if rows.len() < BATCH_SIZE {
+                    (idx + 1, None)
+                } else {
+                    (idx, rows.last().map(|row| (row.ts, row.id)))
+                };
+                if !rows.is_empty() {
+                    return Ok(Some((rows, next)));
+                }
+                (idx, after) = next;
+            }
+            Ok(None)
+        }
+    })
+}
+
+/// Bounding box of exported positions
+#[derive(Default)]
+pub struct Bbox(Option<[f64; 4]>);
+
+impl Bbox {
+    pub fn extend(&mut self, x: f64, y: f64) {
+        let bbox = self.0.get_or_insert([x, y, x, y]);
+        bbox[0] = bbox[0].min(x);
+        bbox[1] = bbox[1].min(y);
+        bbox[2] = bbox[2].max(x);
+        bbox[3] = bbox[3].max(y);
+    }
+    /// `[minx, miny, maxx, maxy]`
+    pub fn bounds(&self) -> Option<[f64; 4]> {
+        self.0
+    }
+}
@@ -35,3 +35,21 @@ pub struct Position {
     )]
     pub annotations: String,
 }
+
+impl Position {
+    /// Position with coordinates only
+    pub fn new(tid: &str, ts: i64, lat: f64, lon: f64) -> Self {
+        Position {
+            tid: tid.to_string(),
+            ts,
+            velocity: None,
+            lat: lat as f32,
+            lon: lon as f32,
+            alt: None,
+            accuracy: None,
+            v_accuracy: None,
+            cog: None,
+            annotations: "{}".to_string(),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Columnar track profile for charts
+
+use crate::db::{GpsPoint, TrackData};
+use crate::stats;
+use crate::timestamp::format_timestamp;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Annotation keys of battery levels
+pub const BATTERY_KEYS: &[&str] = &["batt", "battery"];
+/// Annotation keys of heart rates
+pub const HEART_RATE_KEYS: &[&str] = &["hr", "heart_rate", "heartrate"];
+/// Annotation keys of cadences
+pub const CADENCE_KEYS: &[&str] = &["cad", "cadence"];
+
+#[derive(Serialize, Default)]
+pub struct Profile {
+    /// Time of the first point (RFC 3339)
+    ts_start: Option<String>,
+    /// Cumulative distance in meters
+    distance: Vec<f64>,
+    /// Elapsed time in seconds
+    time: Vec<i64>,
+    /// Elevation in meters
+    elevation: Vec<Option<f64>>,
+    /// Speed in km/h
+    speed: Vec<Option<f64>>,
+    /// Battery level in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    battery: Option<Vec<Option<f64>>>,
+    /// Heart rate in beats per minute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heart_rate: Option<Vec<Option<f64>>>,
+}
+
+/// Values of a track point
+struct Sample {
+    distance: f64,
+    time: i64,
+    elevation: Option<f64>,
+    speed: Option<f64>,
+    battery: Option<f64>,
+    heart_rate: Option<f64>,
+}
+
+/// Profile with `samples` values per column, or a value per point for shorter tracks.
+/// Consecutive points are combined, with averaged elevation, speed, battery and heart rate.
+pub fn profile(track: &TrackData, samples: usize) -> Profile {
+    let Some(first) = track.points.first() else {
+        return Profile::default();
+    };
+    let points: Vec<Sample> = with_distance(&track.points)
+        .map(|(pt, distance, annotations)| Sample {
+            distance,
+            time: pt.ts - first.ts,
+            elevation: pt.elevation.map(f64::from),
+            speed: pt.speed.map(f64::from),
+            battery: annotation(&annotations, BATTERY_KEYS),
+            heart_rate: annotation(&annotations, HEART_RATE_KEYS),
+        })
+        .collect();
+
+    // Evenly sized buckets, differing by at most one point
+    let count = points.len().min(samples.max(1));
+    let buckets =
+        (0..count).map(|i| &points[i * points.len() / count..(i + 1) * points.len() / count]);
+    let mut profile = Profile {
+        ts_start: Some(format_timestamp(first.ts, track.tz)),
+        ..Default::default()
+    };
+    let mut battery = Vec::new();
+    let mut heart_rate = Vec::new();
+    for bucket in buckets {
+        let last = &bucket[bucket.len() - 1];
+        profile.distance.push(last.distance);
+        profile.time.push(last.time);
+        profile.elevation.push(mean(bucket, |s| s.elevation));
+        profile.speed.push(mean(bucket, |s| s.speed));
+        battery.push(mean(bucket, |s| s.battery));
+        heart_rate.push(mean(bucket, |s| s.heart_rate));
+    }
+    profile.battery = battery.iter().any(Option::is_some).then_some(battery);
+    profile.heart_rate = heart_rate.iter().any(Option::is_some).then_some(heart_rate);
+    profile
+}
+
+/// Points with cumulative distance in meters and parsed annotations
+pub fn with_distance(
+    points: &[GpsPoint],
+) -> impl Iterator<Item = (&GpsPoint, f64, Map<String, Value>)> {
+    let mut distance = 0.0;
+    points.iter().enumerate().map(move |(i, pt)| {
+        if i > 0 {
+            let prev = &points[i - 1];
+            distance += stats::distance((prev.x, prev.y), (pt.x, pt.y));
+        }
+        let annotations = serde_json::from_str(&pt.annotations).unwrap_or_default();
+        (pt, distance, annotations)
+    })
+}
+
+/// First numeric annotation value of the given keys
+pub fn annotation(annotations: &Map<String, Value>, keys: &[&str]) -> Option<f64> {
+    keys.iter()
+        .find_map(|key| annotations.get(*key).and_then(Value::as_f64))
+}
+
+/// Mean of available values
+fn mean(bucket: &[Sample], value: impl Fn(&Sample) -> Option<f64>) -> Option<f64> {
+    let values: Vec<f64> = bucket.iter().filter_map(value).collect();
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Tz;
+
+    const START: i64 = 1_700_000_000;
+
+    fn track(count: i64) -> TrackData {
+        TrackData {
+            device_id: 1,
+            date: String::new(),
+            tz: Tz::UTC,
+            points: (0..count)
+                .map(|i| {
+                    let mut pt = GpsPoint::new(8.0 + i as f64 * 1e-4, 47.0, START + i);
+                    pt.elevation = Some(i as i16);
+                    pt
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn resampling() {
+        for (points, samples, expected) in [
+            (1000, 500, 500),
+            (1000, 333, 333),
+            (1000, 999, 999),
+            (100, 500, 100),
+            (1, 500, 1),
+            (10, 0, 1),
+        ] {
+            let profile = profile(&track(points), samples);
+            assert_eq!(profile.distance.len(), expected);
+            assert_eq!(profile.time.len(), expected);
+            assert_eq!(profile.elevation.len(), expected);
+            assert_eq!(profile.speed.len(), expected);
+            // The last value is at the end of the track
+            assert_eq!(profile.time[expected - 1], points - 1);
+        }
+        assert!(profile(&track(0), 500).distance.is_empty());
+    }
+
+    #[test]
+    fn bucket_values() {
+        let profile = profile(&track(1000), 500);
+        // Pairs of points with averaged elevations
+        assert_eq!(profile.time[..3], [1, 3, 5]);
+        assert_eq!(profile.elevation[..3], [Some(0.5), Some(2.5), Some(4.5)]);
+        // 0.0001° of longitude at 47° is about 7.6 m
+        assert!((profile.distance[499] - 999.0 * 7.6).abs() < 10.0);
+        assert!(profile.battery.is_none());
+        assert!(profile.heart_rate.is_none());
+    }
+}
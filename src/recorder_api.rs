@@ -0,0 +1,263 @@
+//! [OwnTracks Recorder](https://github.com/owntracks/recorder#api) compatible HTTP API
+
+use crate::csv;
+use crate::db::{Db, DevicePosition, GpsPoint};
+use crate::filter::FilterParams;
+use crate::geojson;
+use crate::gpx;
+use crate::timestamp::{local_datetime, parse_timestamp, Period, TimeRange};
+use actix_web::{error, get, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+/// Default time range of location queries in seconds
+const DEFAULT_RANGE: i64 = 6 * 3600;
+
+#[derive(Deserialize)]
+struct ListParams {
+    user: Option<String>,
+    device: Option<String>,
+}
+
+/// List users, devices of a user or monthly files of a device
+#[get("/list")]
+async fn list(
+    db: web::Data<Db>,
+    params: web::Query<ListParams>,
+) -> actix_web::Result<impl Responder> {
+    let results = match (&params.user, &params.device) {
+        (None, _) => db.query_users().await,
+        (Some(user), None) => db.query_devices(user).await,
+        (Some(user), Some(device)) => match db.query_device_id(user, device).await {
+            Ok(Some(device_id)) => db.query_device_months(device_id).await.map(|months| {
+                months
+                    .into_iter()
+                    .map(|month| format!("{month}.rec"))
+                    .collect()
+            }),
+            Ok(None) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        },
+    };
+    match results {
+        Ok(results) => Ok(web::Json(json!({ "results": results }))),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError("Failed to fetch list"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LocationsParams {
+    user: String,
+    device: String,
+    /// Start time. Default: 6 hours before `to`
+    from: Option<String>,
+    /// End time. Default: now
+    to: Option<String>,
+    /// `json` (default), `geojson`, `linestring`, `csv` or `gpx`
+    format: Option<String>,
+    /// Return only the last N locations
+    limit: Option<usize>,
+}
+
+/// Locations of a device within a time range
+#[get("/locations")]
+async fn locations(db: web::Data<Db>, params: web::Query<LocationsParams>) -> HttpResponse {
+    let to = match params.to.as_deref().map(parse_timestamp) {
+        Some(None) => return HttpResponse::BadRequest().body("Invalid `to` time"),
+        Some(Some(ts)) => ts,
+        None => Utc::now().timestamp(),
+    };
+    let from = match params.from.as_deref().map(parse_timestamp) {
+        Some(None) => return HttpResponse::BadRequest().body("Invalid `from` time"),
+        Some(Some(ts)) => ts,
+        None => to - DEFAULT_RANGE,
+    };
+    let device = match db
+        .query_last_positions(Some(&params.user), Some(&params.device))
+        .await
+    {
+        Ok(positions) => match positions.into_iter().next() {
+            Some(device) => device,
+            None => return HttpResponse::NotFound().body("Unknown user or device"),
+        },
+        Err(e) => {
+            log::error!("Failed to fetch device: {e}");
+            return HttpResponse::InternalServerError()
+                .reason("Failed to fetch locations")
+                .finish();
+        }
+    };
+    // Recorder API includes the end time
+    let range = TimeRange { from, to: to + 1 };
+    let mut track = match db
+        .query_track(
+            device.device_id,
+            Period::Range(range),
+            &FilterParams::default(),
+        )
+        .await
+    {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to fetch locations: {e}");
+            return HttpResponse::InternalServerError()
+                .reason("Failed to fetch locations")
+                .finish();
+        }
+    };
+    if let Some(limit) = params.limit {
+        let skip = track.points.len().saturating_sub(limit);
+        track.points.drain(..skip);
+    }
+    let (content_type, body) = match params.format.as_deref().unwrap_or("json") {
+        "json" => {
+            let data: Vec<Value> = track
+                .points
+                .iter()
+                .map(|pt| Value::from(location(pt, &device.tid, track.tz)))
+                .collect();
+            let json = json!({ "count": data.len(), "data": data, "status": 200 });
+            ("application/json", Ok(json.to_string()))
+        }
+        "geojson" => (
+            "application/geo+json",
+            geojson::track_points(&[track], None),
+        ),
+        "linestring" => ("application/geo+json", geojson::track(&[track])),
+        "csv" => ("text/csv", csv::tracks(&[track])),
+        "gpx" => ("application/gpx+xml", gpx::tracks(&[track])),
+        format => {
+            return HttpResponse::BadRequest().body(format!("Unsupported format `{format}`"));
+        }
+    };
+    match body {
+        Ok(body) => HttpResponse::Ok().content_type(content_type).body(body),
+        Err(e) => {
+            log::error!("Failed to fetch locations: {e}");
+            HttpResponse::InternalServerError()
+                .reason("Failed to fetch locations")
+                .finish()
+        }
+    }
+}
+
+/// Last location of all devices or of a selected user/device
+#[get("/last")]
+async fn last(
+    db: web::Data<Db>,
+    params: web::Query<ListParams>,
+) -> actix_web::Result<impl Responder> {
+    match db
+        .query_last_positions(params.user.as_deref(), params.device.as_deref())
+        .await
+    {
+        Ok(positions) => Ok(web::Json(
+            positions.iter().map(last_location).collect::<Vec<_>>(),
+        )),
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError(
+                "Failed to fetch last locations",
+            ))
+        }
+    }
+}
+
+/// Timestamp and topic of the last received location
+#[get("/monitor")]
+async fn monitor(db: web::Data<Db>) -> actix_web::Result<impl Responder> {
+    match db.query_last_positions(None, None).await {
+        Ok(positions) => {
+            let latest = positions.iter().max_by_key(|pos| pos.ts);
+            Ok(latest
+                .map(|pos| format!("{} {}", pos.ts, topic(&pos.user_id, &pos.device)))
+                .unwrap_or_default())
+        }
+        Err(e) => {
+            log::error!("{e}");
+            Err(error::ErrorInternalServerError(
+                "Failed to fetch last location",
+            ))
+        }
+    }
+}
+
+#[get("/version")]
+async fn version() -> impl Responder {
+    web::Json(json!({ "version": env!("CARGO_PKG_VERSION") }))
+}
+
+/// Register API endpoints
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/0")
+            .service(list)
+            .service(locations)
+            .service(last)
+            .service(monitor)
+            .service(version),
+    );
+}
+
+/// OwnTracks location object with Recorder timestamp fields
+fn location(pt: &GpsPoint, tid: &str, tz: Tz) -> Map<String, Value> {
+    let mut obj: Map<String, Value> = serde_json::from_str(&pt.annotations).unwrap_or_default();
+    obj.extend(time_fields(pt.ts, tz));
+    obj.extend([
+        ("_type".to_string(), json!("location")),
+        ("tid".to_string(), json!(tid)),
+        ("lat".to_string(), json!(pt.y)),
+        ("lon".to_string(), json!(pt.x)),
+        ("vel".to_string(), json!(pt.speed)),
+        ("alt".to_string(), json!(pt.elevation)),
+        ("acc".to_string(), json!(pt.accuracy)),
+        ("vac".to_string(), json!(pt.v_accuracy)),
+        ("cog".to_string(), json!(pt.cog)),
+    ]);
+    obj.retain(|_, value| !value.is_null());
+    obj
+}
+
+fn last_location(pos: &DevicePosition) -> Map<String, Value> {
+    let mut obj = Map::from_iter([
+        ("_type".to_string(), json!("location")),
+        ("username".to_string(), json!(pos.user_id)),
+        ("device".to_string(), json!(pos.device)),
+        ("topic".to_string(), json!(topic(&pos.user_id, &pos.device))),
+        ("tid".to_string(), json!(pos.tid)),
+        ("lat".to_string(), json!(pos.y)),
+        ("lon".to_string(), json!(pos.x)),
+        ("vel".to_string(), json!(pos.speed)),
+        ("alt".to_string(), json!(pos.elevation)),
+        ("acc".to_string(), json!(pos.accuracy)),
+        ("vac".to_string(), json!(pos.v_accuracy)),
+        ("cog".to_string(), json!(pos.cog)),
+    ]);
+    obj.extend(time_fields(pos.ts, pos.tz));
+    obj.retain(|_, value| !value.is_null());
+    obj
+}
+
+/// `tst`, `isotst` and `disptst` fields. `disptst` is in the time zone of the device.
+fn time_fields(ts: i64, tz: Tz) -> Map<String, Value> {
+    let Some(dt) = DateTime::from_timestamp(ts, 0) else {
+        return Map::new();
+    };
+    Map::from_iter([
+        ("tst".to_string(), json!(ts)),
+        ("isotst".to_string(), json!(dt.format("%FT%TZ").to_string())),
+        (
+            "disptst".to_string(),
+            json!(local_datetime(ts, tz).format("%F %T").to_string()),
+        ),
+    ])
+}
+
+fn topic(user: &str, device: &str) -> String {
+    format!("owntracks/{user}/{device}")
+}
@@ -0,0 +1,262 @@
+//! Track simplification
+
+use crate::db::GpsPoint;
+use crate::stats::EARTH_RADIUS;
+use geo::{LineString, SimplifyIdx, SimplifyVwIdx};
+
+/// Equator length in meters
+const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
+/// Iterations of the tolerance search for a target point count
+const SEARCH_STEPS: usize = 30;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Algorithm {
+    DouglasPeucker,
+    Visvalingam,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Detail {
+    /// Tolerance in meters
+    Tolerance(f64),
+    /// Tolerance of one pixel at a web map zoom level
+    Zoom(u8),
+    /// Maximal number of points
+    MaxPoints(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Simplification {
+    pub algorithm: Algorithm,
+    pub detail: Detail,
+}
+
+impl Simplification {
+    /// Simplification from request parameters. `None` without `tolerance`, `zoom` or `max_points`.
+    pub fn from_params(
+        algorithm: Option<&str>,
+        tolerance: Option<f64>,
+        zoom: Option<u8>,
+        max_points: Option<usize>,
+    ) -> anyhow::Result<Option<Self>> {
+        let algorithm = match algorithm.unwrap_or("dp") {
+            "dp" => Algorithm::DouglasPeucker,
+            "vw" => Algorithm::Visvalingam,
+            name => anyhow::bail!("Unknown simplification algorithm `{name}`"),
+        };
+        let detail = match (tolerance, zoom, max_points) {
+            (Some(tolerance), _, _) if tolerance.is_finite() && tolerance >= 0.0 => {
+                Detail::Tolerance(tolerance)
+            }
+            (Some(tolerance), _, _) => anyhow::bail!("Invalid tolerance {tolerance}"),
+            (None, Some(zoom), _) => Detail::Zoom(zoom.min(30)),
+            (None, None, Some(max_points)) => Detail::MaxPoints(max_points.max(2)),
+            (None, None, None) => return Ok(None),
+        };
+        Ok(Some(Simplification { algorithm, detail }))
+    }
+
+    /// Simplified points
+    pub fn select<'a>(&self, points: &'a [GpsPoint]) -> Vec<&'a GpsPoint> {
+        self.indices(points)
+            .into_iter()
+            .map(|i| &points[i])
+            .collect()
+    }
+
+    /// Remove points not needed for the requested level of detail
+    pub fn apply(&self, points: Vec<GpsPoint>) -> Vec<GpsPoint> {
+        let mut keep = vec![false; points.len()];
+        for i in self.indices(&points) {
+            keep[i] = true;
+        }
+        points
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(pt, keep)| keep.then_some(pt))
+            .collect()
+    }
+
+    fn indices(&self, points: &[GpsPoint]) -> Vec<usize> {
+        let Some(first) = points.first() else {
+            return Vec::new();
+        };
+        // Local metric coordinates
+        let scale_y = EARTH_RADIUS.to_radians();
+        let scale_x = scale_y * first.y.to_radians().cos();
+        let line: LineString<f64> = points
+            .iter()
+            .map(|pt| ((pt.x - first.x) * scale_x, (pt.y - first.y) * scale_y))
+            .collect();
+        match self.detail {
+            Detail::Tolerance(tolerance) => self.simplify(&line, tolerance),
+            Detail::Zoom(zoom) => self.simplify(&line, pixel_size(zoom, first.y)),
+            Detail::MaxPoints(max_points) => {
+                if points.len() <= max_points {
+                    return (0..points.len()).collect();
+                }
+                // Binary search of the smallest tolerance within the point limit
+                let (mut lower, mut upper) = (0.0, 1.0);
+                let mut indices = self.simplify(&line, upper);
+                while indices.len() > max_points {
+                    lower = upper;
+                    upper *= 10.0;
+                    indices = self.simplify(&line, upper);
+                }
+                for _ in 0..SEARCH_STEPS {
+                    let tolerance = (lower + upper) / 2.0;
+                    let candidate = self.simplify(&line, tolerance);
+                    if candidate.len() > max_points {
+                        lower = tolerance;
+                    } else {
+                        upper = tolerance;
+                        indices = candidate;
+                    }
+                }
+                indices
+            }
+        }
+    }
+
+    fn simplify(&self, line: &LineString<f64>, tolerance: f64) -> Vec<usize> {
+        match self.algorithm {
+            Algorithm::DouglasPeucker => line.simplify_idx(&tolerance),
+            // Visvalingam uses the triangle area as threshold
+            Algorithm::Visvalingam => line.simplify_vw_idx(&(tolerance * tolerance)),
+        }
+    }
+}
+
+/// Size of a pixel of a 256 pixel tile at a zoom level and latitude in meters
+fn pixel_size(zoom: u8, lat: f64) -> f64 {
+    EARTH_CIRCUMFERENCE * lat.to_radians().cos() / 2f64.powi(zoom as i32 + 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: i64 = 1_700_000_000;
+
+    /// Zigzag track with decreasing amplitude, from about 500 m to 5 m
+    fn zigzag() -> Vec<GpsPoint> {
+        (0..200)
+            .map(|i| {
+                let amplitude = 0.005 / (1.0 + i as f64 / 2.0);
+                let y = 47.0 + if i % 2 == 0 { amplitude } else { -amplitude };
+                GpsPoint::new(8.0 + i as f64 * 1e-3, y, START + i * 10)
+            })
+            .collect()
+    }
+
+    fn simplification(algorithm: &str, detail: Detail) -> Simplification {
+        let mut simplification = Simplification::from_params(Some(algorithm), None, None, Some(2))
+            .unwrap()
+            .unwrap();
+        simplification.detail = detail;
+        simplification
+    }
+
+    #[test]
+    fn params() {
+        let params = |tolerance, zoom, max_points| {
+            Simplification::from_params(None, tolerance, zoom, max_points)
+        };
+        assert!(params(None, None, None).unwrap().is_none());
+        assert!(matches!(
+            params(Some(10.0), Some(12), None).unwrap().unwrap().detail,
+            Detail::Tolerance(10.0)
+        ));
+        assert!(matches!(
+            params(None, Some(40), None).unwrap().unwrap().detail,
+            Detail::Zoom(30)
+        ));
+        assert!(matches!(
+            params(None, None, Some(1)).unwrap().unwrap().detail,
+            Detail::MaxPoints(2)
+        ));
+        assert!(params(Some(f64::NAN), None, None).is_err());
+        assert!(params(Some(f64::INFINITY), None, None).is_err());
+        assert!(params(Some(-1.0), None, None).is_err());
+        assert!(Simplification::from_params(Some("xy"), Some(1.0), None, None).is_err());
+    }
+
+    #[test]
+    fn keeps_first_and_last_point() {
+        let points = zigzag();
+        for algorithm in ["dp", "vw"] {
+            for detail in [
+                Detail::Tolerance(10_000.0),
+                Detail::Zoom(0),
+                Detail::MaxPoints(2),
+            ] {
+                let selected = simplification(algorithm, detail).select(&points);
+                assert_eq!(selected.len(), 2);
+                assert_eq!(selected[0].ts, points[0].ts);
+                assert_eq!(selected[1].ts, points[points.len() - 1].ts);
+            }
+        }
+    }
+
+    #[test]
+    fn algorithms() {
+        let points = zigzag();
+        let dp = simplification("dp", Detail::Tolerance(50.0)).select(&points);
+        let vw = simplification("vw", Detail::Tolerance(50.0)).select(&points);
+        // Douglas-Peucker keeps the large peaks and drops peaks below the tolerance
+        let kept = |selected: &[&GpsPoint], range: std::ops::Range<usize>| {
+            selected
+                .iter()
+                .filter(|pt| range.contains(&((pt.ts - START) as usize / 10)))
+                .count()
+        };
+        assert_eq!(kept(&dp, 0..10), 10);
+        assert_eq!(kept(&dp, 100..199), 0);
+        // Visvalingam-Whyatt compares triangle areas with the square of the tolerance
+        assert_eq!(kept(&vw, 0..10), 10);
+        assert_eq!(kept(&vw, 100..199), 0);
+        assert_ne!(dp.len(), vw.len());
+    }
+
+    #[test]
+    fn zoom_tolerance() {
+        // 256 pixels cover the equator at zoom level 0
+        assert!((pixel_size(0, 0.0) - 156_543.0).abs() < 1.0);
+        assert!((pixel_size(10, 0.0) - 152.87).abs() < 0.01);
+        assert!((pixel_size(10, 60.0) - 76.44).abs() < 0.01);
+        let points = zigzag();
+        let zoom = |zoom| {
+            simplification("dp", Detail::Zoom(zoom))
+                .select(&points)
+                .len()
+        };
+        let tolerance = |tolerance| {
+            simplification("dp", Detail::Tolerance(tolerance))
+                .select(&points)
+                .len()
+        };
+        assert_eq!(zoom(14), tolerance(pixel_size(14, 47.0)));
+        assert!(zoom(10) < zoom(14));
+    }
+
+    #[test]
+    fn max_points() {
+        let points = zigzag();
+        for algorithm in ["dp", "vw"] {
+            let mut previous = 0;
+            for max_points in [2, 3, 10, 50, 150, 199] {
+                let selected =
+                    simplification(algorithm, Detail::MaxPoints(max_points)).select(&points);
+                assert!(selected.len() <= max_points);
+                assert!(selected.len() >= previous);
+                previous = selected.len();
+            }
+            // Short tracks are not simplified
+            let selected = simplification(algorithm, Detail::MaxPoints(200)).select(&points);
+            assert_eq!(selected.len(), 200);
+        }
+        // The search finds the largest simplification within the limit
+        let selected = simplification("dp", Detail::MaxPoints(22)).select(&points);
+        assert_eq!(selected.len(), 22);
+    }
+}
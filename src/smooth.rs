@@ -0,0 +1,246 @@
+//! Kalman smoothing of track coordinates
+
+use crate::db::GpsPoint;
+use crate::stats::EARTH_RADIUS;
+
+/// Position error in meters of points without accuracy
+const DEFAULT_ACCURACY: f64 = 20.0;
+/// Standard deviation of the acceleration in m/s²
+const ACCELERATION_SD: f64 = 2.0;
+/// Standard deviation of reported speeds in m/s
+const SPEED_SD: f64 = 1.0;
+/// Reported speeds below are considered as standing still (m/s)
+const MIN_SPEED: f64 = 0.5;
+
+/// Constant velocity model for one axis
+#[derive(Clone, Copy)]
+struct State {
+    /// Position and velocity
+    s: [f64; 2],
+    /// Covariance
+    p: [[f64; 2]; 2],
+}
+
+impl State {
+    fn predict(&self, dt: f64) -> State {
+        let q = ACCELERATION_SD * ACCELERATION_SD;
+        let [[p00, p01], [p10, p11]] = self.p;
+        State {
+            s: [self.s[0] + dt * self.s[1], self.s[1]],
+            p: [
+                [
+                    p00 + dt * (p10 + p01) + dt * dt * p11 + q * dt.powi(4) / 4.0,
+                    p01 + dt * p11 + q * dt.powi(3) / 2.0,
+                ],
+                [p10 + dt * p11 + q * dt.powi(3) / 2.0, p11 + q * dt * dt],
+            ],
+        }
+    }
+
+    /// Update with a measurement of position (`idx` 0) or velocity (`idx` 1)
+    fn update(&mut self, idx: usize, value: f64, variance: f64) {
+        let innovation_var = self.p[idx][idx] + variance;
+        let gain = [
+            self.p[0][idx] / innovation_var,
+            self.p[1][idx] / innovation_var,
+        ];
+        let innovation = value - self.s[idx];
+        let row = self.p[idx];
+        for (i, gain) in gain.iter().enumerate() {
+            self.s[i] += gain * innovation;
+            for (p, r) in self.p[i].iter_mut().zip(row) {
+                *p -= gain * r;
+            }
+        }
+    }
+}
+
+/// Replace coordinates with Kalman smoothed positions.
+/// Uses accuracy as measurement error and speed and course as velocity measurements.
+pub fn kalman(points: &mut [GpsPoint]) {
+    let Some(first) = points.first() else {
+        return;
+    };
+    // Local metric coordinates
+    let (lon0, lat0) = (first.x, first.y);
+    let scale_y = EARTH_RADIUS.to_radians();
+    let scale_x = scale_y * lat0.to_radians().cos();
+
+    // Forward filtering
+    let mut filtered: Vec<[State; 2]> = Vec::with_capacity(points.len());
+    let mut predicted: Vec<[State; 2]> = Vec::with_capacity(points.len());
+    for (i, pt) in points.iter().enumerate() {
+        let pos = [(pt.x - lon0) * scale_x, (pt.y - lat0) * scale_y];
+        let accuracy = pt
+            .accuracy
+            .map_or(DEFAULT_ACCURACY, |acc| acc.max(1) as f64);
+        let mut states = if i == 0 {
+            let init = |pos| State {
+                s: [pos, 0.0],
+                p: [[accuracy * accuracy, 0.0], [0.0, 100.0]],
+            };
+            [init(pos[0]), init(pos[1])]
+        } else {
+            let dt = (pt.ts - points[i - 1].ts).max(0) as f64;
+            let last = filtered[i - 1];
+            [last[0].predict(dt), last[1].predict(dt)]
+        };
+        predicted.push(states);
+        for axis in 0..2 {
+            states[axis].update(0, pos[axis], accuracy * accuracy);
+        }
+        if let Some(velocity) = velocity(pt) {
+            for axis in 0..2 {
+                states[axis].update(1, velocity[axis], SPEED_SD * SPEED_SD);
+            }
+        }
+        filtered.push(states);
+    }
+
+    // Rauch-Tung-Striebel backward smoothing
+    let mut smoothed = filtered.clone();
+    for i in (0..points.len().saturating_sub(1)).rev() {
+        let dt = (points[i + 1].ts - points[i].ts).max(0) as f64;
+        for axis in 0..2 {
+            let state = &filtered[i][axis];
+            let pred = &predicted[i + 1][axis];
+            // C = P F^T P_pred^-1
+            let [[p00, p01], [p10, p11]] = state.p;
+            let pf = [[p00 + dt * p01, p01], [p10 + dt * p11, p11]];
+            let [[a, b], [c, d]] = pred.p;
+            let det = a * d - b * c;
+            if det.abs() < f64::EPSILON {
+                continue;
+            }
+            let inv = [[d / det, -b / det], [-c / det, a / det]];
+            let diff = [
+                smoothed[i + 1][axis].s[0] - pred.s[0],
+                smoothed[i + 1][axis].s[1] - pred.s[1],
+            ];
+            for (k, row) in pf.iter().enumerate() {
+                let gain = [
+                    row[0] * inv[0][0] + row[1] * inv[1][0],
+                    row[0] * inv[0][1] + row[1] * inv[1][1],
+                ];
+                smoothed[i][axis].s[k] = state.s[k] + gain[0] * diff[0] + gain[1] * diff[1];
+            }
+        }
+    }
+
+    for (pt, states) in points.iter_mut().zip(smoothed) {
+        pt.x = lon0 + states[0].s[0] / scale_x;
+        pt.y = lat0 + states[1].s[0] / scale_y;
+    }
+}
+
+/// Velocity in m/s (east, north) from reported speed and course.
+/// Values derived from positions are no independent measurements and are skipped.
+fn velocity(pt: &GpsPoint) -> Option<[f64; 2]> {
+    let speed = pt.speed.filter(|_| !pt.speed_derived)? as f64 / 3.6;
+    if speed < MIN_SPEED {
+        return Some([0.0, 0.0]);
+    }
+    let course = (pt.cog.filter(|_| !pt.cog_derived)? as f64).to_radians();
+    Some([speed * course.sin(), speed * course.cos()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: i64 = 1_700_000_000;
+    const LAT: f64 = 47.0;
+
+    /// Track moving east at 10 m/s with alternating lateral noise of 15 m
+    fn noisy_track() -> Vec<GpsPoint> {
+        let scale_y = EARTH_RADIUS.to_radians();
+        let scale_x = scale_y * LAT.to_radians().cos();
+        (0..60)
+            .map(|i| {
+                let noise = if i % 2 == 0 { 15.0 } else { -15.0 };
+                let mut pt = GpsPoint::new(
+                    8.0 + i as f64 * 10.0 / scale_x,
+                    LAT + noise / scale_y,
+                    START + i,
+                );
+                pt.accuracy = Some(15);
+                pt
+            })
+            .collect()
+    }
+
+    /// Mean distance in meters from the true track
+    fn mean_error(points: &[GpsPoint]) -> f64 {
+        let scale_y = EARTH_RADIUS.to_radians();
+        points
+            .iter()
+            .map(|pt| (pt.y - LAT).abs() * scale_y)
+            .sum::<f64>()
+            / points.len() as f64
+    }
+
+    #[test]
+    fn empty_and_single_point() {
+        kalman(&mut []);
+        let mut points = vec![GpsPoint::new(8.0, LAT, START)];
+        kalman(&mut points);
+        assert!((points[0].x - 8.0).abs() < 1e-9);
+        assert!((points[0].y - LAT).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reduces_noise() {
+        let mut points = noisy_track();
+        let raw_error = mean_error(&points);
+        kalman(&mut points);
+        assert!(mean_error(&points) < raw_error / 2.0);
+        // Progress along the track is preserved
+        assert!(points.windows(2).all(|pair| pair[1].x > pair[0].x));
+        assert_eq!(points.len(), 60);
+    }
+
+    #[test]
+    fn speed_and_course_measurements() {
+        let mut without = noisy_track();
+        kalman(&mut without);
+        let mut with = noisy_track();
+        for pt in &mut with {
+            pt.speed = Some(36);
+            pt.cog = Some(90);
+        }
+        kalman(&mut with);
+        assert!(mean_error(&with) < mean_error(&without));
+    }
+
+    #[test]
+    fn derived_speed_and_course() {
+        let mut reported = noisy_track();
+        kalman(&mut reported);
+        let mut derived = noisy_track();
+        for pt in &mut derived {
+            // Wrong direction to make a difference visible
+            pt.speed = Some(36);
+            pt.cog = Some(0);
+            pt.speed_derived = true;
+            pt.cog_derived = true;
+        }
+        kalman(&mut derived);
+        for (pt, expected) in derived.iter().zip(&reported) {
+            assert_eq!((pt.x, pt.y), (expected.x, expected.y));
+        }
+    }
+
+    #[test]
+    fn standing_still() {
+        let mut points: Vec<GpsPoint> = (0..30)
+            .map(|i| {
+                let offset = if i % 2 == 0 { 0.0001 } else { -0.0001 };
+                let mut pt = GpsPoint::new(8.0, LAT + offset, START + i * 10);
+                pt.speed = Some(0);
+                pt
+            })
+            .collect();
+        kalman(&mut points);
+        assert!(points.iter().all(|pt| (pt.y - LAT).abs() < 0.00005));
+    }
+}
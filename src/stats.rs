@@ -1,9 +1,19 @@
 use crate::db::GpsPoint;
-use chrono::{DateTime, FixedOffset, Local, NaiveDateTime};
+use crate::timestamp::format_timestamp;
+use chrono_tz::Tz;
 use geo::algorithm::vincenty_distance::VincentyDistance;
 use geojson::{JsonObject, JsonValue};
 use stats::{MinMax, OnlineStats};
 
+/// Mean earth radius in meters
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+/// Minimal speed between two points considered as moving in m/s
+const MIN_MOVING_SPEED: f64 = 0.5;
+/// Time window in seconds around a segment for the moving speed
+const MOVING_WINDOW: i64 = 30;
+/// Minimal elevation change counted as climb or descent in meters
+const ELEVATION_THRESHOLD: i16 = 5;
+
 #[derive(Default)]
 pub struct TrackStats {
     ts: MinMax<i64>,
@@ -16,19 +26,8 @@ pub struct TrackStats {
 impl TrackStats {
     pub fn from_iter<'a>(iter: impl Iterator<Item = &'a GpsPoint>) -> Self {
         let mut stats = Self::default();
-        let tz = *Local::now().offset();
         for pt in iter {
-            // SQLite stores in UTC without TZ
-            let dt = DateTime::<FixedOffset>::parse_from_str(&pt.ts, "%F %T%#z")
-                .or(NaiveDateTime::parse_from_str(&pt.ts, "%F %T")
-                    .map(|utcts| utcts.and_local_timezone(tz).unwrap()));
-            // log::debug!("Timestamp `{}` -> `{dt:?}`", &pt.ts);
-            match dt {
-                Ok(dt) => stats.ts.add(dt.timestamp()),
-                Err(e) => {
-                    log::info!("Ignoring invalid timestamp `{}`: {e}", &pt.ts);
-                }
-            }
+            stats.ts.add(pt.ts);
             if let Some(speed) = pt.speed {
                 stats.speed.add(speed);
                 stats.speed_stats.add(speed);
@@ -40,15 +39,9 @@ impl TrackStats {
         }
         stats
     }
-    pub fn as_properties(&self) -> JsonObject {
-        let (ts_start, ts_end) = (
-            self.ts
-                .min()
-                .and_then(|ts| DateTime::from_timestamp(*ts, 0)),
-            self.ts
-                .max()
-                .and_then(|ts| DateTime::from_timestamp(*ts, 0)),
-        );
+    /// Statistics with timestamps in the given time zone
+    pub fn as_properties(&self, tz: Tz) -> JsonObject {
+        let (ts_start, ts_end) = (self.ts.min().copied(), self.ts.max().copied());
         let duration = if let (Some(ts_start), Some(ts_end)) = (ts_start, ts_end) {
             Some(ts_end - ts_start)
         } else {
@@ -82,16 +75,13 @@ impl TrackStats {
             ),
             (
                 "ts_start".to_string(),
-                JsonValue::from(ts_start.map(|ts| ts.format("%F %T%z").to_string())),
+                JsonValue::from(ts_start.map(|ts| format_timestamp(ts, tz))),
             ),
             (
                 "ts_end".to_string(),
-                JsonValue::from(ts_end.map(|ts| ts.format("%F %T%z").to_string())),
-            ),
-            (
-                "duration".to_string(),
-                JsonValue::from(duration.map(|v| v.num_seconds())),
+                JsonValue::from(ts_end.map(|ts| format_timestamp(ts, tz))),
             ),
+            ("duration".to_string(), JsonValue::from(duration)),
         ])
     }
 }
@@ -132,10 +122,7 @@ impl DistanceStats {
         let mut stats = Self::default();
         let points = iter.collect::<Vec<_>>();
         points.windows(2).for_each(|pair| {
-            let p1 = geo::Point::from(pair[0]);
-            let p2 = geo::Point::from(pair[1]);
-            let distance = p1.vincenty_distance(&p2).unwrap();
-            stats.distance += distance;
+            stats.distance += distance(pair[0], pair[1]);
         });
         stats
     }
@@ -144,6 +131,123 @@ impl DistanceStats {
     }
 }
 
+/// Distance between two (x, y) points in meters
+pub fn distance(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    geo::Point::from(p1)
+        .vincenty_distance(&geo::Point::from(p2))
+        .unwrap_or(0.0)
+}
+
+#[derive(Default)]
+pub struct MovingStats {
+    /// Distance while moving in meters
+    moving_distance: f64,
+    /// Time while moving in seconds
+    moving_time: i64,
+    /// Time without movement in seconds
+    stopped_time: i64,
+    /// Duration of the current pause
+    pause: i64,
+    longest_pause: i64,
+}
+
+impl MovingStats {
+    pub fn from_iter<'a>(iter: impl Iterator<Item = &'a GpsPoint>) -> Self {
+        let mut stats = Self::default();
+        let points = iter.collect::<Vec<_>>();
+        // Speed over the window around each segment, which cancels out position jitter
+        let (mut start, mut end) = (0, 0);
+        points.windows(2).enumerate().for_each(|(i, pair)| {
+            let dist = distance((pair[0].x, pair[0].y), (pair[1].x, pair[1].y));
+            let dt = pair[1].ts - pair[0].ts;
+            while points[start].ts < pair[0].ts - MOVING_WINDOW / 2 {
+                start += 1;
+            }
+            end = end.max(i + 1);
+            while end + 1 < points.len() && points[end + 1].ts <= pair[1].ts + MOVING_WINDOW / 2 {
+                end += 1;
+            }
+            let window_dist = distance(
+                (points[start].x, points[start].y),
+                (points[end].x, points[end].y),
+            );
+            let window_dt = points[end].ts - points[start].ts;
+            if dt == 0 || window_dist / window_dt.max(1) as f64 >= MIN_MOVING_SPEED {
+                stats.moving_distance += dist;
+                stats.moving_time += dt;
+                stats.pause = 0;
+            } else {
+                stats.stopped_time += dt;
+                stats.pause += dt;
+                stats.longest_pause = stats.longest_pause.max(stats.pause);
+            }
+        });
+        stats
+    }
+    pub fn as_properties(&self) -> JsonObject {
+        let moving_speed =
+            (self.moving_time > 0).then(|| self.moving_distance / self.moving_time as f64 * 3.6);
+        // Minutes per km
+        let pace = (self.moving_distance > 0.0)
+            .then(|| self.moving_time as f64 / 60.0 / (self.moving_distance / 1000.0));
+        JsonObject::from_iter([
+            ("moving_time".to_string(), JsonValue::from(self.moving_time)),
+            (
+                "stopped_time".to_string(),
+                JsonValue::from(self.stopped_time),
+            ),
+            ("moving_speed".to_string(), JsonValue::from(moving_speed)),
+            ("pace".to_string(), JsonValue::from(pace)),
+            (
+                "longest_pause".to_string(),
+                JsonValue::from(self.longest_pause),
+            ),
+        ])
+    }
+}
+
+/// Fill in missing speed and course from the distance and bearing to the next point
+pub fn with_motion(mut points: Vec<GpsPoint>) -> Vec<GpsPoint> {
+    let n = points.len();
+    for i in 0..n {
+        if points[i].speed.is_some() && points[i].cog.is_some() {
+            continue;
+        }
+        // Segment starting at the point, or ending at the last point
+        let (from, to) = if i + 1 < n {
+            (&points[i], &points[i + 1])
+        } else if i > 0 {
+            (&points[i - 1], &points[i])
+        } else {
+            continue;
+        };
+        let dt = to.ts - from.ts;
+        let dist = distance((from.x, from.y), (to.x, to.y));
+        let speed = (dt > 0).then(|| (dist / dt as f64 * 3.6).round() as i16);
+        let cog =
+            (dist > 0.0).then(|| bearing((from.x, from.y), (to.x, to.y)).round() as i16 % 360);
+        let pt = &mut points[i];
+        if pt.speed.is_none() && speed.is_some() {
+            pt.speed = speed;
+            pt.speed_derived = true;
+        }
+        if pt.cog.is_none() && cog.is_some() {
+            pt.cog = cog;
+            pt.cog_derived = true;
+        }
+    }
+    points
+}
+
+/// Initial bearing from p1 to p2 in degrees (0-360)
+fn bearing(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (p1.1.to_radians(), p2.1.to_radians());
+    let dlon = (p2.0 - p1.0).to_radians();
+    let y = dlon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
 #[derive(Default)]
 pub struct ElevationDiffStats {
     elevation_up: i64,
@@ -153,15 +257,22 @@ pub struct ElevationDiffStats {
 impl ElevationDiffStats {
     pub fn from_iter(iter: impl Iterator<Item = i16>) -> Self {
         let mut stats = Self::default();
-        let points = iter.collect::<Vec<_>>();
-        points.windows(2).for_each(|pair| {
-            let (elev0, elev1) = (pair[0], pair[1]);
-            if elev1 > elev0 {
-                stats.elevation_up += (elev1 - elev0) as i64;
-            } else {
-                stats.elevation_down += (elev0 - elev1) as i64;
+        // Hysteresis: count changes from the last reference elevation above threshold only
+        let mut reference: Option<i16> = None;
+        for elevation in iter {
+            let Some(elev0) = reference else {
+                reference = Some(elevation);
+                continue;
+            };
+            let diff = elevation as i64 - elev0 as i64;
+            if diff >= ELEVATION_THRESHOLD as i64 {
+                stats.elevation_up += diff;
+                reference = Some(elevation);
+            } else if diff <= -(ELEVATION_THRESHOLD as i64) {
+                stats.elevation_down -= diff;
+                reference = Some(elevation);
             }
-        });
+        }
         stats
     }
     pub fn as_properties(&self) -> JsonObject {
@@ -177,3 +288,68 @@ impl ElevationDiffStats {
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: i64 = 1_700_000_000;
+    /// Degrees latitude per meter
+    const METER: f64 = 1.0 / 111_195.0;
+
+    #[test]
+    fn moving_stats_jitter() {
+        // 1 Hz positions jumping by 2 m around a fixed position
+        let points: Vec<GpsPoint> = (0..120)
+            .map(|i| {
+                let offset = if i % 2 == 0 { METER } else { -METER };
+                GpsPoint::new(8.0, 47.0 + offset, START + i)
+            })
+            .collect();
+        let stats = MovingStats::from_iter(points.iter());
+        assert_eq!(stats.moving_time, 0);
+        assert_eq!(stats.stopped_time, 119);
+        assert_eq!(stats.longest_pause, 119);
+    }
+
+    #[test]
+    fn moving_stats_walk_with_pause() {
+        // Walking north at 1.5 m/s, stopping for 5 minutes and walking on
+        let mut points: Vec<GpsPoint> = (0..=60)
+            .map(|i| GpsPoint::new(8.0, 47.0 + i as f64 * 15.0 * METER, START + i * 10))
+            .collect();
+        let stop = points[60].y;
+        points.extend((1..=30).map(|i| GpsPoint::new(8.0, stop, START + 600 + i * 10)));
+        points.extend(
+            (1..=60)
+                .map(|i| GpsPoint::new(8.0, stop + i as f64 * 15.0 * METER, START + 900 + i * 10)),
+        );
+        let stats = MovingStats::from_iter(points.iter());
+        assert_eq!(stats.moving_time + stats.stopped_time, 1500);
+        // Segments within the window around the stop count as moving
+        assert!((1180..=1220).contains(&stats.moving_time));
+        assert!((280..=300).contains(&stats.longest_pause));
+        assert!((stats.moving_distance - 1800.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn elevation_hysteresis() {
+        let stats = |elevations: &[i16]| {
+            let stats = ElevationDiffStats::from_iter(elevations.iter().copied());
+            (stats.elevation_up, stats.elevation_down)
+        };
+        // Noise below the threshold
+        assert_eq!(stats(&[500, 503, 498, 502, 499, 501]), (0, 0));
+        // Slow climb in small steps
+        assert_eq!(stats(&(500..=510).collect::<Vec<_>>()), (10, 0));
+        // Climb and descent with noise
+        assert_eq!(
+            stats(&[500, 506, 504, 512, 509, 520, 514, 516, 500]),
+            (20, 20)
+        );
+        // Changes above the threshold are counted completely
+        assert_eq!(stats(&[500, 507, 500]), (7, 7));
+        assert_eq!(stats(&[]), (0, 0));
+        assert_eq!(stats(&[500]), (0, 0));
+    }
+}
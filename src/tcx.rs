@@ -0,0 +1,111 @@
+//! Training Center XML (TCX) export
+
+use crate::activity::{laps, LapSplit, Sport};
+use crate::db::TrackData;
+use crate::timestamp::format_timestamp;
+use crate::xml_writer::{text_element, writer, Writer};
+use chrono_tz::Tz;
+use xml::writer::XmlEvent;
+
+const TCX_NS: &str = "http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2";
+const XSI_NS: &str = "http://www.w3.org/2001/XMLSchema-instance";
+const TPX_NS: &str = "http://www.garmin.com/xmlschemas/ActivityExtension/v2";
+const SCHEMA_LOCATION: &str = "http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2 http://www.garmin.com/xmlschemas/TrainingCenterDatabasev2.xsd";
+
+/// Build a TCX file with an activity per track
+pub fn tracks(tracks: &[TrackData], sport: Sport, split: LapSplit) -> anyhow::Result<String> {
+    let mut w = writer();
+    w.write(
+        XmlEvent::start_element("TrainingCenterDatabase")
+            .default_ns(TCX_NS)
+            .ns("xsi", XSI_NS)
+            .ns("ns3", TPX_NS)
+            .attr("xsi:schemaLocation", SCHEMA_LOCATION),
+    )?;
+    w.write(XmlEvent::start_element("Activities"))?;
+    let sport = match sport {
+        Sport::Running => "Running",
+        Sport::Cycling => "Biking",
+        _ => "Other",
+    };
+    for track in tracks {
+        let laps = laps(track, split);
+        let Some(first) = laps.first() else {
+            continue;
+        };
+        w.write(XmlEvent::start_element("Activity").attr("Sport", sport))?;
+        text_element(&mut w, "Id", &utc_time(first.ts_start))?;
+        for lap in &laps {
+            w.write(XmlEvent::start_element("Lap").attr("StartTime", &utc_time(lap.ts_start)))?;
+            text_element(&mut w, "TotalTimeSeconds", &lap.duration().to_string())?;
+            text_element(&mut w, "DistanceMeters", &format!("{:.1}", lap.distance))?;
+            if let Some(speed) = lap.max_speed() {
+                text_element(&mut w, "MaximumSpeed", &format!("{speed:.2}"))?;
+            }
+            text_element(&mut w, "Calories", "0")?;
+            if let Some(heart_rate) = lap.avg_heart_rate() {
+                heart_rate_element(&mut w, "AverageHeartRateBpm", heart_rate)?;
+            }
+            if let Some(heart_rate) = lap.max_heart_rate() {
+                heart_rate_element(&mut w, "MaximumHeartRateBpm", heart_rate)?;
+            }
+            text_element(&mut w, "Intensity", "Active")?;
+            if let Some(cadence) = lap.avg_cadence() {
+                text_element(&mut w, "Cadence", &cadence.round().to_string())?;
+            }
+            let trigger = if lap.distance_trigger {
+                "Distance"
+            } else {
+                "Manual"
+            };
+            text_element(&mut w, "TriggerMethod", trigger)?;
+            w.write(XmlEvent::start_element("Track"))?;
+            for p in &lap.points {
+                let pt = p.pt;
+                w.write(XmlEvent::start_element("Trackpoint"))?;
+                text_element(&mut w, "Time", &utc_time(pt.ts))?;
+                w.write(XmlEvent::start_element("Position"))?;
+                text_element(&mut w, "LatitudeDegrees", &format!("{:.7}", pt.y))?;
+                text_element(&mut w, "LongitudeDegrees", &format!("{:.7}", pt.x))?;
+                w.write(XmlEvent::end_element())?;
+                if let Some(elevation) = pt.elevation {
+                    text_element(&mut w, "AltitudeMeters", &elevation.to_string())?;
+                }
+                text_element(&mut w, "DistanceMeters", &format!("{:.1}", p.distance))?;
+                if let Some(heart_rate) = p.heart_rate {
+                    heart_rate_element(&mut w, "HeartRateBpm", heart_rate)?;
+                }
+                if let Some(cadence) = p.cadence {
+                    text_element(&mut w, "Cadence", &cadence.round().to_string())?;
+                }
+                if let Some(speed) = pt.speed {
+                    w.write(XmlEvent::start_element("Extensions"))?;
+                    w.write(XmlEvent::start_element("ns3:TPX"))?;
+                    let speed = format!("{:.2}", speed as f64 / 3.6);
+                    text_element(&mut w, "ns3:Speed", &speed)?;
+                    w.write(XmlEvent::end_element())?;
+                    w.write(XmlEvent::end_element())?;
+                }
+                w.write(XmlEvent::end_element())?;
+            }
+            w.write(XmlEvent::end_element())?;
+            w.write(XmlEvent::end_element())?;
+        }
+        w.write(XmlEvent::end_element())?;
+    }
+    w.write(XmlEvent::end_element())?;
+    w.write(XmlEvent::end_element())?;
+    Ok(String::from_utf8(w.into_inner())?)
+}
+
+fn heart_rate_element(w: &mut Writer, name: &str, heart_rate: f64) -> anyhow::Result<()> {
+    w.write(XmlEvent::start_element(name))?;
+    text_element(w, "Value", &heart_rate.round().to_string())?;
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+/// TCX times are expected in UTC
+fn utc_time(ts: i64) -> String {
+    format_timestamp(ts, Tz::UTC)
+}
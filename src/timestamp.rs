@@ -0,0 +1,208 @@
+//! Timestamp parsing
+
+use chrono::{DateTime, Months, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, TimeZone};
+use chrono_tz::Tz;
+
+/// Parse timestamps in RFC 3339, SQL or ISO 8601 (UTC if without offset),
+/// date only (UTC midnight) or UNIX epoch (s or ms) format
+pub fn parse_timestamp(ts: &str) -> Option<i64> {
+    let ts = ts.trim();
+    if let Ok(epoch) = ts.parse::<i64>() {
+        return Some(epoch_seconds(epoch));
+    }
+    if let Ok(dt) =
+        DateTime::parse_from_rfc3339(ts).or_else(|_| DateTime::parse_from_str(ts, "%F %T%#z"))
+    {
+        return Some(dt.timestamp());
+    }
+    let ts = ts.trim_end_matches('Z');
+    ["%F %T", "%FT%T", "%FT%T%.f", "%FT%R"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(ts, fmt).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(ts, "%F")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|dt| dt.and_utc().timestamp())
+}
+
+/// Convert epoch in seconds or milliseconds to seconds
+pub fn epoch_seconds(epoch: i64) -> i64 {
+    if epoch > 100_000_000_000 {
+        epoch / 1000
+    } else {
+        epoch
+    }
+}
+
+/// Time range of UNIX timestamps. `to` is exclusive.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeRange {
+    pub from: i64,
+    pub to: i64,
+}
+
+impl TimeRange {
+    pub fn contains(&self, ts: i64) -> bool {
+        self.from <= ts && ts < self.to
+    }
+}
+
+/// Queried time period
+#[derive(Clone, Copy, Debug)]
+pub enum Period {
+    Range(TimeRange),
+    /// Calendar day in the time zone of the device
+    Day(NaiveDate),
+    /// Calendar day in the time zone of the device containing the timestamp
+    DayOf(i64),
+    /// Calendar month in the time zone of the device, starting at the given date
+    Month(NaiveDate),
+    /// Calendar days in the time zone of the device, including the last day
+    Days(NaiveDate, NaiveDate),
+}
+
+impl Period {
+    const DAY: i64 = 24 * 3600;
+
+    /// Period from `from` and `to` parameters or from a `day` date or timestamp.
+    /// `to` defaults to one day after `from`.
+    pub fn from_params(
+        from: Option<&str>,
+        to: Option<&str>,
+        day: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        if let Some(from) = from {
+            let Some(from) = parse_timestamp(from) else {
+                anyhow::bail!("Invalid start time `{from}`");
+            };
+            let to = match to {
+                Some(to) => match parse_timestamp(to) {
+                    Some(to) => to,
+                    None => anyhow::bail!("Invalid end time `{to}`"),
+                },
+                None => from + Self::DAY,
+            };
+            if to < from {
+                anyhow::bail!("End time before start time");
+            }
+            Ok(Period::Range(TimeRange { from, to }))
+        } else if let Some(day) = day {
+            if let Ok(date) = NaiveDate::parse_from_str(day, "%F") {
+                Ok(Period::Day(date))
+            } else if let Some(ts) = parse_timestamp(day) {
+                Ok(Period::DayOf(ts))
+            } else {
+                anyhow::bail!("Invalid date `{day}`")
+            }
+        } else {
+            anyhow::bail!("Missing time range")
+        }
+    }
+
+    /// Calendar month in format YYYY-MM
+    pub fn month(month: &str) -> anyhow::Result<Self> {
+        match NaiveDate::parse_from_str(&format!("{month}-01"), "%F") {
+            Ok(date) => Ok(Period::Month(date)),
+            Err(_) => anyhow::bail!("Invalid month `{month}`"),
+        }
+    }
+
+    /// Calendar days in format YYYY-MM-DD, including the last day
+    pub fn days(first: &str, last: &str) -> anyhow::Result<Self> {
+        let parse = |date| {
+            NaiveDate::parse_from_str(date, "%F")
+                .map_err(|_| anyhow::anyhow!("Invalid date `{date}`"))
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if last < first {
+            anyhow::bail!("End date before start date");
+        }
+        Ok(Period::Days(first, last))
+    }
+
+    /// Time range with calendar days in the given time zone
+    pub fn time_range(&self, tz: Tz) -> TimeRange {
+        let date = match self {
+            Period::Range(range) => return *range,
+            Period::Day(date) => *date,
+            Period::DayOf(ts) => local_datetime(*ts, tz).date_naive(),
+            Period::Month(date) => {
+                let from = local_midnight(*date, tz);
+                let to = date
+                    .checked_add_months(Months::new(1))
+                    .map(|next| local_midnight(next, tz))
+                    .unwrap_or(from + 31 * Self::DAY);
+                return TimeRange { from, to };
+            }
+            Period::Days(first, last) => {
+                let from = local_midnight(*first, tz);
+                let to = last
+                    .succ_opt()
+                    .map(|next| local_midnight(next, tz))
+                    .unwrap_or(local_midnight(*last, tz) + Self::DAY);
+                return TimeRange { from, to };
+            }
+        };
+        let from = local_midnight(date, tz);
+        let to = date
+            .succ_opt()
+            .map(|next| local_midnight(next, tz))
+            .unwrap_or(from + Self::DAY);
+        TimeRange { from, to }
+    }
+
+    /// Time range containing the period in all time zones
+    pub fn bounds(&self) -> TimeRange {
+        let range = self.time_range(Tz::UTC);
+        match self {
+            Period::Range(_) => range,
+            // UTC offsets are between -12 and +14 hours
+            _ => TimeRange {
+                from: range.from - 14 * 3600,
+                to: range.to + 12 * 3600,
+            },
+        }
+    }
+}
+
+/// Start of a calendar day in the given time zone
+fn local_midnight(date: NaiveDate, tz: Tz) -> i64 {
+    let midnight = date.and_time(NaiveTime::MIN);
+    // Midnight may be skipped by a DST transition
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .map(|dt| dt.timestamp())
+        .unwrap_or_else(|| tz.from_utc_datetime(&midnight).timestamp())
+}
+
+/// Date and time of a UNIX timestamp in the given time zone
+pub fn local_datetime(ts: i64, tz: Tz) -> DateTime<Tz> {
+    DateTime::from_timestamp(ts, 0)
+        .unwrap_or_default()
+        .with_timezone(&tz)
+}
+
+/// RFC 3339 timestamp with the offset of the given time zone
+pub fn format_timestamp(ts: i64, tz: Tz) -> String {
+    local_datetime(ts, tz).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Parse IANA time zone name
+pub fn parse_tz(name: &str) -> anyhow::Result<Tz> {
+    name.parse()
+        .map_err(|_| anyhow::anyhow!("Unknown time zone `{name}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_reversed_range() {
+        assert!(Period::from_params(Some("1700000000"), Some("1700003600"), None).is_ok());
+        assert!(Period::from_params(Some("1700003600"), Some("1700000000"), None).is_err());
+        assert!(Period::days("2024-06-02", "2024-06-01").is_err());
+    }
+}
@@ -0,0 +1,368 @@
+//! Trip segmentation with stop detection
+
+use crate::db::{Db, DevicePosition, GpsPoint, TripInfo};
+use crate::filter::Filter;
+use crate::places;
+use crate::stats;
+use crate::timestamp::{format_timestamp, Period, TimeRange};
+use chrono::Utc;
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+/// Maximal distance from the first point of a stay in meters
+const STAY_RADIUS: f64 = 100.0;
+/// Minimal duration of a stay in seconds
+const MIN_STAY: i64 = 5 * 60;
+/// Signal gap in seconds ending a trip
+const MAX_GAP: i64 = 20 * 60;
+/// Minimal length of a trip in meters
+const MIN_TRIP_DISTANCE: f64 = STAY_RADIUS;
+/// Maximal number of points segmented at once
+const BATCH_SIZE: usize = 50_000;
+/// Interval of the background segmentation
+const UPDATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Segmented trip
+#[derive(Debug)]
+pub struct Trip {
+    pub ts_start: i64,
+    pub ts_end: i64,
+    /// Distance in meters
+    pub distance: f64,
+    /// Trip is not finished by a stay or a signal gap yet
+    pub open: bool,
+}
+
+/// Detected stay
+#[derive(Debug)]
+pub struct Stay {
+    pub arrival: i64,
+    pub departure: i64,
+    /// Center of stay points
+    pub lat: f64,
+    pub lon: f64,
+    /// Name from position annotations (`poi` or `semantic_type`)
+    pub name: Option<String>,
+    /// Stay continues after the last point
+    pub open: bool,
+}
+
+/// Result of a segmentation run
+pub struct Segmentation {
+    pub trips: Vec<Trip>,
+    pub stays: Vec<Stay>,
+    /// Timestamp of the first point not belonging to a finished trip or stay
+    pub resume: i64,
+}
+
+/// Split points into trips at stays and signal gaps.
+/// With `finished` set, a trailing trip is closed at the last point.
+pub fn segment(all_points: &[GpsPoint], filter: &Filter, finished: bool) -> Segmentation {
+    let points = filter.select(all_points);
+    let n = points.len();
+    let mut trips = Vec::new();
+    let mut stays = Vec::new();
+    let mut trip_start: Option<usize> = None;
+    let mut i = 0;
+    while i < n {
+        // Extend stay candidate as long as points are within radius
+        let mut j = i + 1;
+        while j < n && distance(points[i], points[j]) <= STAY_RADIUS {
+            j += 1;
+        }
+        if points[j - 1].ts - points[i].ts >= MIN_STAY {
+            if let Some(start) = trip_start.take() {
+                trips.extend(trip(&points[start..=i], false));
+            }
+            if j == n {
+                // Stay continues, resume at its beginning unless finished
+                let resume = if finished {
+                    points[j - 1].ts + 1
+                } else {
+                    points[i].ts
+                };
+                stays.push(stay(&points[i..j], !finished));
+                return Segmentation {
+                    trips,
+                    stays,
+                    resume,
+                };
+            }
+            stays.push(stay(&points[i..j], false));
+            // Departure at the last point of the stay
+            trip_start = Some(j - 1);
+            i = j;
+            continue;
+        }
+        let start = *trip_start.get_or_insert(i);
+        if i + 1 < n && points[i + 1].ts - points[i].ts > MAX_GAP {
+            trips.extend(trip(&points[start..=i], false));
+            trip_start = None;
+        }
+        i += 1;
+    }
+    let resume = match trip_start {
+        Some(start) if !finished => {
+            trips.extend(trip(&points[start..], true));
+            points[start].ts
+        }
+        Some(start) => {
+            trips.extend(trip(&points[start..], false));
+            points[n - 1].ts + 1
+        }
+        None => all_points.last().map(|pt| pt.ts + 1).unwrap_or(0),
+    };
+    Segmentation {
+        trips,
+        stays,
+        resume,
+    }
+}
+
+fn trip(points: &[&GpsPoint], open: bool) -> Option<Trip> {
+    let (first, last) = (points.first()?, points.last()?);
+    let distance = points
+        .windows(2)
+        .map(|pair| distance(pair[0], pair[1]))
+        .sum();
+    if points.len() < 2 || distance < MIN_TRIP_DISTANCE {
+        return None;
+    }
+    Some(Trip {
+        ts_start: first.ts,
+        ts_end: last.ts,
+        distance,
+        open,
+    })
+}
+
+fn stay(points: &[&GpsPoint], open: bool) -> Stay {
+    let count = points.len() as f64;
+    let name = points.iter().find_map(|pt| {
+        let annotations: Map<String, Value> = serde_json::from_str(&pt.annotations).ok()?;
+        ["poi", "semantic_type"]
+            .iter()
+            .find_map(|key| annotations.get(*key)?.as_str().map(str::to_string))
+    });
+    Stay {
+        arrival: points[0].ts,
+        departure: points[points.len() - 1].ts,
+        lat: points.iter().map(|pt| pt.y).sum::<f64>() / count,
+        lon: points.iter().map(|pt| pt.x).sum::<f64>() / count,
+        name,
+        open,
+    }
+}
+
+fn distance(p1: &GpsPoint, p2: &GpsPoint) -> f64 {
+    stats::distance((p1.x, p1.y), (p2.x, p2.y))
+}
+
+/// Segment new positions of a device and store finished trips and visits.
+/// Returns the number of stored trips.
+pub async fn update_device_trips(db: &Db, device: &DevicePosition) -> anyhow::Result<usize> {
+    let device_id = device.device_id;
+    let mut places = db.query_places(&device.user_id).await?;
+    let filter = Filter::from(&db.query_device_filter(device_id).await?);
+    let mut count = 0;
+    loop {
+        let since = db.query_trips_until(device_id).await?;
+        let points = db.query_points_since(device_id, since, BATCH_SIZE).await?;
+        let Some(last) = points.last() else {
+            return Ok(count);
+        };
+        let complete = points.len() < BATCH_SIZE;
+        let mut segmentation = segment(&points, &filter, complete && is_finished(last));
+        if !complete && segmentation.resume <= since {
+            // Trip or stay longer than a batch: split it
+            segmentation = segment(&points, &filter, true);
+            if segmentation.resume <= since {
+                anyhow::bail!("Segmentation of device {device_id} does not advance");
+            }
+        }
+        segmentation.trips.retain(|trip| !trip.open);
+        segmentation.stays.retain(|stay| !stay.open);
+        count += segmentation.trips.len();
+        let mut visits = Vec::new();
+        for stay in &segmentation.stays {
+            let place_id = places::assign_place(db, &device.user_id, &mut places, stay).await?;
+            visits.push((stay, place_id));
+        }
+        db.store_segmentation(device_id, &segmentation.trips, &visits, segmentation.resume)
+            .await?;
+        if complete {
+            return Ok(count);
+        }
+    }
+}
+
+/// Without new positions since a signal gap, the last trip is finished
+fn is_finished(last: &GpsPoint) -> bool {
+    Utc::now().timestamp() - last.ts > MAX_GAP
+}
+
+/// Segment new positions of all devices
+pub async fn update_trips(db: &Db) -> anyhow::Result<()> {
+    for pos in db.query_last_positions(None, None).await? {
+        let count = update_device_trips(db, &pos).await?;
+        if count > 0 {
+            log::info!("{}/{}: {count} new trips", pos.user_id, pos.device);
+        }
+    }
+    Ok(())
+}
+
+/// Periodically segment new positions
+pub async fn run_segmentation(db: Db) {
+    let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = update_trips(&db).await {
+            log::error!("Trip segmentation failed: {e}");
+        }
+    }
+}
+
+/// Segmentation of positions not processed by the background job yet.
+/// Empty for time ranges ending before the unprocessed positions.
+pub async fn pending_segmentation(
+    db: &Db,
+    device_id: i32,
+    range: TimeRange,
+) -> anyhow::Result<Segmentation> {
+    let since = db.query_trips_until(device_id).await?;
+    if range.to <= since {
+        return Ok(Segmentation {
+            trips: Vec::new(),
+            stays: Vec::new(),
+            resume: since,
+        });
+    }
+    let points = db.query_points_since(device_id, since, BATCH_SIZE).await?;
+    let filter = Filter::from(&db.query_device_filter(device_id).await?);
+    let finished = points.last().is_some_and(is_finished);
+    Ok(segment(&points, &filter, finished))
+}
+
+/// Stored trips and trips of not yet segmented positions of all or selected devices within a period
+pub async fn query_trips(
+    db: &Db,
+    device_ids: Option<&[i32]>,
+    period: Period,
+) -> anyhow::Result<Vec<TripInfo>> {
+    let mut trips = Vec::new();
+    for device in db.query_last_positions(None, None).await? {
+        if device_ids.is_some_and(|ids| !ids.contains(&device.device_id)) {
+            continue;
+        }
+        let range = period.time_range(device.tz);
+        trips.extend(db.query_trips(&device, range).await?);
+        // Pending trips, including the current trip
+        for trip in pending_segmentation(db, device.device_id, range)
+            .await?
+            .trips
+        {
+            if trip.ts_end >= range.from && trip.ts_start < range.to {
+                trips.push(TripInfo {
+                    id: None,
+                    device_id: device.device_id,
+                    user_id: device.user_id.clone(),
+                    device: device.device.clone(),
+                    tid: device.tid.clone(),
+                    ts_start: format_timestamp(trip.ts_start, device.tz),
+                    ts_end: format_timestamp(trip.ts_end, device.tz),
+                    first_ts: trip.ts_start,
+                    last_ts: trip.ts_end,
+                    distance: trip.distance,
+                    duration: trip.ts_end - trip.ts_start,
+                    open: trip.open,
+                    tz: device.tz,
+                    from: trip.ts_start,
+                    to: trip.ts_end + 1,
+                });
+            }
+        }
+    }
+    // Sort the trips by ts_end in descending order
+    trips.sort_by_key(|trip| std::cmp::Reverse(trip.last_ts));
+    Ok(trips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: i64 = 1_700_000_000;
+
+    /// Points every `interval` seconds at a position with small jitter
+    fn stationary(ts: i64, count: i64, interval: i64) -> Vec<GpsPoint> {
+        (0..count)
+            .map(|i| GpsPoint::new(8.0 + (i % 3) as f64 * 1e-5, 47.0, ts + i * interval))
+            .collect()
+    }
+
+    /// Points every 10 seconds moving east by about 75 meters
+    fn moving(ts: i64, x: f64, count: i64) -> Vec<GpsPoint> {
+        (0..count)
+            .map(|i| GpsPoint::new(x + i as f64 * 1e-3, 47.0, ts + i * 10))
+            .collect()
+    }
+
+    #[test]
+    fn stay_longer_than_batch() {
+        // 14 hours at the same place with 1 Hz logging
+        let points = stationary(START, 14 * 3600, 1);
+        let filter = Filter::default();
+
+        let segmentation = segment(&points, &filter, false);
+        assert!(segmentation.trips.is_empty());
+        assert_eq!(segmentation.stays.len(), 1);
+        assert!(segmentation.stays[0].open);
+        assert_eq!(segmentation.resume, START);
+
+        // Finished batches advance past the stay
+        let last = points.last().unwrap().ts;
+        let segmentation = segment(&points, &filter, true);
+        assert_eq!(segmentation.stays.len(), 1);
+        assert!(!segmentation.stays[0].open);
+        assert_eq!(segmentation.stays[0].departure, last);
+        assert_eq!(segmentation.resume, last + 1);
+    }
+
+    #[test]
+    fn trailing_open_trip() {
+        let mut points = stationary(START, 20, 30);
+        let departure = points.last().unwrap().ts;
+        points.extend(moving(departure + 10, 8.002, 30));
+        let filter = Filter::default();
+
+        let segmentation = segment(&points, &filter, false);
+        assert_eq!(segmentation.stays.len(), 1);
+        assert!(!segmentation.stays[0].open);
+        assert_eq!(segmentation.trips.len(), 1);
+        assert!(segmentation.trips[0].open);
+        assert_eq!(segmentation.trips[0].ts_start, departure);
+        assert_eq!(segmentation.resume, departure);
+
+        let last = points.last().unwrap().ts;
+        let segmentation = segment(&points, &filter, true);
+        assert_eq!(segmentation.trips.len(), 1);
+        assert!(!segmentation.trips[0].open);
+        assert_eq!(segmentation.trips[0].ts_end, last);
+        assert_eq!(segmentation.resume, last + 1);
+    }
+
+    #[test]
+    fn signal_gap() {
+        let mut points = moving(START, 8.0, 30);
+        let gap_start = points.last().unwrap().ts;
+        points.extend(moving(gap_start + MAX_GAP + 60, 8.1, 30));
+        let segmentation = segment(&points, &Filter::default(), true);
+        assert!(segmentation.stays.is_empty());
+        assert_eq!(segmentation.trips.len(), 2);
+        assert_eq!(segmentation.trips[0].ts_start, START);
+        assert_eq!(segmentation.trips[0].ts_end, gap_start);
+        assert_eq!(segmentation.trips[1].ts_start, gap_start + MAX_GAP + 60);
+        assert!(segmentation.trips.iter().all(|trip| !trip.open));
+    }
+}
@@ -0,0 +1,19 @@
+//! Helpers for XML exports
+
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+
+/// XML writer into a buffer
+pub type Writer = EventWriter<Vec<u8>>;
+
+/// Writer with indented output
+pub fn writer() -> Writer {
+    EventWriter::new_with_config(Vec::new(), EmitterConfig::new().perform_indent(true))
+}
+
+/// Element with text content
+pub fn text_element(w: &mut Writer, name: &str, text: &str) -> anyhow::Result<()> {
+    w.write(XmlEvent::start_element(name))?;
+    w.write(XmlEvent::characters(text))?;
+    w.write(XmlEvent::end_element())?;
+    Ok(())
+}